@@ -0,0 +1,64 @@
+//! Compares how long the same programs take on each evaluation backend. Run
+//! with `cargo bench`.
+
+use std::time::{Duration, Instant};
+
+use turtle::{Backend, Evaluator, Interpreter};
+
+const WORKLOADS: &[(&str, &str)] = &[
+    (
+        "counting loop",
+        "(let 'i 0) (while (gt i 300000) (let 'i (sum i 1)))",
+    ),
+    (
+        "loop in a function",
+        "(let 'count (lambda '(n) '(do (let 'i 0) (while (gt i n) (let 'i (sum i 1))) i))) \
+         (count 300000)",
+    ),
+    (
+        "function calls",
+        "(let 'inc (lambda '(x) '(sum x 1))) \
+         (let 'i 0) (while (gt i 300000) (let 'i (inc i)))",
+    ),
+    (
+        "macro calls",
+        "(import \"@prelude\") (letq i 0) (while (gt i 100000) (++ i))",
+    ),
+    ("project euler 4", include_str!("../src/spec/euler_4.lisp")),
+];
+
+// The fastest of a few runs, which is the least affected by noise
+fn time(code: &str, backend: Backend) -> Duration {
+    (0..3)
+        .map(|_| {
+            let evaluator = Evaluator::new()
+                .expect("could not start evaluator")
+                .with_backend(backend);
+            let interpreter = Interpreter::with_evaluator(evaluator);
+            let start = Instant::now();
+            if let Err(err) = interpreter.eval_source(code, "<benchmark>") {
+                panic!("the benchmark failed:\n{}", err);
+            }
+            start.elapsed()
+        })
+        .min()
+        .unwrap()
+}
+
+fn main() {
+    println!(
+        "{:<20} {:>12} {:>12} {:>8}",
+        "workload", "interpreter", "bytecode", "speedup"
+    );
+    for (name, code) in WORKLOADS {
+        let interpreted = time(code, Backend::Interpreter);
+        let compiled = time(code, Backend::Bytecode);
+        println!(
+            "{:<20} {:>10.0}ms {:>10.0}ms {:>7.1}x",
+            name,
+            interpreted.as_secs_f64() * 1000.0,
+            compiled.as_secs_f64() * 1000.0,
+            interpreted.as_secs_f64() / compiled.as_secs_f64()
+        );
+    }
+}
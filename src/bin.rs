@@ -1,7 +1,18 @@
-use clap::{App, Arg};
+use clap::{App, Arg, ArgMatches};
 use std::fs;
+use std::time::Duration;
 use turtle::*;
 
+fn numeric_arg(matches: &ArgMatches, name: &str) -> Option<usize> {
+    matches.value_of(name).map(|value| match value.parse() {
+        Ok(number) => number,
+        Err(_) => {
+            eprintln!("`{}` is not a valid value for --{}", value, name);
+            std::process::exit(1);
+        }
+    })
+}
+
 fn main() {
     let matches = App::new("turtle")
         .version(env!("CARGO_PKG_VERSION"))
@@ -21,6 +32,56 @@ fn main() {
                 .help("Run without the prelude")
                 .takes_value(false),
         )
+        .arg(
+            Arg::with_name("BYTECODE")
+                .short("b")
+                .long("bytecode")
+                .help("Compile code to bytecode and run it on the VM")
+                .takes_value(false),
+        )
+        .arg(
+            Arg::with_name("max-depth")
+                .long("max-depth")
+                .value_name("CALLS")
+                .help("Limit how deeply calls may nest (1000 by default)")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("max-steps")
+                .long("max-steps")
+                .value_name("STEPS")
+                .help("Limit how many expressions each top-level form may evaluate")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("max-time")
+                .long("max-time")
+                .value_name("MILLISECONDS")
+                .help("Limit how long each top-level form may run for")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("max-list-length")
+                .long("max-list-length")
+                .value_name("ELEMENTS")
+                .help("Limit how long the lists code creates may be")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("stack-size")
+                .long("stack-size")
+                .value_name("MEGABYTES")
+                .help("The stack size of the evaluation thread (64 by default)")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("error-format")
+                .long("error-format")
+                .value_name("FORMAT")
+                .possible_values(&["pretty", "plain", "json"])
+                .help("How to print errors (plain when NO_COLOR is set, pretty otherwise)")
+                .takes_value(true),
+        )
         .arg(
             Arg::with_name("FILE")
                 .help("The file to run")
@@ -28,21 +89,47 @@ fn main() {
                 .index(1),
         )
         .get_matches();
-    let env = Locker::new(Environment::root());
+    let error_format = match matches.value_of("error-format") {
+        // Only valid formats get past clap
+        Some(format) => format.parse().unwrap(),
+        None => match std::env::var_os("NO_COLOR") {
+            Some(val) if !val.is_empty() => ErrorFormat::Plain,
+            _ => ErrorFormat::Pretty,
+        },
+    };
+    let backend = match matches.is_present("BYTECODE") {
+        true => Backend::Bytecode,
+        false => Backend::Interpreter,
+    };
+    let limits = EvalLimits {
+        max_depth: numeric_arg(&matches, "max-depth").unwrap_or(DEFAULT_MAX_DEPTH),
+        max_steps: numeric_arg(&matches, "max-steps"),
+        max_time: numeric_arg(&matches, "max-time").map(|ms| Duration::from_millis(ms as u64)),
+        max_list_length: numeric_arg(&matches, "max-list-length"),
+    };
+    let stack_size = numeric_arg(&matches, "stack-size")
+        .map(|megabytes| match megabytes.checked_mul(1024 * 1024) {
+            Some(bytes) => bytes,
+            None => {
+                eprintln!("`{}` is not a valid value for --stack-size", megabytes);
+                std::process::exit(1);
+            }
+        })
+        .unwrap_or(DEFAULT_STACK_SIZE);
+    let interpreter = match Evaluator::with_stack_size(stack_size) {
+        Ok(evaluator) => {
+            Interpreter::with_evaluator(evaluator.with_backend(backend).with_limits(limits))
+        }
+        Err(err) => {
+            eprintln!("{}", err.render(error_format));
+            std::process::exit(1);
+        }
+    };
 
     if !matches.is_present("NO_PRELUDE") {
-        match parse("(import \"@prelude\")", "<builtin>") {
-            Ok(expressions) => {
-                for expression in expressions {
-                    let snapshot = CallSnapshot::root(&expression);
-                    match expression.eval(snapshot, env.clone()) {
-                        Ok(_) => {}
-                        Err(err) => eprintln!("{}", err),
-                    }
-                }
-            }
-            Err(err) => eprintln!("{}", err),
-        };
+        if let Err(err) = interpreter.load_prelude() {
+            eprintln!("{}", err.render(error_format));
+        }
     }
     let file = matches.value_of("FILE");
     match file {
@@ -51,27 +138,18 @@ fn main() {
                 let exp_parsed = match parse(&code, location) {
                     Ok(val) => val,
                     Err(err) => {
-                        eprintln!("{}", err);
+                        eprintln!("{}", err.render(error_format));
                         std::process::exit(2);
                     }
                 };
                 for val in exp_parsed {
-                    match val
-                        .clone()
-                        .eval_async(CallSnapshot::root(&val), env.clone())
-                        .unwrap()
-                        .recv()
-                        .unwrap()
-                    {
-                        Ok(_) => {}
-                        Err(err) => {
-                            eprintln!("{}", err);
-                            std::process::exit(3);
-                        }
+                    if let Err(err) = interpreter.eval(val) {
+                        eprintln!("{}", err.render(error_format));
+                        std::process::exit(3);
                     }
                 }
                 if matches.is_present("INTERACTIVE") {
-                    repl::spawn(env);
+                    repl::spawn(&interpreter, error_format);
                 }
             }
             Err(err) => {
@@ -79,6 +157,6 @@ fn main() {
                 std::process::exit(1);
             }
         },
-        None => repl::spawn(env),
+        None => repl::spawn(&interpreter, error_format),
     }
 }
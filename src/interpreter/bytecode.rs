@@ -0,0 +1,365 @@
+use std::sync::Arc;
+
+use crate::interpreter::environment::BindingCache;
+use crate::{Exception, Expression, Operator, Symbol, SymbolMap, Value};
+
+/// A single instruction of a compiled `Program`. Instructions refer to each
+/// other (and to the program's constants) by index.
+///
+/// Which code runs for a call is only known once its head has been resolved
+/// at runtime (`cond` may have been rebound, and macros receive their arguments
+/// unevaluated), so every argument of a call is compiled separately and only
+/// run if the callee asks for it.
+#[derive(Debug, Clone)]
+pub enum Instruction {
+    /// Produces the constant at the given index.
+    Constant(usize),
+    /// Looks up the (namespaced) symbol expression stored in the constant at
+    /// the given index.
+    Lookup(usize),
+    /// Reads a local of the function being run straight from its slot in the
+    /// call's environment. If the code is running somewhere else (or nothing
+    /// has been bound to the local yet), `symbol` is looked up instead.
+    Local { slot: usize, symbol: usize },
+    /// Reads a local of the function `depth` functions out from the one being
+    /// run (which it was defined in), from its slot in the environment of the
+    /// call the closure was made by. Falls back to looking `symbol` up if any
+    /// environment on the way isn't laid out as it was at compile time.
+    Captured {
+        depth: usize,
+        slot: usize,
+        symbol: usize,
+    },
+    /// Reads a name none of the enclosing functions bind, resolving it from
+    /// the environment `depth` calls out (where the outermost of them was
+    /// defined). What it resolved to is kept in the program's cache at index
+    /// `cache` until a binding that could shadow it appears.
+    Global {
+        depth: usize,
+        name: Symbol,
+        symbol: usize,
+        cache: usize,
+    },
+    /// Evaluates the constant at the given index with the tree-walking
+    /// interpreter.
+    Interpret(usize),
+    Call {
+        /// The constant holding the whole call expression.
+        site: usize,
+        /// The instruction producing the function or operator being called.
+        head: usize,
+        /// The compiled code and the (unevaluated) constant of each argument.
+        arguments: Vec<(usize, usize)>,
+    },
+    /// A call that looks like a well-formed `cond`: the `Call` at index
+    /// `call` (whose arguments are left to the interpreter, as they're only
+    /// evaluated as such if `cond` has been rebound), and the compiled
+    /// condition and value of each clause.
+    Cond {
+        call: usize,
+        clauses: Vec<(usize, usize)>,
+    },
+    /// A call of `sum`, run without a snapshot of its own when its arguments
+    /// can be read straight from their bindings. Like the other builtin
+    /// instructions below, it refers to the `Call` it runs as, which the VM
+    /// falls back to if the head no longer refers to the builtin.
+    Sum(usize),
+    /// A call of `gt`.
+    Gt(usize),
+    /// A call of `let`.
+    Let(usize),
+    /// A call of `while`.
+    While(usize),
+    /// A call of `if`.
+    If(usize),
+}
+
+/// The locals of a function: its parameters, then the names its body binds
+/// with `let` or `letq` (outside of any function nested in it). Each has a
+/// slot in the environment of a call to the function, which compiled code
+/// reads it from by index.
+#[derive(Debug, Default)]
+pub struct Layout {
+    symbols: Vec<Symbol>,
+    slots: SymbolMap<usize>,
+}
+
+impl Layout {
+    fn new(params: &[Symbol], expressions: &[Expression]) -> Result<Self, Exception> {
+        let mut layout = Self::default();
+        for param in params {
+            layout.add(*param);
+        }
+        for exp in expressions {
+            layout.add_bound(exp)?;
+        }
+        Ok(layout)
+    }
+
+    pub fn len(&self) -> usize {
+        self.symbols.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.symbols.is_empty()
+    }
+
+    pub fn symbols(&self) -> &'_ [Symbol] {
+        &self.symbols
+    }
+
+    /// The slot of `symbol`, if it's one of the locals.
+    pub fn slot(&self, symbol: &Symbol) -> Option<usize> {
+        self.slots.get(symbol).copied()
+    }
+
+    fn add(&mut self, symbol: Symbol) {
+        if !self.slots.contains_key(&symbol) {
+            self.slots.insert(symbol, self.symbols.len());
+            self.symbols.push(symbol);
+        }
+    }
+
+    // Adds the names `exp` binds. Quoted code (like the bodies of nested
+    // functions) is left alone, and so are forms that don't name what they
+    // bind outright; those are still found by name when they're looked up.
+    fn add_bound(&mut self, exp: &Expression) -> Result<(), Exception> {
+        let value = exp.value();
+        let value = value.read()?;
+        let vals = match &*value {
+            Value::List(vals) if !vals.is_empty() => vals,
+            _ => return Ok(()),
+        };
+        if let Value::Operator(Operator::Quote) = &*vals[0].value().read()? {
+            return Ok(());
+        }
+        if let (Value::Symbol(head), Some(target)) = (&*vals[0].value().read()?, vals.get(1)) {
+            let target = target.value();
+            let target = target.read()?;
+            match (head.string_value().as_str(), &*target) {
+                ("letq", Value::Symbol(symbol)) if symbol.namespace().is_none() => {
+                    self.add(*symbol)
+                }
+                ("let", Value::List(quoted)) if quoted.len() == 2 => {
+                    if let (Value::Operator(Operator::Quote), Value::Symbol(symbol)) =
+                        (&*quoted[0].value().read()?, &*quoted[1].value().read()?)
+                    {
+                        if symbol.namespace().is_none() {
+                            self.add(*symbol);
+                        }
+                    }
+                }
+                _ => {}
+            }
+        }
+        for val in vals.iter() {
+            self.add_bound(val)?;
+        }
+        Ok(())
+    }
+}
+
+/// Expressions lowered into a flat list of instructions, ready to be run by
+/// the virtual machine in `vm`.
+#[derive(Debug, Default)]
+pub struct Program {
+    instructions: Vec<Instruction>,
+    constants: Vec<Expression>,
+    // The instruction each compiled top-level expression starts at
+    entries: Vec<usize>,
+    // The locals of the function this is the body of, if it is one
+    layout: Option<Arc<Layout>>,
+    // The locals of the functions it was defined in, innermost first
+    enclosing: Vec<Arc<Layout>>,
+    // What each `Global` instruction last resolved to
+    caches: Vec<BindingCache>,
+}
+
+impl Program {
+    pub fn compile(expressions: &[Expression]) -> Result<Self, Exception> {
+        Self::default().compile_entries(expressions)
+    }
+
+    /// Compiles the body of a function taking `params`, reading its locals
+    /// from their slots. `enclosing` are the layouts of the calls the function
+    /// was defined in (innermost first), whose locals it reads the same way.
+    pub fn compile_function(
+        params: &[Symbol],
+        expressions: &[Expression],
+        enclosing: Vec<Arc<Layout>>,
+    ) -> Result<Self, Exception> {
+        let program = Self {
+            layout: Some(Arc::new(Layout::new(params, expressions)?)),
+            enclosing,
+            ..Self::default()
+        };
+        program.compile_entries(expressions)
+    }
+
+    pub fn instructions(&self) -> &'_ [Instruction] {
+        &self.instructions
+    }
+
+    pub fn constants(&self) -> &'_ [Expression] {
+        &self.constants
+    }
+
+    pub fn entries(&self) -> &'_ [usize] {
+        &self.entries
+    }
+
+    pub fn layout(&self) -> Option<&Arc<Layout>> {
+        self.layout.as_ref()
+    }
+
+    /// The layouts of the calls the function was defined in, innermost first.
+    pub fn enclosing(&self) -> &'_ [Arc<Layout>] {
+        &self.enclosing
+    }
+
+    pub(crate) fn caches(&self) -> &'_ [BindingCache] {
+        &self.caches
+    }
+
+    fn compile_entries(mut self, expressions: &[Expression]) -> Result<Self, Exception> {
+        for exp in expressions {
+            let entry = self.compile_expression(exp)?;
+            self.entries.push(entry);
+        }
+        Ok(self)
+    }
+
+    fn constant(&mut self, exp: &Expression) -> usize {
+        self.constants.push(exp.clone());
+        self.constants.len() - 1
+    }
+
+    fn emit(&mut self, instruction: Instruction) -> usize {
+        self.instructions.push(instruction);
+        self.instructions.len() - 1
+    }
+
+    fn compile_expression(&mut self, exp: &Expression) -> Result<usize, Exception> {
+        let value = exp.value();
+        let instruction = match &*value.read()? {
+            Value::Symbol(sym) => {
+                let sym = *sym;
+                let symbol = self.constant(exp);
+                return Ok(self.compile_symbol(sym, symbol));
+            }
+            Value::List(vals) if !vals.is_empty() => {
+                let head_exp = vals.first().unwrap();
+                let (head, operator) = match &*head_exp.value().read()? {
+                    // Quoting is resolved at compile time, since the sugar
+                    // refers to the operator directly (not through a symbol)
+                    Value::Operator(Operator::Quote) if vals.len() == 2 => {
+                        let quoted = self.constant(&vals[1]);
+                        return Ok(self.emit(Instruction::Constant(quoted)));
+                    }
+                    Value::Symbol(sym) => (
+                        self.compile_expression(head_exp)?,
+                        match sym.literal() {
+                            Some(Value::Operator(op)) => Some(op),
+                            _ => None,
+                        },
+                    ),
+                    Value::List(_) => (self.compile_expression(head_exp)?, None),
+                    Value::Operator(op) => (self.compile_constant(head_exp), Some(op.clone())),
+                    _ => (self.compile_constant(head_exp), None),
+                };
+
+                let clauses = match operator {
+                    Some(Operator::Cond) => self.compile_clauses(&vals[1..])?,
+                    _ => None,
+                };
+
+                // The clauses of a `cond` are only evaluated as ordinary
+                // arguments if `cond` has been rebound, so they are not
+                // compiled twice
+                let mut arguments = Vec::with_capacity(vals.len() - 1);
+                for arg in vals.iter().skip(1) {
+                    let code = match clauses {
+                        Some(_) => {
+                            let constant = self.constant(arg);
+                            self.emit(Instruction::Interpret(constant))
+                        }
+                        None => self.compile_expression(arg)?,
+                    };
+                    arguments.push((code, self.constant(arg)));
+                }
+
+                let site = self.constant(exp);
+                let call = self.emit(Instruction::Call {
+                    site,
+                    head,
+                    arguments,
+                });
+                match (clauses, operator) {
+                    (Some(clauses), _) => Instruction::Cond { call, clauses },
+                    (None, Some(Operator::Sum)) => Instruction::Sum(call),
+                    (None, Some(Operator::Gt)) => Instruction::Gt(call),
+                    (None, Some(Operator::Let)) => Instruction::Let(call),
+                    (None, Some(Operator::While)) => Instruction::While(call),
+                    (None, Some(Operator::If)) => Instruction::If(call),
+                    _ => return Ok(call),
+                }
+            }
+            _ => return Ok(self.compile_constant(exp)),
+        };
+        Ok(self.emit(instruction))
+    }
+
+    // Resolves what `sym` refers to as far as the layouts allow: a local of
+    // this function or one it was defined in, or otherwise something bound
+    // outside all of them
+    fn compile_symbol(&mut self, sym: Symbol, symbol: usize) -> usize {
+        if sym.namespace().is_some() {
+            return self.emit(Instruction::Lookup(symbol));
+        }
+        let layouts = self.layout.iter().chain(self.enclosing.iter());
+        let found = layouts
+            .enumerate()
+            .find_map(|(depth, layout)| layout.slot(&sym).map(|slot| (depth, slot)));
+        let instruction = match found {
+            Some((0, slot)) => Instruction::Local { slot, symbol },
+            Some((depth, slot)) => Instruction::Captured {
+                depth,
+                slot,
+                symbol,
+            },
+            None => {
+                self.caches.push(BindingCache::default());
+                Instruction::Global {
+                    depth: self.layout.iter().count() + self.enclosing.len(),
+                    name: sym,
+                    symbol,
+                    cache: self.caches.len() - 1,
+                }
+            }
+        };
+        self.emit(instruction)
+    }
+
+    fn compile_constant(&mut self, exp: &Expression) -> usize {
+        let constant = self.constant(exp);
+        self.emit(Instruction::Constant(constant))
+    }
+
+    fn compile_clauses(
+        &mut self,
+        clauses: &[Expression],
+    ) -> Result<Option<Vec<(usize, usize)>>, Exception> {
+        let mut compiled = Vec::with_capacity(clauses.len());
+        for clause in clauses {
+            match &*clause.value().read()? {
+                Value::List(elems) if elems.len() == 2 => compiled.push((
+                    self.compile_expression(&elems[0])?,
+                    self.compile_expression(&elems[1])?,
+                )),
+                // Malformed clauses are left to `cond` itself to report
+                _ => return Ok(None),
+            }
+        }
+        Ok(Some(compiled))
+    }
+}
@@ -1,12 +1,145 @@
-use crate::{exp, Exception, ExceptionValue as EV, Expression, Locker};
-use ansi_term::Color;
+use crate::interpreter::conditions::{Debugger, Handler, Restart, Stack};
+use crate::interpreter::exceptions::styled;
+use crate::interpreter::json::Json;
+use crate::interpreter::resolver::Modules;
+use crate::{
+    exp, Exception, ExceptionValue as EV, Expression, Function, Keyword, Locker, Map,
+    ModuleResolver, Number, SourcePosition, Symbol, Value,
+};
+use ansi_term::{Color, Style};
 use std::fmt;
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+/// The call depth evaluations are limited to unless otherwise configured.
+pub const DEFAULT_MAX_DEPTH: usize = 1000;
+
+/// Bounds on the resources a single evaluation may use. Each limit that is
+/// exceeded raises its own exception, which Turtle code can `catch`.
+///
+/// Once a step or time limit has been exceeded, the evaluation is given a
+/// further tenth of that limit to handle the exception in; after that, it
+/// can no longer be caught.
+#[derive(Debug, Clone, PartialEq)]
+pub struct EvalLimits {
+    /// How deeply calls may nest (raises `StackOverflow`). Deep recursion also
+    /// needs a large enough stack; see `Evaluator::with_stack_size`.
+    pub max_depth: usize,
+    /// How many expressions may be evaluated (raises `StepLimit`).
+    pub max_steps: Option<usize>,
+    /// How long the evaluation may run for (raises `TimeLimit`).
+    pub max_time: Option<Duration>,
+    /// How long a list created by the evaluation may be (raises
+    /// `LengthLimit`).
+    pub max_list_length: Option<usize>,
+}
+
+impl Default for EvalLimits {
+    fn default() -> Self {
+        Self {
+            max_depth: DEFAULT_MAX_DEPTH,
+            max_steps: None,
+            max_time: None,
+            max_list_length: None,
+        }
+    }
+}
+
+// The limits an evaluation runs under, and how much of them it has used.
+#[derive(Debug)]
+struct Budget {
+    limits: EvalLimits,
+    steps: AtomicUsize,
+    started: Instant,
+    // Whether a step or time limit has already been exceeded (so the grace
+    // period for handling it has started)
+    exceeded: AtomicBool,
+}
+
+impl Budget {
+    fn check(&self, snapshot: &Locker<CallSnapshot>) -> Result<(), Exception> {
+        let grace = self.exceeded.load(Ordering::Relaxed);
+        if let Some(max_steps) = self.limits.max_steps {
+            let allowed = match grace {
+                true => max_steps + max_steps / 10,
+                false => max_steps,
+            };
+            if self.steps.fetch_add(1, Ordering::Relaxed) >= allowed {
+                self.exceeded.store(true, Ordering::Relaxed);
+                exp!(
+                    EV::StepLimit,
+                    snapshot,
+                    format!("the evaluation may take at most {} steps", max_steps)
+                )
+            }
+        }
+        if let Some(max_time) = self.limits.max_time {
+            let allowed = match grace {
+                true => max_time + max_time / 10,
+                false => max_time,
+            };
+            if self.started.elapsed() > allowed {
+                self.exceeded.store(true, Ordering::Relaxed);
+                exp!(
+                    EV::TimeLimit,
+                    snapshot,
+                    format!("the evaluation may take at most {:?}", max_time)
+                )
+            }
+        }
+        Ok(())
+    }
+
+    // Counts `count` steps if they're all within the limits (and none have
+    // been exceeded yet); otherwise leaves it to `check` to raise
+    fn reserve(&self, count: usize) -> bool {
+        if self.exceeded.load(Ordering::Relaxed) {
+            return false;
+        }
+        if let Some(max_time) = self.limits.max_time {
+            if self.started.elapsed() > max_time {
+                return false;
+            }
+        }
+        if let Some(max_steps) = self.limits.max_steps {
+            if self.steps.fetch_add(count, Ordering::Relaxed) + count > max_steps {
+                self.steps.fetch_sub(count, Ordering::Relaxed);
+                return false;
+            }
+        }
+        true
+    }
+}
 
 #[derive(Debug, Clone)]
 pub struct CallSnapshot {
     parent: Option<Locker<Self>>,
     expression: Expression,
     depth: usize,
+    // How many tail calls have replaced this snapshot in place (zero for
+    // ordinary calls).
+    tail_calls: usize,
+    // Set from another thread to ask the evaluation to stop; inherited by
+    // every child snapshot.
+    interrupt: Option<Arc<AtomicBool>>,
+    // The limits the evaluation runs under, shared by every child snapshot.
+    budget: Option<Arc<Budget>>,
+    // The macro call (if any) whose body is being run, inherited by every
+    // child snapshot until a function is called
+    expansion: Option<SourcePosition>,
+    // The name of the function (or macro) the snapshot's call runs, if it was
+    // named when it was bound
+    function: Option<Symbol>,
+    // The condition handlers and restarts established around the evaluation,
+    // inherited by every child snapshot
+    handlers: Stack<Handler>,
+    restarts: Stack<Restart>,
+    // The modules `import` finds and shares (found by the default resolver,
+    // and not shared, if `None`), and the locations of those being imported
+    // (innermost first), inherited by every child snapshot
+    modules: Option<Modules>,
+    importing: Stack<String>,
 }
 
 impl CallSnapshot {
@@ -15,66 +148,511 @@ impl CallSnapshot {
             parent: None,
             expression: exp.clone(),
             depth: 0,
+            tail_calls: 0,
+            interrupt: None,
+            budget: None,
+            expansion: None,
+            function: None,
+            handlers: Stack::default(),
+            restarts: Stack::default(),
+            modules: None,
+            importing: Stack::default(),
         })
     }
 
+    /// Copies `snapshot` into a new snapshot whose evaluation (and that of its
+    /// children) stops with an `Interrupted` exception once `interrupt` is set.
+    pub fn interruptible(
+        snapshot: &Locker<Self>,
+        interrupt: Arc<AtomicBool>,
+    ) -> Result<Locker<Self>, Exception> {
+        let mut copy = snapshot.read()?.clone();
+        copy.interrupt = Some(interrupt);
+        Ok(Locker::new(copy))
+    }
+
+    /// Copies `snapshot` into a new snapshot whose evaluation (and that of its
+    /// children) is held to `limits`, starting now.
+    pub fn limited(snapshot: &Locker<Self>, limits: EvalLimits) -> Result<Locker<Self>, Exception> {
+        let mut copy = snapshot.read()?.clone();
+        copy.budget = Some(Arc::new(Budget {
+            limits,
+            steps: AtomicUsize::new(0),
+            started: Instant::now(),
+            exceeded: AtomicBool::new(false),
+        }));
+        Ok(Locker::new(copy))
+    }
+
+    /// Copies `snapshot` into a new snapshot that asks `debugger` which
+    /// restart to invoke for exceptions nothing else handles.
+    pub fn with_debugger(
+        snapshot: &Locker<Self>,
+        debugger: Debugger,
+    ) -> Result<Locker<Self>, Exception> {
+        Self::with_handler(snapshot, Handler::Debugger(debugger))
+    }
+
+    /// Copies `snapshot` into a new snapshot whose evaluation (and that of its
+    /// children) imports modules with `resolver`, evaluating each only once.
+    pub fn with_resolver(
+        snapshot: &Locker<Self>,
+        resolver: Arc<dyn ModuleResolver>,
+    ) -> Result<Locker<Self>, Exception> {
+        Self::with_modules(snapshot, Modules::new(resolver))
+    }
+
+    pub(crate) fn with_modules(
+        snapshot: &Locker<Self>,
+        modules: Modules,
+    ) -> Result<Locker<Self>, Exception> {
+        let mut copy = snapshot.read()?.clone();
+        copy.modules = Some(modules);
+        Ok(Locker::new(copy))
+    }
+
+    pub(crate) fn modules(&self) -> Option<Modules> {
+        self.modules.clone()
+    }
+
+    /// Copies `snapshot` into a new snapshot for evaluating the module at
+    /// `location`.
+    pub(crate) fn importing(
+        snapshot: &Locker<Self>,
+        location: String,
+    ) -> Result<Locker<Self>, Exception> {
+        let mut copy = snapshot.read()?.clone();
+        copy.importing = copy.importing.push(location);
+        Ok(Locker::new(copy))
+    }
+
+    pub(crate) fn imports(&self) -> Stack<String> {
+        self.importing.clone()
+    }
+
+    pub(crate) fn with_handler(
+        snapshot: &Locker<Self>,
+        handler: Handler,
+    ) -> Result<Locker<Self>, Exception> {
+        let handlers = snapshot.read()?.handlers.push(handler);
+        Self::with_handlers(snapshot, handlers)
+    }
+
+    pub(crate) fn with_handlers(
+        snapshot: &Locker<Self>,
+        handlers: Stack<Handler>,
+    ) -> Result<Locker<Self>, Exception> {
+        let mut copy = snapshot.read()?.clone();
+        copy.handlers = handlers;
+        Ok(Locker::new(copy))
+    }
+
+    /// Copies `snapshot` into a new snapshot with `restarts` established
+    /// (the first of them innermost).
+    pub(crate) fn with_restarts(
+        snapshot: &Locker<Self>,
+        restarts: Vec<Restart>,
+    ) -> Result<Locker<Self>, Exception> {
+        let mut copy = snapshot.read()?.clone();
+        for restart in restarts.into_iter().rev() {
+            copy.restarts = copy.restarts.push(restart);
+        }
+        Ok(Locker::new(copy))
+    }
+
+    pub(crate) fn handlers(&self) -> Stack<Handler> {
+        self.handlers.clone()
+    }
+
+    /// The restarts established around the evaluation, innermost first.
+    pub fn restarts(&self) -> Vec<Restart> {
+        self.restarts.iter().cloned().collect()
+    }
+
     pub fn new(exp: &Expression, parent: &Locker<Self>) -> Result<Locker<Self>, Exception> {
         // TODO: make read lock check return an exception instead of panicking
-        let depth = parent
+        let parent_snapshot = parent
             .read()
-            .expect("could not access call snapshot parent (are threads locked?)")
-            .depth
-            + 1;
-        if depth > 1000 {
+            .expect("could not access call snapshot parent (are threads locked?)");
+        let depth = parent_snapshot.depth + 1;
+        parent_snapshot.check_interrupt(parent)?;
+        if depth > parent_snapshot.max_depth() {
             exp!(
                 EV::StackOverflow,
                 parent,
-                "this can happen when recursion goes too deep; verify there aren't any endless loops, or consider using `while` instead".to_string()
+                format!("calls may nest at most {} deep; this can happen when recursion goes too deep, so verify there aren't any endless loops, or consider using `while` instead", parent_snapshot.max_depth())
             )
         }
+        parent_snapshot.check_budget(parent)?;
 
         Ok(Locker::new(CallSnapshot {
             parent: Some(parent.clone()),
             expression: exp.clone(),
             depth,
+            tail_calls: 0,
+            interrupt: parent_snapshot.interrupt.clone(),
+            budget: parent_snapshot.budget.clone(),
+            expansion: parent_snapshot.expansion.clone(),
+            function: None,
+            handlers: parent_snapshot.handlers.clone(),
+            restarts: parent_snapshot.restarts.clone(),
+            modules: parent_snapshot.modules.clone(),
+            importing: parent_snapshot.importing.clone(),
+        }))
+    }
+
+    /// Whether `count` calls, each made from the one before it (starting
+    /// from `parent`), would pass the checks `new` makes; if so, they're
+    /// counted as steps as `new` would count them. This lets calls that can't
+    /// fail otherwise run without snapshots of their own. When it returns
+    /// false, the calls have to be made in full, which raises what's wrong.
+    pub(crate) fn reserve_calls(parent: &Locker<Self>, count: usize) -> Result<bool, Exception> {
+        let parent = parent.read()?;
+        if parent.depth + count > parent.max_depth() {
+            return Ok(false);
+        }
+        if let Some(interrupt) = &parent.interrupt {
+            if interrupt.load(Ordering::SeqCst) {
+                return Ok(false);
+            }
+        }
+        Ok(match &parent.budget {
+            Some(budget) => budget.reserve(count),
+            None => true,
+        })
+    }
+
+    /// Creates the snapshot for an expression evaluated in tail position of
+    /// `current`. The first tail call in a chain is recorded as a child of
+    /// `current` (so the original call site stays visible); every following
+    /// one replaces that child instead of growing the stack.
+    pub fn tail(exp: &Expression, current: &Locker<Self>) -> Result<Locker<Self>, Exception> {
+        if current.read()?.tail_calls == 0 {
+            let child = Self::new(exp, current)?;
+            child.write()?.tail_calls = 1;
+            return Ok(child);
+        }
+
+        let snapshot = current.read()?;
+        snapshot.check_interrupt(current)?;
+        snapshot.check_budget(current)?;
+        Ok(Locker::new(CallSnapshot {
+            parent: snapshot.parent.clone(),
+            expression: exp.clone(),
+            depth: snapshot.depth,
+            tail_calls: snapshot.tail_calls + 1,
+            interrupt: snapshot.interrupt.clone(),
+            budget: snapshot.budget.clone(),
+            expansion: snapshot.expansion.clone(),
+            function: None,
+            handlers: snapshot.handlers.clone(),
+            restarts: snapshot.restarts.clone(),
+            modules: snapshot.modules.clone(),
+            importing: snapshot.importing.clone(),
         }))
     }
 
+    fn check_interrupt(&self, this: &Locker<Self>) -> Result<(), Exception> {
+        if let Some(interrupt) = &self.interrupt {
+            if interrupt.load(Ordering::SeqCst) {
+                exp!(
+                    EV::Interrupted,
+                    this,
+                    "the evaluation was cancelled before it finished".to_string()
+                )
+            }
+        }
+        Ok(())
+    }
+
+    fn check_budget(&self, this: &Locker<Self>) -> Result<(), Exception> {
+        match &self.budget {
+            Some(budget) => budget.check(this),
+            None => Ok(()),
+        }
+    }
+
+    fn max_depth(&self) -> usize {
+        match &self.budget {
+            Some(budget) => budget.limits.max_depth,
+            None => DEFAULT_MAX_DEPTH,
+        }
+    }
+
+    /// Fails with a `LengthLimit` exception if the evaluation `snapshot` is
+    /// part of may not create a list of `length` elements.
+    pub fn check_length(snapshot: &Locker<Self>, length: usize) -> Result<(), Exception> {
+        if let Some(budget) = &snapshot.read()?.budget {
+            if let Some(max_list_length) = budget.limits.max_list_length {
+                if length > max_list_length {
+                    exp!(
+                        EV::LengthLimit,
+                        snapshot,
+                        format!(
+                            "lists may have at most {} elements (this one would have {})",
+                            max_list_length, length
+                        )
+                    )
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// Records that `snapshot` runs the body of the macro called at `site`.
+    pub fn expand(snapshot: &Locker<Self>, site: &Expression) -> Result<(), Exception> {
+        if let Some(source) = site.source() {
+            let mut snapshot = snapshot.write()?;
+            let expansion = match snapshot.expansion.take() {
+                // A macro that ends up calling itself doesn't grow the chain
+                Some(outer) if outer.is_at(source) => outer,
+                Some(outer) if source.expansion_site().is_none() => {
+                    source.clone().expanded_from(outer)
+                }
+                _ => source.clone(),
+            };
+            snapshot.expansion = Some(expansion);
+        }
+        Ok(())
+    }
+
+    /// Records that `snapshot` runs the body of a function, which is never
+    /// part of a macro expansion (even when a macro calls the function).
+    pub fn enter_function(snapshot: &Locker<Self>) -> Result<(), Exception> {
+        if snapshot.read()?.expansion.is_some() {
+            snapshot.write()?.expansion = None;
+        }
+        Ok(())
+    }
+
+    /// Records the name of the function (or macro) `snapshot` calls, so its
+    /// frame shows it even when it was called through another name.
+    pub fn calls(snapshot: &Locker<Self>, function: &Function) -> Result<(), Exception> {
+        if function.name.is_some() {
+            snapshot.write()?.function = function.name;
+        }
+        Ok(())
+    }
+
+    /// Where code created by evaluating `exp` (like a list built by `cons`)
+    /// comes from: `exp` itself, as part of the macro expansion it ran in.
+    pub fn origin(
+        snapshot: &Locker<Self>,
+        exp: &Expression,
+    ) -> Result<Option<SourcePosition>, Exception> {
+        let source = match exp.source() {
+            Some(source) => source,
+            None => return Ok(None),
+        };
+        Ok(match &snapshot.read()?.expansion {
+            Some(site) if source.expansion_site().is_none() => {
+                Some(source.clone().expanded_from(site.clone()))
+            }
+            _ => Some(source.clone()),
+        })
+    }
+
     pub fn expression(&self) -> &'_ Expression {
         &self.expression
     }
 }
 
+/// One call in a backtrace.
+#[derive(Debug, Clone)]
+pub struct Frame {
+    /// The name of the function called: the name it was bound to when it
+    /// was defined, or else the name it was called by (if any).
+    pub function: Option<Symbol>,
+    /// The code being evaluated.
+    pub expression: String,
+    pub source: Option<SourcePosition>,
+    /// How deeply the call is nested (top-level code is at depth zero).
+    pub depth: usize,
+    /// How many tail calls have run in this frame's place (they aren't kept).
+    pub tail_calls: usize,
+}
+
+// Deep recursion shows up as the same few frames repeated this many times or
+// more, which are collapsed in traces
+const MIN_REPEATS: usize = 4;
+const MAX_REPEATED_FRAMES: usize = 8;
+
+impl Frame {
+    fn of(snapshot: &CallSnapshot) -> Self {
+        let expression = &snapshot.expression;
+        let function = snapshot
+            .function
+            .or_else(|| match &*expression.value().read().unwrap() {
+                Value::List(vals) => {
+                    vals.first()
+                        .and_then(|head| match &*head.value().read().unwrap() {
+                            Value::Symbol(sym) => Some(*sym),
+                            _ => None,
+                        })
+                }
+                _ => None,
+            });
+        Self {
+            function,
+            expression: expression
+                .source()
+                .as_ref()
+                .and_then(|source| source.code())
+                .unwrap_or_else(|| format!("{}", expression)),
+            source: expression.source().clone(),
+            depth: snapshot.depth,
+            tail_calls: snapshot.tail_calls,
+        }
+    }
+
+    fn is_at(&self, other: &Self) -> bool {
+        match (&self.source, &other.source) {
+            (Some(source), Some(other_source)) => source.is_at(other_source),
+            (None, None) => self.expression == other.expression,
+            _ => false,
+        }
+    }
+
+    /// The frame as JSON, in the form diagnostics use: an object with the
+    /// `function` (or null), the `expression`, its `source` position (or
+    /// null), the `depth` and the number of `tail_calls`.
+    pub fn to_json(&self) -> Json {
+        Json::Object(vec![
+            (
+                "function",
+                self.function.map(|function| function.to_string()).into(),
+            ),
+            ("expression", self.expression.as_str().into()),
+            (
+                "source",
+                self.source.as_ref().map(|source| source.to_json()).into(),
+            ),
+            ("depth", self.depth.into()),
+            ("tail_calls", self.tail_calls.into()),
+        ])
+    }
+
+    /// The frame as Turtle data: a map with the keys `:function`,
+    /// `:expression`, `:location`, `:line`, `:column` and `:depth` (those
+    /// that aren't known are left out).
+    pub fn into_expression(self) -> Expression {
+        let entry = |name: &str| Value::Keyword(Keyword::from_str(name));
+        let mut map = Map::new()
+            .insert(
+                entry("expression"),
+                Expression::new(Value::Text(self.expression)),
+            )
+            .insert(
+                entry("depth"),
+                Expression::new(Value::Number(Number::from(self.depth))),
+            );
+        if let Some(function) = self.function {
+            map = map.insert(entry("function"), Expression::new(Value::Symbol(function)));
+        }
+        if let Some(source) = &self.source {
+            if let (Some(location), Some((line, column))) = (source.location(), source.line_col()) {
+                map = map
+                    .insert(entry("location"), Expression::new(Value::Text(location)))
+                    .insert(
+                        entry("line"),
+                        Expression::new(Value::Number(Number::from(line))),
+                    )
+                    .insert(
+                        entry("column"),
+                        Expression::new(Value::Number(Number::from(column))),
+                    );
+            }
+        }
+        Expression::new(Value::Map(map))
+    }
+}
+
+impl CallSnapshot {
+    /// The calls that led to this snapshot, outermost first. Consecutive
+    /// snapshots of the same code (like a call and the tail calls it makes)
+    /// are merged into one frame.
+    pub fn backtrace(&self) -> Vec<Frame> {
+        let mut frames: Vec<Frame> = vec![Frame::of(self)];
+        let mut parent = self.parent.clone();
+        while let Some(parent_ref) = parent {
+            let snapshot = match parent_ref.read() {
+                Ok(snapshot) => snapshot,
+                Err(_) => break,
+            };
+            let frame = Frame::of(&snapshot);
+            let last = frames.last_mut().unwrap(); // never empty
+            if last.is_at(&frame) {
+                last.function = frame.function.or(last.function);
+                last.depth = frame.depth;
+                last.tail_calls = last.tail_calls.max(frame.tail_calls);
+            } else {
+                frames.push(frame);
+            }
+            parent = snapshot.parent.clone();
+        }
+        frames.reverse();
+        frames
+    }
+}
+
+// How many frames at the start of `frames` repeat, and how many times over
+fn repetition(frames: &[Frame]) -> Option<(usize, usize)> {
+    (1..=MAX_REPEATED_FRAMES).find_map(|period| {
+        let repeats = frames
+            .chunks_exact(period)
+            .take_while(|chunk| chunk.iter().zip(&frames[..period]).all(|(a, b)| a.is_at(b)))
+            .count();
+        match repeats >= MIN_REPEATS {
+            true => Some((period, repeats)),
+            false => None,
+        }
+    })
+}
+
+fn fmt_frame(frame: &Frame, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+    if frame.tail_calls > 1 {
+        writeln!(
+            f,
+            "      {} {}",
+            styled(f, Color::Blue.bold()).paint("┆"),
+            styled(f, Style::new().dimmed())
+                .paint(format!("{} tail calls elided", frame.tail_calls - 1))
+        )?;
+    }
+    if let Some(source) = &frame.source {
+        fmt::Display::fmt(source, f)?
+    }
+    Ok(())
+}
+
 impl fmt::Display for CallSnapshot {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
-        match &self.parent {
-            Some(parent_ref) => match parent_ref.read() {
-                // This crazy match makes sure we don't print redundant lines
-                Ok(parent) => {
-                    match format!("{}", self.expression) == format!("{}", parent.expression) {
-                        true => match &parent.parent {
-                            Some(superparent_ref) => {
-                                if let Ok(superparent) = superparent_ref.read() {
-                                    write!(f, "{}", superparent)?
-                                }
-                            }
-                            None => {}
-                        },
-                        false => write!(f, "{}", parent)?,
+        let frames = self.backtrace();
+        let mut i = 0;
+        while i < frames.len() {
+            match repetition(&frames[i..]) {
+                // The first and last repetitions are shown in full
+                Some((period, repeats)) => {
+                    for frame in &frames[i..i + period] {
+                        fmt_frame(frame, f)?;
                     }
+                    writeln!(
+                        f,
+                        "      {} {}",
+                        styled(f, Color::Blue.bold()).paint("┆"),
+                        styled(f, Style::new().dimmed()).paint(format!(
+                            "... {} similar frames omitted",
+                            period * (repeats - 2)
+                        ))
+                    )?;
+                    i += period * (repeats - 1);
                 }
-                Err(_) => writeln!(
-                    f,
-                    "      {} unable to access parent call (are threads locked?)",
-                    Color::Yellow.bold().paint("!")
-                )?,
-            },
-            None => {}
-        };
-        match self.expression().source() {
-            Some(source) => write!(f, "{}", source)?,
-            None => {}
+                None => {
+                    fmt_frame(&frames[i], f)?;
+                    i += 1;
+                }
+            }
         }
-        write!(f, "")
+        Ok(())
     }
 }
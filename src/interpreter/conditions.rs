@@ -0,0 +1,164 @@
+use std::fmt;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Arc;
+
+use crate::{
+    CallSnapshot, Environment, Exception, ExceptionValue as EV, Expression, Symbol, Value,
+};
+
+use crate::Locker;
+
+/// Chooses a restart for an exception that nothing else handled, for when a
+/// person can be asked (like at the REPL). It's given the exception and the
+/// restarts available (innermost first), and returns the index of the
+/// restart to invoke with the arguments to invoke it with, or `None` to let
+/// the exception unwind.
+pub type Debugger =
+    Arc<dyn Fn(&Exception, &[Restart]) -> Option<(usize, Vec<Expression>)> + Send + Sync>;
+
+/// A way of carrying on from an exception, established by `restart-case`.
+#[derive(Debug, Clone)]
+pub struct Restart {
+    pub name: Symbol,
+    /// The function that is called (in place of the `restart-case`'s code)
+    /// when the restart is invoked.
+    pub function: Expression,
+    // Identifies the `restart-case` that established the restart
+    establishment: usize,
+}
+
+impl Restart {
+    /// Creates the restarts a single `restart-case` establishes.
+    pub(crate) fn establish(restarts: Vec<(Symbol, Expression)>) -> Vec<Self> {
+        static ESTABLISHMENTS: AtomicUsize = AtomicUsize::new(0);
+        let establishment = ESTABLISHMENTS.fetch_add(1, Ordering::Relaxed);
+        restarts
+            .into_iter()
+            .map(|(name, function)| Self {
+                name,
+                function,
+                establishment,
+            })
+            .collect()
+    }
+
+    /// Whether this restart was established alongside `other`.
+    pub(crate) fn is_sibling(&self, other: &Self) -> bool {
+        self.establishment == other.establishment
+    }
+}
+
+#[derive(Clone)]
+pub(crate) enum Handler {
+    // A function established by `handler-bind`
+    Function(Expression),
+    // A `catch`, which handles every exception by unwinding to it, so no
+    // handler outside of it sees them
+    Catch,
+    Debugger(Debugger),
+}
+
+impl fmt::Debug for Handler {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Handler::Function(function) => write!(f, "Function({})", function),
+            Handler::Catch => write!(f, "Catch"),
+            Handler::Debugger(_) => write!(f, "Debugger"),
+        }
+    }
+}
+
+/// An immutable stack, innermost item first; pushing shares the rest of the
+/// stack with the original.
+#[derive(Debug)]
+pub(crate) struct Stack<T>(Option<Arc<(T, Stack<T>)>>);
+
+impl<T> Clone for Stack<T> {
+    fn clone(&self) -> Self {
+        Self(self.0.clone())
+    }
+}
+
+impl<T> Default for Stack<T> {
+    fn default() -> Self {
+        Self(None)
+    }
+}
+
+impl<T> Stack<T> {
+    pub(crate) fn push(&self, item: T) -> Self {
+        Self(Some(Arc::new((item, self.clone()))))
+    }
+
+    /// The items in the stack with what's below each of them, innermost first.
+    fn entries(&self) -> impl Iterator<Item = (&T, &Stack<T>)> {
+        let mut next = self.0.as_deref();
+        std::iter::from_fn(move || {
+            let (item, rest) = next?;
+            next = rest.0.as_deref();
+            Some((item, rest))
+        })
+    }
+
+    pub(crate) fn iter(&self) -> impl Iterator<Item = &T> {
+        self.entries().map(|(item, _)| item)
+    }
+}
+
+/// Offers `err` to the handlers established around `snapshot` (innermost
+/// first) before it unwinds. Handlers run in place, with only the handlers
+/// outside their own established; one that returns declines the exception,
+/// and one that throws (or invokes a restart) throws instead of `err`, which
+/// is returned as the error. The debugger is only asked when `interactive`.
+///
+/// Exceptions are only signalled once, and not at all when they come from
+/// running out of resources (handling those in place would run out again).
+pub(crate) fn signal(
+    err: &mut Exception,
+    snapshot: &Locker<CallSnapshot>,
+    interactive: bool,
+) -> Result<(), Exception> {
+    if err.is_signalled() || !err.value().is_condition() {
+        return Ok(());
+    }
+    err.set_signalled(true);
+    let handlers = snapshot.read()?.handlers();
+    for (handler, outer) in handlers.entries() {
+        match handler {
+            Handler::Catch => break,
+            Handler::Function(function) => {
+                let handler_snapshot = CallSnapshot::with_handlers(snapshot, outer.clone())?;
+                let condition = Expression::new(Value::Exception(Box::new(err.clone())));
+                Expression::new(Value::List(vec![function.clone(), condition]))
+                    .eval(handler_snapshot, Locker::new(Environment::root()))?;
+            }
+            Handler::Debugger(debugger) if interactive => {
+                let restarts = snapshot.read()?.restarts();
+                if restarts.is_empty() {
+                    continue;
+                }
+                if let Some((index, arguments)) = debugger(err, &restarts) {
+                    if let Some(restart) = restarts.get(index) {
+                        return Err(invoke(restart.clone(), arguments, snapshot));
+                    }
+                }
+            }
+            Handler::Debugger(_) => {}
+        }
+    }
+    Ok(())
+}
+
+/// The exception that unwinds to the `restart-case` that established
+/// `restart`, which then calls it with `arguments`.
+pub(crate) fn invoke(
+    restart: Restart,
+    arguments: Vec<Expression>,
+    snapshot: &Locker<CallSnapshot>,
+) -> Exception {
+    Exception::new(
+        EV::Restart(restart, arguments),
+        Some(snapshot.clone()),
+        None,
+    )
+}
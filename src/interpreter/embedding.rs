@@ -0,0 +1,144 @@
+use std::fs;
+use std::path::Path;
+use std::sync::Arc;
+
+use crate::interpreter::resolver::Modules;
+use crate::Locker;
+
+use crate::{
+    parse, CallSnapshot, DefaultResolver, Environment, Evaluator, Exception, ExceptionValue as EV,
+    Expression, IntoNative, ModuleResolver, NativeFunction, Symbol, Value,
+};
+
+/// Turtle, ready to be embedded: a global environment that code runs in, the
+/// `Evaluator` that runs it, and the `ModuleResolver` it imports modules
+/// with. Rust functions can be made available to that code with
+/// `register_fn`.
+pub struct Interpreter {
+    env: Locker<Environment>,
+    evaluator: Evaluator,
+    modules: Modules,
+}
+
+impl Interpreter {
+    /// An interpreter with an empty global environment (see `load_prelude`)
+    /// and the default evaluator.
+    pub fn new() -> Result<Self, Exception> {
+        Ok(Self::with_evaluator(Evaluator::new()?))
+    }
+
+    pub fn with_evaluator(evaluator: Evaluator) -> Self {
+        Self {
+            env: Locker::new(Environment::root()),
+            evaluator,
+            modules: Modules::new(Arc::new(DefaultResolver::new())),
+        }
+    }
+
+    /// Imports modules with `resolver` from now on (evaluating them afresh,
+    /// even those imported already). Keep a handle on a `DefaultResolver` to
+    /// register more libraries with it later.
+    pub fn with_resolver(mut self, resolver: Arc<dyn ModuleResolver>) -> Self {
+        self.modules = Modules::new(resolver);
+        self
+    }
+
+    pub fn environment(&self) -> &Locker<Environment> {
+        &self.env
+    }
+
+    pub fn evaluator(&self) -> &Evaluator {
+        &self.evaluator
+    }
+
+    /// Makes the standard prelude available to the code that runs from now on.
+    pub fn load_prelude(&self) -> Result<(), Exception> {
+        // The prelude is evaluated directly (and always comes from the
+        // standard library), so it isn't held to the limits the evaluator or
+        // the resolver put on other code. It's still shared with the modules
+        // that import it.
+        let modules = self.modules.with_resolver(Arc::new(DefaultResolver::new()));
+        for expression in parse("(import \"@prelude\")", "<builtin>")? {
+            let snapshot =
+                CallSnapshot::with_modules(&CallSnapshot::root(&expression), modules.clone())?;
+            expression.eval(snapshot, self.env.clone())?;
+        }
+        Ok(())
+    }
+
+    /// Runs `code`, returning what its last expression evaluates to (nil if
+    /// there are none). Stops at the first exception.
+    pub fn eval_str(&self, code: &str) -> Result<Expression, Exception> {
+        self.eval_source(code, "<string>")
+    }
+
+    /// Runs `code` like `eval_str`, with exceptions pointing into `location`.
+    pub fn eval_source(&self, code: &str, location: &str) -> Result<Expression, Exception> {
+        let mut result = Expression::nil();
+        for expression in parse(code, location)? {
+            result = self.eval(expression)?;
+        }
+        Ok(result)
+    }
+
+    /// Evaluates a single (already parsed) expression as top-level code.
+    pub fn eval(&self, expression: Expression) -> Result<Expression, Exception> {
+        let snapshot = self.snapshot(&expression)?;
+        expression
+            .eval_async(&self.evaluator, snapshot, self.env.clone())?
+            .wait()
+    }
+
+    /// The snapshot top-level code runs from.
+    pub(crate) fn snapshot(
+        &self,
+        expression: &Expression,
+    ) -> Result<Locker<CallSnapshot>, Exception> {
+        CallSnapshot::with_modules(&CallSnapshot::root(expression), self.modules.clone())
+    }
+
+    /// Runs the code in the file at `path` like `eval_str`. Files it imports
+    /// are found relative to it.
+    pub fn eval_file(&self, path: impl AsRef<Path>) -> Result<Expression, Exception> {
+        let location = path.as_ref().to_string_lossy().into_owned();
+        match fs::read_to_string(&location) {
+            Ok(code) => self.eval_source(&code, &location),
+            Err(err) => Err(Exception::new(
+                EV::InvalidIncludePath(location),
+                None,
+                Some(format!("the file could not be read ({})", err)),
+            )),
+        }
+    }
+
+    /// The value of the global `name`, if it has one (builtins included).
+    pub fn get(&self, name: &str) -> Option<Expression> {
+        let symbol = Symbol::from_str(name);
+        let found = self.env.read().ok()?.lookup(&symbol)?;
+        let value = found.read().ok()?.clone();
+        Some(value)
+    }
+
+    /// Binds the global `name` to `value`.
+    pub fn set(&self, name: &str, value: Expression) -> Result<(), Exception> {
+        let snapshot = CallSnapshot::root(&value);
+        self.env
+            .write()?
+            .assign(Symbol::from_str(name), value, true, snapshot)?;
+        Ok(())
+    }
+
+    /// Makes `function` available to Turtle code as the global `name`. It can
+    /// take up to six arguments (or a `Variadic` of any number of them) of
+    /// any `FromTurtle` type, which Turtle checks it's given the right number
+    /// and kind of, and return anything `IntoTurtle`. Exceptions it returns
+    /// are thrown from where it was called.
+    pub fn register_fn<Args>(
+        &self,
+        name: &str,
+        function: impl IntoNative<Args>,
+    ) -> Result<(), Exception> {
+        let native = NativeFunction::new(Symbol::from_str(name), function);
+        self.set(name, Expression::new(Value::Native(native)))
+    }
+}
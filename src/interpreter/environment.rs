@@ -1,27 +1,176 @@
-use std::collections::HashMap;
-
+use std::collections::HashSet;
 use std::fmt;
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex, OnceLock, RwLock, Weak};
 
+use crate::interpreter::heap::{Allocation, Tracer};
+use crate::interpreter::suggestions;
 use crate::Locker;
 
 use crate::{
-    exp, CallSnapshot, Exception, ExceptionValue as EV, Expression, Operator, Symbol, Value,
+    exp, CallSnapshot, Exception, ExceptionValue as EV, Expression, Layout, Symbol, SymbolMap,
 };
 
 #[derive(Debug, Clone)]
 struct ParentEnvironment {
-    namespace: Option<String>,
+    namespace: Option<Symbol>,
     environment: Locker<Environment>,
+    // Whether the parent is an imported module, which everything that imports
+    // it shares (and so is never assigned into from here)
+    imported: bool,
 }
 
-#[derive(Debug, Clone)]
+/// What the resolutions cached by a group of connected environments depend
+/// on. An environment shares the scope of the first parent it's given (so a
+/// call shares the scope of the environment it was defined in), and any other
+/// scope it resolves through passes its invalidations on to it.
+#[derive(Debug, Default)]
+struct Scope {
+    // Bumped whenever a binding appears (or a parent is added) that could
+    // change what a cached resolution resolves to
+    epoch: AtomicUsize,
+    dependents: Mutex<Vec<Weak<Scope>>>,
+}
+
+impl Scope {
+    fn epoch(&self) -> usize {
+        self.epoch.load(Ordering::Acquire)
+    }
+
+    fn invalidate(self: &Arc<Self>) {
+        let mut pending = vec![self.clone()];
+        let mut visited: Vec<*const Scope> = vec![];
+        while let Some(scope) = pending.pop() {
+            if visited.contains(&Arc::as_ptr(&scope)) {
+                continue;
+            }
+            visited.push(Arc::as_ptr(&scope));
+            scope.epoch.fetch_add(1, Ordering::AcqRel);
+            pending.extend(
+                scope
+                    .dependents
+                    .lock()
+                    .unwrap()
+                    .iter()
+                    .filter_map(Weak::upgrade),
+            );
+        }
+    }
+
+    fn add_dependent(&self, dependent: &Arc<Scope>) {
+        let mut dependents = self.dependents.lock().unwrap();
+        dependents.retain(|existing| existing.strong_count() > 0);
+        if !dependents
+            .iter()
+            .any(|existing| existing.as_ptr() == Arc::as_ptr(dependent))
+        {
+            dependents.push(Arc::downgrade(dependent));
+        }
+    }
+}
+
+#[derive(Debug)]
+struct Resolution {
+    exp: Locker<Expression>,
+    depth: usize,
+    // The scope's epoch when this was resolved; if it has moved on, a binding
+    // may have appeared that shadows this one
+    epoch: usize,
+}
+
+/// Symbols this environment has resolved through its parents, so repeat
+/// lookups don't have to walk the whole chain again.
+#[derive(Debug, Default)]
+struct Resolutions {
+    entries: Mutex<SymbolMap<Resolution>>,
+    // Whether any other environment has resolved a symbol through this one
+    // (and so may have cached something a new binding here would shadow)
+    shared: AtomicBool,
+}
+
+impl Clone for Resolutions {
+    fn clone(&self) -> Self {
+        Self::default()
+    }
+}
+
+/// What a name compiled code reads (see `Instruction::Global`) was last
+/// resolved to, and from which environment. It stays valid for as long as
+/// that environment's scope doesn't move on; assigning to the binding it holds
+/// doesn't make it stale, since assignment writes into the binding in place.
+#[derive(Debug, Default)]
+pub(crate) struct BindingCache(Mutex<Option<CachedBinding>>);
+
+#[derive(Debug)]
+struct CachedBinding {
+    // Held weakly so the cache doesn't keep the environment alive (a weak
+    // reference still keeps its address from being reused)
+    env: Weak<RwLock<Environment>>,
+    scope: Arc<Scope>,
+    epoch: usize,
+    binding: Locker<Expression>,
+}
+
+impl BindingCache {
+    pub(crate) fn trace(&self, tracer: &mut Tracer) {
+        match self.0.try_lock() {
+            Ok(cached) => {
+                if let Some(cached) = &*cached {
+                    tracer.binding(&cached.binding);
+                }
+            }
+            Err(_) => tracer.opaque(),
+        }
+    }
+}
+
+#[derive(Debug)]
 pub struct Environment {
-    values: HashMap<Symbol, Locker<Expression>>,
+    values: SymbolMap<Locker<Expression>>,
+    // The bindings of the locals of the function this environment is a call
+    // to (if it's known), by slot
+    layout: Option<Arc<Layout>>,
+    locals: Vec<Option<Locker<Expression>>>,
     // This unreadable memory model might cause issues going forward
     parents: Vec<ParentEnvironment>,
     // Whether this environment is a "shadow environment" -- that is, whether
     // it defers local assignment to the first non-namespaced parent.
     shadow: bool,
+    resolutions: Resolutions,
+    scope: OnceLock<Arc<Scope>>,
+    _allocation: Allocation,
+}
+
+// Clones get bindings of their own (holding the same values), so assigning in
+// one doesn't change the other
+impl Clone for Environment {
+    fn clone(&self) -> Self {
+        let copy = |lock: &Locker<Expression>| {
+            let exp = match lock.read() {
+                Ok(exp) => exp.clone(),
+                Err(_) => Expression::nil(),
+            };
+            Locker::new(exp)
+        };
+        Self {
+            values: self
+                .values
+                .iter()
+                .map(|(symbol, lock)| (*symbol, copy(lock)))
+                .collect(),
+            layout: self.layout.clone(),
+            locals: self
+                .locals
+                .iter()
+                .map(|lock| lock.as_ref().map(copy))
+                .collect(),
+            parents: self.parents.clone(),
+            shadow: self.shadow,
+            resolutions: self.resolutions.clone(),
+            scope: self.scope.clone(),
+            _allocation: Allocation::new(),
+        }
+    }
 }
 
 impl Environment {
@@ -29,9 +178,14 @@ impl Environment {
 
     pub fn root() -> Self {
         Self {
-            values: HashMap::new(),
+            values: SymbolMap::default(),
+            layout: None,
+            locals: vec![],
             parents: vec![],
             shadow: false,
+            resolutions: Resolutions::default(),
+            scope: OnceLock::new(),
+            _allocation: Allocation::new(),
         }
     }
 
@@ -45,129 +199,306 @@ impl Environment {
         self
     }
 
-    fn get_literal(symbol: &Symbol) -> Option<Value> {
-        use Operator::*;
-
-        match symbol.string_value().as_str() {
-            "nil" => Some(Value::List(vec![])),
-            "t" | "true" => Some(Value::True),
-            "quote" => Some(Value::Operator(Quote)),
-            "atom" => Some(Value::Operator(Atom)),
-            "eq" => Some(Value::Operator(Eq)),
-            "car" => Some(Value::Operator(Car)),
-            "cdr" => Some(Value::Operator(Cdr)),
-            "cons" => Some(Value::Operator(Cons)),
-            "cond" => Some(Value::Operator(Cond)),
-            "export" => Some(Value::Operator(Export)),
-            "let" => Some(Value::Operator(Let)),
-            "sum" => Some(Value::Operator(Sum)),
-            "prod" => Some(Value::Operator(Prod)),
-            "exp" => Some(Value::Operator(Exp)),
-            "modulo" => Some(Value::Operator(Modulo)),
-            "gt" => Some(Value::Operator(Gt)),
-            "ge" => Some(Value::Operator(Ge)),
-            "type" => Some(Value::Operator(Type)),
-            "disp" => Some(Value::Operator(Disp)),
-            "import" => Some(Value::Operator(Import)),
-            "eval" => Some(Value::Operator(Eval)),
-            "while" => Some(Value::Operator(While)),
-            "macro" => Some(Value::Operator(Macro)),
-            "lambda" => Some(Value::Operator(Lambda)),
-            "list" => Some(Value::Operator(List)),
-            "catch" => Some(Value::Operator(Catch)),
-            "throw" => Some(Value::Operator(Throw)),
-            "format" => Some(Value::Operator(Format)),
-            "parse" => Some(Value::Operator(Parse)),
-            "length" => Some(Value::Operator(Length)),
-            "append" => Some(Value::Operator(Append)),
-            "do" => Some(Value::Operator(Do)),
-            "floor" => Some(Value::Operator(Floor)),
-            "rand" => Some(Value::Operator(Rand)),
-            "equiv" => Some(Value::Operator(Equiv)),
-            "nth" => Some(Value::Operator(Nth)),
+    /// Keeps the bindings of the locals in `layout` in slots, which compiled
+    /// code can read directly (see `local`).
+    pub fn with_layout(mut self, layout: Arc<Layout>) -> Self {
+        self.locals = vec![None; layout.len()];
+        self.layout = Some(layout);
+        self
+    }
+
+    /// The binding in `slot`, if this environment keeps the locals of
+    /// `layout` and something's been bound there.
+    pub fn local(&self, layout: &Arc<Layout>, slot: usize) -> Option<Locker<Expression>> {
+        match &self.layout {
+            Some(own) if Arc::ptr_eq(own, layout) => self.locals[slot].clone(),
+            _ => None,
+        }
+    }
+
+    /// The environment that code compiled for `layout` running here resolves
+    /// everything it doesn't bind through, if this is a call laid out as
+    /// `layout` and nothing else can be found from here: it binds no other
+    /// names, and has a single parent that isn't namespaced.
+    pub fn static_parent(&self, layout: &Arc<Layout>) -> Option<Locker<Environment>> {
+        match (&self.layout, self.parents.as_slice()) {
+            (Some(own), [parent])
+                if Arc::ptr_eq(own, layout)
+                    && !self.shadow
+                    && self.values.is_empty()
+                    && parent.namespace.is_none() =>
+            {
+                Some(parent.environment.clone())
+            }
             _ => None,
         }
     }
 
+    /// The layouts of the calls that `env` is nested in, innermost first
+    /// (starting with its own, if it's a call), for as far as nothing else
+    /// could be found through them (see `static_parent`).
+    pub fn enclosing_layouts(env: &Locker<Environment>) -> Vec<Arc<Layout>> {
+        let mut layouts = vec![];
+        let mut env = env.clone();
+        loop {
+            let parent = match env.read() {
+                Ok(env) => match &env.layout {
+                    Some(layout) => env
+                        .static_parent(layout)
+                        .map(|parent| (layout.clone(), parent)),
+                    None => None,
+                },
+                Err(_) => None,
+            };
+            match parent {
+                Some((layout, parent)) => {
+                    layouts.push(layout);
+                    env = parent;
+                }
+                None => return layouts,
+            }
+        }
+    }
+
+    /// Resolves `symbol` (which has no namespace) from `env`, reusing what
+    /// `cache` last resolved it to from there if nothing has changed since.
+    pub(crate) fn resolve_cached(
+        env: &Locker<Environment>,
+        symbol: &Symbol,
+        cache: &BindingCache,
+    ) -> Result<Option<Locker<Expression>>, Exception> {
+        if let Some(cached) = &*cache.0.lock().unwrap() {
+            if cached.env.as_ptr() as *const () as usize == env.id()
+                && cached.scope.epoch() == cached.epoch
+            {
+                return Ok(Some(cached.binding.clone()));
+            }
+        }
+        let environment = env.read()?;
+        let scope = environment.scope().clone();
+        let epoch = scope.epoch();
+        // A new binding here now has to move the scope on too
+        environment
+            .resolutions
+            .shared
+            .store(true, Ordering::Relaxed);
+        let resolved = environment.resolve_symbol(symbol, None);
+        if let Some((binding, _)) = &resolved {
+            *cache.0.lock().unwrap() = Some(CachedBinding {
+                env: env.downgrade(),
+                scope,
+                epoch,
+                binding: binding.clone(),
+            });
+        }
+        Ok(resolved.map(|(binding, _)| binding))
+    }
+
+    fn binding(&self, symbol: &Symbol) -> Option<&Locker<Expression>> {
+        match self.layout.as_ref().and_then(|layout| layout.slot(symbol)) {
+            Some(slot) => self.locals[slot].as_ref(),
+            None => self.values.get(symbol),
+        }
+    }
+
+    fn bind(&mut self, symbol: Symbol, lock: Locker<Expression>) {
+        match self.layout.as_ref().and_then(|layout| layout.slot(&symbol)) {
+            Some(slot) => self.locals[slot] = Some(lock),
+            None => {
+                self.values.insert(symbol, lock);
+            }
+        }
+    }
+
+    // Every binding made here, locals included
+    fn bindings(&self) -> impl Iterator<Item = (Symbol, &Locker<Expression>)> {
+        let locals = self.layout.iter().flat_map(move |layout| {
+            layout
+                .symbols()
+                .iter()
+                .zip(self.locals.iter())
+                .filter_map(|(symbol, lock)| lock.as_ref().map(|lock| (*symbol, lock)))
+        });
+        self.values
+            .iter()
+            .map(|(symbol, lock)| (*symbol, lock))
+            .chain(locals)
+    }
+
+    fn scope(&self) -> &Arc<Scope> {
+        self.scope.get_or_init(Default::default)
+    }
+
     fn resolve_symbol(
         &self,
         symbol: &Symbol,
-        namespace: Option<String>,
+        namespace: Option<Symbol>,
     ) -> Option<(Locker<Expression>, usize)> {
-        if namespace == None {
-            if let Some(value) = self.values.get(&symbol) {
+        if namespace.is_none() {
+            if let Some(value) = self.binding(symbol) {
                 return Some((value.clone(), 0));
             }
+            if let Some(resolution) = self.resolutions.entries.lock().unwrap().get(symbol) {
+                if resolution.epoch == self.scope().epoch() {
+                    return Some((resolution.exp.clone(), resolution.depth));
+                }
+            }
         } else {
             for parent in self.parents.iter() {
                 if namespace == parent.namespace {
-                    return parent
-                        .environment
-                        .read()
-                        .unwrap()
-                        .resolve_symbol(symbol, None);
+                    return Self::resolve_in(&parent.environment, symbol);
                 }
             }
         }
+
+        // Read before resolving, so that a binding introduced in the meantime
+        // invalidates the result
+        let epoch = self.scope().epoch();
         let mut best_match: (Option<Locker<Expression>>, usize) = (None, 0);
         for parent in self.parents.iter() {
             if parent.namespace.is_some() {
                 continue;
             }
-            if let Some((exp, depth)) = parent
-                .environment
-                .read()
-                .unwrap()
-                .resolve_symbol(symbol, None)
-            {
+            if let Some((exp, depth)) = Self::resolve_in(&parent.environment, symbol) {
                 if best_match.0.is_none() || depth < best_match.1 {
                     best_match = (Some(exp), depth);
                 }
             }
         }
-        if let Some(exp) = best_match.0 {
-            return Some((exp, best_match.1 + 1));
-        }
-        match Self::get_literal(symbol) {
-            Some(value) => Some((Locker::new(Expression::new(value)), 9999)),
-            None => None,
+        let resolved = match best_match.0 {
+            Some(exp) => (exp, best_match.1 + 1),
+            None => (Locker::new(Expression::new(symbol.literal()?)), 9999),
+        };
+        if namespace.is_none() {
+            self.resolutions.entries.lock().unwrap().insert(
+                *symbol,
+                Resolution {
+                    exp: resolved.0.clone(),
+                    depth: resolved.1,
+                    epoch,
+                },
+            );
         }
+        Some(resolved)
     }
 
-    fn extract_components(symbol: &Symbol) -> (Option<String>, Symbol) {
-        let components: Vec<&str> = symbol.string_value().split("::").collect();
-
-        match components.len() {
-            1 => (None, Symbol::from_str(components.get(0).unwrap())),
-            _ => (
-                Some(components.get(0).unwrap().to_string()),
-                Symbol::from_str(
-                    &components
-                        .iter()
-                        .skip(1)
-                        .map(|x| x.to_string())
-                        .collect::<Vec<String>>()
-                        .join("::"),
-                ),
-            ),
-        }
+    fn resolve_in(parent: &Locker<Self>, symbol: &Symbol) -> Option<(Locker<Expression>, usize)> {
+        let parent = parent.read().unwrap();
+        parent.resolutions.shared.store(true, Ordering::Relaxed);
+        parent.resolve_symbol(symbol, None)
     }
 
     pub fn lookup(&self, symbol: &Symbol) -> Option<Locker<Expression>> {
-        let (namespace, identifier) = Self::extract_components(symbol);
-        match self.resolve_symbol(&identifier, namespace) {
-            Some((exp, _)) => Some(exp),
-            None => None,
+        self.resolve_symbol(&symbol.identifier(), symbol.namespace())
+            .map(|(exp, _)| exp)
+    }
+
+    /// The names `symbol` may have been a typo of: names bound where this
+    /// environment can see them, and builtins.
+    pub fn suggestions(&self, symbol: &Symbol) -> Vec<String> {
+        let mut names = self.names();
+        names.extend(Symbol::builtin_names().map(String::from));
+        suggestions::suggest(symbol.string_value(), names.iter().map(String::as_str))
+    }
+
+    /// Every name bound where this environment can see it, including names
+    /// in namespaced parents (as `namespace::name`).
+    pub fn names(&self) -> Vec<String> {
+        let mut names = vec![];
+        self.collect_names(None, &mut HashSet::new(), &mut names);
+        names
+    }
+
+    fn collect_names(
+        &self,
+        namespace: Option<&str>,
+        visited: &mut HashSet<usize>,
+        names: &mut Vec<String>,
+    ) {
+        for (symbol, _) in self.bindings() {
+            names.push(match namespace {
+                Some(namespace) => format!("{}::{}", namespace, symbol),
+                None => symbol.to_string(),
+            });
+        }
+        for parent in self.parents.iter() {
+            // Namespaces only go one level deep
+            let namespace = match (namespace, &parent.namespace) {
+                (None, Some(inner)) => Some(inner.string_value().as_str()),
+                (namespace, None) => namespace,
+                (Some(_), Some(_)) => continue,
+            };
+            // Parents can be shared (and reached more than one way)
+            if !visited.insert(parent.environment.id()) {
+                continue;
+            }
+            if let Ok(environment) = parent.environment.read() {
+                environment.collect_names(namespace, visited, names);
+            }
         }
     }
 
+    /// Makes the values of `parent` available (as `namespace::name`, if it's
+    /// given). Adding a parent that's already there under the same namespace
+    /// does nothing.
     pub fn add_parent(&mut self, parent: Locker<Self>, namespace: Option<String>) {
+        self.push_parent(parent, namespace, false);
+    }
+
+    /// Makes the values of the imported module `module` available, like
+    /// `add_parent`. Unlike other parents, modules are never assigned into:
+    /// an `export` that would reach one binds here instead.
+    pub fn add_import(&mut self, module: Locker<Self>, namespace: Option<String>) {
+        self.push_parent(module, namespace, true);
+    }
+
+    fn push_parent(&mut self, parent: Locker<Self>, namespace: Option<String>, imported: bool) {
+        let namespace = namespace.map(Symbol::new);
+        if self.parents.iter().any(|existing| {
+            existing.environment.id() == parent.id() && existing.namespace == namespace
+        }) {
+            return;
+        }
+        // Environments take on the scope of their first parent; any other
+        // scope they resolve through has to tell them when it moves on
+        let parent_scope = match parent.read() {
+            Ok(parent) => parent.scope().clone(),
+            Err(_) => Arc::new(Scope::default()),
+        };
+        if self.parents.is_empty() && self.scope.get().is_none() {
+            let _ = self.scope.set(parent_scope);
+        } else if !Arc::ptr_eq(self.scope(), &parent_scope) {
+            parent_scope.add_dependent(self.scope());
+        }
+        // The new parent may shadow anything resolved through the others
+        if self.resolutions.shared.load(Ordering::Relaxed) {
+            self.scope().invalidate();
+        }
+        self.resolutions.entries.lock().unwrap().clear();
         self.parents.push(ParentEnvironment {
             namespace,
             environment: parent,
+            imported,
         });
     }
 
+    pub(crate) fn trace(&self, tracer: &mut Tracer) {
+        for (_, exp) in self.bindings() {
+            tracer.binding(exp);
+        }
+        for parent in self.parents.iter() {
+            tracer.environment(&parent.environment);
+        }
+        match self.resolutions.entries.try_lock() {
+            Ok(entries) => {
+                for resolution in entries.values() {
+                    tracer.binding(&resolution.exp);
+                }
+            }
+            Err(_) => tracer.opaque(),
+        }
+    }
+
     pub fn assign(
         &mut self,
         symbol: Symbol,
@@ -175,7 +506,7 @@ impl Environment {
         only_local: bool,
         snapshot: Locker<CallSnapshot>,
     ) -> Result<Locker<Expression>, Exception> {
-        let (namespace, identifier) = Self::extract_components(&symbol);
+        let (namespace, identifier) = (symbol.namespace(), symbol.identifier());
 
         if only_local && namespace.is_some() {
             exp!(
@@ -185,15 +516,30 @@ impl Environment {
             )
         }
 
+        let assignable = self.parents.iter().any(|parent| !parent.imported);
         if !self.shadow
-            && (only_local || self.values.contains_key(&identifier) || self.parents.is_empty())
+            && (only_local
+                || self.binding(&identifier).is_some()
+                || self.parents.is_empty()
+                || (namespace.is_none() && !assignable))
         {
+            // Rebinding a name writes into its binding, which anything that
+            // resolved it already holds
+            if let Some(lock) = self.binding(&identifier) {
+                *lock.write()? = exp;
+                return Ok(lock.clone());
+            }
+            // Resolutions cached through this environment may point at a
+            // binding the new one shadows
+            if self.resolutions.shared.load(Ordering::Relaxed) {
+                self.scope().invalidate();
+            }
             let lock = Locker::new(exp);
-            self.values.insert(identifier, lock.clone());
+            self.bind(identifier, lock.clone());
             Ok(lock)
         } else {
             for parent in self.parents.iter() {
-                if parent.namespace == namespace {
+                if parent.namespace == namespace && !parent.imported {
                     return parent
                         .environment
                         .write()
@@ -201,8 +547,22 @@ impl Environment {
                         .assign(identifier, exp, only_local, snapshot);
                 }
             }
+            if let Some(namespace) = namespace.filter(|namespace| {
+                self.parents
+                    .iter()
+                    .any(|parent| parent.namespace == Some(*namespace))
+            }) {
+                exp!(
+                    EV::Assignment(symbol, exp),
+                    snapshot,
+                    format!(
+                        "`{}` is an imported module (modules can only be assigned into by their own code)",
+                        namespace
+                    )
+                )
+            }
             exp!(EV::Assignment(symbol, exp), snapshot, format!("could not find suitable environment for assignment (namespace `{}` not available for assignment)", match namespace {
-                Some(value) => value,
+                Some(value) => value.to_string(),
                 None => "no namespace".to_string(),
             }))
         }
@@ -214,16 +574,15 @@ impl fmt::Display for Environment {
         write!(
             f,
             "[values: {}]\n{}\nimported namespaces: {}",
-            self.values.len(),
-            self.values
-                .iter()
+            self.bindings().count(),
+            self.bindings()
                 .map(|(k, v)| format!("{} := {}", k, v.read().unwrap()))
                 .collect::<Vec<String>>()
                 .join("\n"),
             self.parents
                 .iter()
                 .map(|p| match &p.namespace {
-                    Some(val) => val.clone(),
+                    Some(val) => val.to_string(),
                     None => "(directly injected)".to_string(),
                 })
                 .collect::<Vec<String>>()
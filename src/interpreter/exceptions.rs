@@ -1,22 +1,37 @@
+use crate::interpreter::heap::Tracer;
+use crate::interpreter::json::Json;
+use crate::interpreter::suggestions;
+use crate::Restart;
 use crate::{
-    parser, CallSnapshot, Environment, Expression, Keyword, SourcePosition, Symbol, Value,
+    parser, CallSnapshot, Environment, Expression, Frame, Keyword, Map, Number, SourcePosition,
+    Symbol, Value,
 };
 use ansi_term::{Color, Style};
+use std::cmp::Ordering;
 use std::error::Error;
 use std::fmt;
+use std::str::FromStr;
 
 use crate::Locker;
 
 #[macro_export]
 macro_rules! exp {
     ($value:expr) => {
-        return Err(Exception::new($value, None, None));
+        return Err($crate::Exception::new($value, None, None))
     };
     ($value:expr, $snapshot:expr) => {
-        return Err(Exception::new($value, Some($snapshot.clone()), None));
+        return Err($crate::Exception::new(
+            $value,
+            Some($snapshot.clone()),
+            None,
+        ))
     };
     ($value:expr, $snapshot:expr, $note:expr) => {
-        return Err(Exception::new($value, Some($snapshot.clone()), Some($note)));
+        return Err($crate::Exception::new(
+            $value,
+            Some($snapshot.clone()),
+            Some($note),
+        ))
     };
 }
 
@@ -42,15 +57,26 @@ macro_rules! exp_assert {
 #[derive(Debug, Clone)]
 pub enum ExceptionValue {
     Other(Expression),
-    UndefinedSymbol(Symbol),
+    // The symbol, and the names in scope it may have been a typo of
+    UndefinedSymbol(Symbol, Vec<String>),
     ArgumentMismatch(usize, String),
     InvalidArgument,
     Syntax,
     InvalidIncludePath(String),
+    // The locations of the modules that import each other, starting and
+    // ending with the same one
+    ImportCycle(Vec<String>),
     InvalidOperator(Value),
     StackOverflow,
     Assignment(Symbol, Expression),
     Concurrency,
+    Interrupted,
+    StepLimit,
+    TimeLimit,
+    LengthLimit,
+    // Unwinds to the `restart-case` that established the restart, which
+    // calls it with the arguments
+    Restart(Restart, Vec<Expression>),
 }
 
 impl ExceptionValue {
@@ -59,9 +85,11 @@ impl ExceptionValue {
 
         match self {
             Other(exp) => format!("{}", exp),
-            UndefinedSymbol(symbol) => format!(
-                "the symbol `{}` has no assigned value (did you mean to quote this symbol?)",
-                symbol
+            UndefinedSymbol(symbol, suggestions) => format!(
+                "the symbol `{}` has no assigned value ({})",
+                symbol,
+                suggestions::did_you_mean(suggestions)
+                    .unwrap_or_else(|| "did you mean to quote this symbol?".to_string())
             ),
             ArgumentMismatch(given, expected) => format!(
                 "wrong number of arguments: {} required, but {} given",
@@ -70,18 +98,88 @@ impl ExceptionValue {
             InvalidArgument => String::from("the arguments to this function are invalid"),
             Syntax => String::from("the syntax of this code is incorrect"),
             InvalidIncludePath(path) => format!("no code is available for import from `{}`", path),
+            ImportCycle(chain) => {
+                let mut explanation = String::from("import cycle:");
+                for (index, location) in chain.iter().enumerate() {
+                    explanation += match index {
+                        0 => " ",
+                        1 => " imports ",
+                        _ => ", which imports ",
+                    };
+                    explanation += &format!("`{}`", location);
+                }
+                explanation
+            }
             InvalidOperator(value) => format!(
                 "`{}` is not a valid list operator (did you mean to quote this list?)",
                 value
             ),
-            StackOverflow => "the call stack exceeded the limit (1000)".to_string(),
+            StackOverflow => "the call stack exceeded its depth limit".to_string(),
             Assignment(sym, exp) => format!("could not assign `{}` to `{}`", sym, exp),
             Concurrency => {
                 "something went wrong when evaluating this expression concurrently".to_string()
             }
+            Interrupted => "the evaluation of this expression was interrupted".to_string(),
+            StepLimit => "the evaluation exceeded its step limit".to_string(),
+            TimeLimit => "the evaluation exceeded its time limit".to_string(),
+            LengthLimit => "this value would exceed the length limit".to_string(),
+            Restart(restart, _) => format!(
+                "the restart `{}` was invoked outside of the code that established it",
+                restart.name
+            ),
+        }
+    }
+
+    /// What the exception carries besides its kind, as Turtle data: the
+    /// thrown value, the symbol that was undefined, a map of the `:given` and
+    /// `:expected` argument counts, and so on (nil if there's nothing).
+    pub fn payload(&self) -> Expression {
+        use ExceptionValue::*;
+
+        let entry = |name: &str| Value::Keyword(Keyword::from_str(name));
+        match self {
+            Other(exp) => exp.clone(),
+            UndefinedSymbol(symbol, _) => Expression::new(Value::Symbol(*symbol)),
+            ArgumentMismatch(given, expected) => Expression::new(Value::Map(
+                Map::new()
+                    .insert(
+                        entry("given"),
+                        Expression::new(Value::Number(Number::from(*given))),
+                    )
+                    .insert(
+                        entry("expected"),
+                        Expression::new(Value::Text(expected.clone())),
+                    ),
+            )),
+            InvalidIncludePath(path) => Expression::new(Value::Text(path.clone())),
+            ImportCycle(chain) => Expression::new(Value::List(
+                chain
+                    .iter()
+                    .map(|location| Expression::new(Value::Text(location.clone())))
+                    .collect(),
+            )),
+            InvalidOperator(value) => Expression::new(value.clone()),
+            Assignment(symbol, exp) => Expression::new(Value::Map(
+                Map::new()
+                    .insert(entry("symbol"), Expression::new(Value::Symbol(*symbol)))
+                    .insert(entry("value"), exp.clone()),
+            )),
+            _ => Expression::nil(),
         }
     }
 
+    /// Whether handlers established with `handler-bind` are offered the
+    /// exception: everything except running out of resources or being
+    /// cancelled (where handling it in place would fail again), and restarts.
+    pub fn is_condition(&self) -> bool {
+        use ExceptionValue::*;
+
+        !matches!(
+            self,
+            StackOverflow | Concurrency | Interrupted | StepLimit | TimeLimit | Restart(_, _)
+        )
+    }
+
     pub fn into_expression(self) -> Expression {
         use ExceptionValue::*;
 
@@ -89,7 +187,7 @@ impl ExceptionValue {
 
         match self {
             Other(expression) => expression,
-            UndefinedSymbol(_) => {
+            UndefinedSymbol(_, _) => {
                 Expression::new(Value::Keyword(Keyword::from_str("undefined-symbol-exp")))
             }
             ArgumentMismatch(_, _) => {
@@ -102,6 +200,9 @@ impl ExceptionValue {
             InvalidIncludePath(_) => Expression::new(Value::Keyword(Keyword::from_str(
                 "invalid-include-path-exp",
             ))),
+            ImportCycle(_) => {
+                Expression::new(Value::Keyword(Keyword::from_str("import-cycle-exp")))
+            }
             InvalidOperator(_) => {
                 Expression::new(Value::Keyword(Keyword::from_str("invalid-operator-exp")))
             }
@@ -112,6 +213,11 @@ impl ExceptionValue {
                 Expression::new(Value::Keyword(Keyword::from_str("assignment-exp")))
             }
             Concurrency => Expression::new(Value::Keyword(Keyword::from_str("concurrency-exp"))),
+            Interrupted => Expression::new(Value::Keyword(Keyword::from_str("interrupted-exp"))),
+            StepLimit => Expression::new(Value::Keyword(Keyword::from_str("step-limit-exp"))),
+            TimeLimit => Expression::new(Value::Keyword(Keyword::from_str("time-limit-exp"))),
+            LengthLimit => Expression::new(Value::Keyword(Keyword::from_str("length-limit-exp"))),
+            Restart(_, _) => Expression::new(Value::Keyword(Keyword::from_str("restart-exp"))),
         }
     }
 }
@@ -122,12 +228,57 @@ impl fmt::Display for ExceptionValue {
     }
 }
 
+/// How exceptions are rendered for people (or programs) to read.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum ErrorFormat {
+    /// Colored, with the code each frame points at underlined.
+    Pretty,
+    /// The same as `Pretty`, without colors or other ANSI escape codes.
+    Plain,
+    /// A single line of JSON (see `Exception::to_json`).
+    Json,
+}
+
+// `style`, unless formatting to `f` without colors (which exceptions, and the
+// snapshots and sources in them, are with `{:#}`)
+pub(crate) fn styled(f: &fmt::Formatter<'_>, style: Style) -> Style {
+    match f.alternate() {
+        true => Style::new(),
+        false => style,
+    }
+}
+
+impl FromStr for ErrorFormat {
+    type Err = String;
+
+    fn from_str(val: &str) -> Result<Self, Self::Err> {
+        match val {
+            "pretty" => Ok(ErrorFormat::Pretty),
+            "plain" => Ok(ErrorFormat::Plain),
+            "json" => Ok(ErrorFormat::Json),
+            _ => Err(format!(
+                "`{}` is not an error format (expected `pretty`, `plain` or `json`)",
+                val
+            )),
+        }
+    }
+}
+
+// Boxed, so that the `Result`s exceptions are returned in stay small
+#[derive(Debug, Clone)]
+pub struct Exception(Box<Thrown>);
+
 #[derive(Debug, Clone)]
-pub struct Exception {
+struct Thrown {
     value: ExceptionValue,
     snapshot: Option<Locker<CallSnapshot>>,
     additional_sources: Vec<SourcePosition>,
     note: Option<String>,
+    // The exception that was being cleaned up after when this one was thrown
+    cause: Option<Exception>,
+    // Whether the exception has been offered to the handlers around where it
+    // was thrown
+    signalled: bool,
 }
 
 impl Exception {
@@ -136,34 +287,156 @@ impl Exception {
         snapshot: Option<Locker<CallSnapshot>>,
         note: Option<String>,
     ) -> Self {
-        Exception {
+        Exception(Box::new(Thrown {
             value,
             snapshot,
             note,
             additional_sources: vec![],
+            cause: None,
+            signalled: false,
+        }))
+    }
+
+    /// Also points the exception at `source` (e.g. for code that fails before
+    /// it has a snapshot).
+    pub fn with_source(mut self, source: SourcePosition) -> Self {
+        self.0.additional_sources.push(source);
+        self
+    }
+
+    /// The exception, thrown from `snapshot` unless it says where it was
+    /// thrown from already.
+    pub(crate) fn or_at(mut self, snapshot: &Locker<CallSnapshot>) -> Self {
+        if self.0.snapshot.is_none() {
+            self.0.snapshot = Some(snapshot.clone());
+        }
+        self
+    }
+
+    /// The calls that led to the exception, outermost first.
+    pub fn backtrace(&self) -> Vec<Frame> {
+        match &self.0.snapshot {
+            Some(snapshot) => match snapshot.read() {
+                Ok(snapshot) => snapshot.backtrace(),
+                Err(_) => vec![],
+            },
+            None => vec![],
+        }
+    }
+
+    /// Records that the exception was thrown while cleaning up after `cause`
+    /// (after any cause it already has).
+    pub fn with_cause(mut self, cause: Exception) -> Self {
+        self.0.cause = Some(match self.0.cause.take() {
+            Some(earlier) => earlier.with_cause(cause),
+            None => cause,
+        });
+        self
+    }
+
+    pub fn cause(&self) -> Option<&Exception> {
+        self.0.cause.as_ref()
+    }
+
+    pub(crate) fn is_signalled(&self) -> bool {
+        self.0.signalled
+    }
+
+    pub(crate) fn set_signalled(&mut self, signalled: bool) {
+        self.0.signalled = signalled;
+    }
+
+    pub fn value(&self) -> &ExceptionValue {
+        &self.0.value
+    }
+
+    pub fn note(&self) -> Option<&str> {
+        self.0.note.as_deref()
+    }
+
+    /// The exception as JSON, in a schema that stays stable across versions:
+    /// an object with the `kind` (as Turtle would print it), the `message`,
+    /// the `note` (or null), the `primary` source position (where it was
+    /// thrown, or null) and any `additional` ones, the `backtrace` (outermost frame first; see
+    /// `Frame::to_json`), and the `cause` (an exception in the same form, or
+    /// null). Source positions are as `SourcePosition::to_json` describes.
+    pub fn to_json(&self) -> Json {
+        let backtrace = self.backtrace();
+        // Exceptions thrown before there's a snapshot (like syntax errors)
+        // point at their first additional source instead
+        let mut additional = self.0.additional_sources.iter();
+        let primary = match backtrace.last() {
+            Some(frame) => frame.source.as_ref(),
+            None => additional.next(),
+        };
+        Json::Object(vec![
+            (
+                "kind",
+                format!("{:#}", self.0.value.clone().into_expression()).into(),
+            ),
+            ("message", self.0.value.explain().into()),
+            ("note", self.0.note.clone().into()),
+            ("primary", primary.map(|source| source.to_json()).into()),
+            (
+                "additional",
+                Json::Array(additional.map(|source| source.to_json()).collect()),
+            ),
+            (
+                "backtrace",
+                Json::Array(backtrace.iter().map(|frame| frame.to_json()).collect()),
+            ),
+            (
+                "cause",
+                self.0.cause.as_ref().map(|cause| cause.to_json()).into(),
+            ),
+        ])
+    }
+
+    /// The exception as text in `format`.
+    pub fn render(&self, format: ErrorFormat) -> String {
+        match format {
+            ErrorFormat::Pretty => format!("{}", self),
+            ErrorFormat::Plain => format!("{:#}", self),
+            ErrorFormat::Json => self.to_json().to_string(),
+        }
+    }
+
+    pub(crate) fn trace(&self, tracer: &mut Tracer) {
+        match &self.0.value {
+            ExceptionValue::Other(exp) | ExceptionValue::Assignment(_, exp) => exp.trace(tracer),
+            ExceptionValue::InvalidOperator(value) => value.trace(tracer),
+            _ => {}
         }
     }
 
     pub fn into_value(self) -> ExceptionValue {
-        self.value
+        self.0.value
     }
 }
 
+// Without the source the code came from, the best that can be done is pest's
+// own description of where parsing failed (`parse` points into the source)
 impl From<pest::error::Error<parser::Rule>> for Exception {
     fn from(err: pest::error::Error<parser::Rule>) -> Self {
-        use pest::error::InputLocation::*;
+        Exception::new(ExceptionValue::Syntax, None, Some(format!("{}", err)))
+    }
+}
 
-        let (_start, _end) = match err.location {
-            Pos(start) => (start, start),
-            Span((start, end)) => (start, end),
-        };
+// Caught exceptions are values too; they're the same if they're of the same
+// kind and say the same thing
+impl PartialEq for Exception {
+    fn eq(&self, other: &Self) -> bool {
+        self.0.value.clone().into_expression() == other.0.value.clone().into_expression()
+            && self.0.note == other.0.note
+    }
+}
 
-        Self {
-            value: ExceptionValue::Syntax,
-            snapshot: None,
-            note: Some(format!("{}", err)),
-            // TODO: find a nice way to extract the text-level information
-            additional_sources: vec![],
+impl PartialOrd for Exception {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        if self == other {
+            Some(Ordering::Equal)
+        } else {
+            None
         }
     }
 }
@@ -173,47 +446,58 @@ impl fmt::Display for Exception {
         writeln!(
             f,
             "{}{}{} {}",
-            Color::Red.bold().paint("error"),
-            Color::Blue.bold().paint(" ┬ "),
+            styled(f, Color::Red.bold()).paint("error"),
+            styled(f, Color::Blue.bold()).paint(" ┬ "),
             Style::new().paint("uncaught exception"),
-            Color::Yellow.paint(format!("{}", self.value.clone().into_expression()))
+            styled(f, Color::Yellow.normal())
+                .paint(format!("{}", self.0.value.clone().into_expression()))
         )?;
 
-        match &self.snapshot {
-            Some(snapshot_lock) => match snapshot_lock.read() {
-                Ok(snapshot) => write!(f, "{}", snapshot)?,
+        if let Some(snapshot_lock) = &self.0.snapshot {
+            match snapshot_lock.read() {
+                Ok(snapshot) => fmt::Display::fmt(&*snapshot, f)?,
                 Err(_) => {
                     write!(
                         f,
                         "{}{}",
-                        Color::Yellow.bold().paint("warning"),
-                        Style::new()
-                            .bold()
+                        styled(f, Color::Yellow.bold()).paint("warning"),
+                        styled(f, Style::new().bold())
                             .paint(": unable to access execution snapshot (are threads locked?)")
                     )?;
                 }
-            },
-            None => {}
+            }
         };
 
-        for addl_source in &self.additional_sources {
-            write!(f, "{}", addl_source)?;
+        for addl_source in &self.0.additional_sources {
+            fmt::Display::fmt(addl_source, f)?;
         }
 
         write!(
             f,
             "      {}{}",
-            Color::Blue.bold().paint("└ "),
-            Style::new().bold().paint(self.value.explain()),
+            styled(f, Color::Blue.bold()).paint("└ "),
+            styled(f, Style::new().bold()).paint(self.0.value.explain()),
         )?;
 
-        match &self.note {
-            Some(note) => write!(
+        if let Some(note) = &self.0.note {
+            write!(
                 f,
                 "\n        {} {}",
-                Style::new().dimmed().paint("note:"),
+                styled(f, Style::new().dimmed()).paint("note:"),
                 note
-            ),
+            )?;
+        }
+
+        match &self.0.cause {
+            Some(cause) => {
+                writeln!(
+                    f,
+                    "\n{}",
+                    styled(f, Style::new().dimmed())
+                        .paint("which was thrown while cleaning up after"),
+                )?;
+                fmt::Display::fmt(cause, f)
+            }
             None => write!(f, ""),
         }
     }
@@ -1,18 +1,23 @@
 use std::fmt;
-use std::sync::mpsc;
+use std::sync::{Arc, RwLockReadGuard};
 
+use crate::interpreter::conditions;
+use crate::interpreter::heap::Tracer;
+use crate::interpreter::vm;
 use crate::Locker;
-use std::thread;
 
 use crate::{
-    exp, exp_assert, CallSnapshot, Environment, Exception, ExceptionValue as EV, SourcePosition,
-    Value,
+    exp, CallSnapshot, Environment, EvaluationHandle, Evaluator, Exception, ExceptionValue as EV,
+    Program, SourcePosition, Value,
 };
 
 #[derive(Debug, Clone)]
 pub struct Expression {
     value: Locker<Value>,
     source: Option<SourcePosition>,
+    // The bytecode this exact expression was compiled to, if it was handed out
+    // by the VM (e.g. as a macro argument), so evaluating it again can reuse it
+    compiled: Option<(Arc<Program>, usize)>,
 }
 
 impl PartialEq for Expression {
@@ -27,6 +32,7 @@ impl Expression {
         Self {
             value: Locker::new(value),
             source: None,
+            compiled: None,
         }
     }
 
@@ -35,6 +41,22 @@ impl Expression {
         self
     }
 
+    pub(crate) fn with_compiled(mut self, program: &Arc<Program>, instruction: usize) -> Self {
+        self.compiled = Some((program.clone(), instruction));
+        self
+    }
+
+    pub(crate) fn compiled(&self) -> Option<&(Arc<Program>, usize)> {
+        self.compiled.as_ref()
+    }
+
+    pub(crate) fn trace(&self, tracer: &mut Tracer) {
+        tracer.value(&self.value);
+        if let Some((program, _)) = &self.compiled {
+            tracer.program(program);
+        }
+    }
+
     pub fn nil() -> Self {
         Self::new(Value::List(vec![]))
     }
@@ -47,6 +69,12 @@ impl Expression {
         self.value.clone()
     }
 
+    /// Reads the value in place, rather than through a new reference to it
+    /// like `value` gives.
+    pub(crate) fn read_value(&self) -> Result<RwLockReadGuard<'_, Value>, Exception> {
+        self.value.read()
+    }
+
     pub fn into_value(self) -> Locker<Value> {
         self.value
     }
@@ -55,33 +83,26 @@ impl Expression {
         &self.source
     }
 
+    /// Submits this expression to `evaluator` to be evaluated on its worker
+    /// thread; see `Evaluator::submit`.
     pub fn eval_async(
         self,
+        evaluator: &Evaluator,
         parent_snapshot: Locker<CallSnapshot>,
         env: Locker<Environment>,
-    ) -> Result<mpsc::Receiver<Result<Self, Exception>>, Exception> {
-        let exp = self;
-        let snap = parent_snapshot.clone();
-        // TODO: do this without clone
-        let (sender, receiver) = mpsc::channel::<Result<Self, Exception>>();
-        if let Ok(th) = thread::Builder::new()
-            .stack_size(64 * 1024 * 1024)
-            .spawn(move || {
-                sender.send(exp.eval(snap, env)).unwrap();
-            })
-        {
-            match th.join() {
-                Ok(_) => {}
-                Err(_) => {
-                    return Err(Exception::new(
-                        EV::Concurrency,
-                        Some(parent_snapshot),
-                        Some("could not wait for thread to finish executing".to_string()),
-                    ))
-                }
-            }
-        };
-        Ok(receiver)
+    ) -> Result<EvaluationHandle, Exception> {
+        evaluator.submit(self, parent_snapshot, env)
+    }
+
+    /// Evaluates this expression by compiling it to bytecode and running it
+    /// on the VM, rather than walking the expression tree.
+    pub fn eval_compiled(
+        &self,
+        parent_snapshot: Locker<CallSnapshot>,
+        env: Locker<Environment>,
+    ) -> Result<Self, Exception> {
+        let program = Arc::new(Program::compile(std::slice::from_ref(self))?);
+        vm::run(&program, parent_snapshot, env)
     }
 
     pub fn eval(
@@ -89,112 +110,132 @@ impl Expression {
         parent_snapshot: Locker<CallSnapshot>,
         env: Locker<Environment>,
     ) -> Result<Self, Exception> {
-        use Value::*;
+        let mut snapshot = CallSnapshot::new(self, &parent_snapshot)?;
+        let mut exp = self.clone();
+        let mut env = env;
+
+        // Expressions in tail position are evaluated by this loop rather than
+        // recursively, so tail calls run in constant (Rust and snapshot) space.
+        loop {
+            match exp.step(snapshot.clone(), env) {
+                Ok(Step::Done(result)) => return Ok(result),
+                Ok(Step::Tail(next, next_env)) => {
+                    snapshot = CallSnapshot::tail(&next, &snapshot)?;
+                    exp = next;
+                    env = next_env;
+                }
+                // Handlers see the exception where it was thrown, before it
+                // unwinds
+                Err(mut err) => {
+                    conditions::signal(&mut err, &snapshot, true)?;
+                    return Err(err);
+                }
+            }
+        }
+    }
 
-        let snapshot = CallSnapshot::new(&self, &parent_snapshot)?;
+    fn step(
+        &self,
+        snapshot: Locker<CallSnapshot>,
+        env: Locker<Environment>,
+    ) -> Result<Step, Exception> {
+        use Value::*;
 
         let snap = || snapshot.clone();
 
         match &*self.value.read().unwrap() {
             List(vals) => {
                 if !vals.is_empty() {
-                    let operator = vals.get(0).unwrap();
+                    let operator = vals.first().unwrap();
                     let arguments: Vec<&Expression> = vals.iter().skip(1).collect();
                     match &*operator.value.read().unwrap() {
-                        Operator(operand) => operand.apply(snapshot, arguments, self, env),
+                        Operator(operand) => operand.step(snapshot, &arguments, self, env),
                         List(_) | Symbol(_) => {
                             let evaled_operator = operator.eval(snap(), env.clone())?;
+                            if evaled_operator == Expression::nil() {
+                                exp!(EV::InvalidOperator(Value::List(vec![])), snapshot)
+                            }
                             let mut new_list = vec![evaled_operator];
                             for arg in arguments {
                                 new_list.push(arg.clone());
                             }
-                            Expression::new(Value::List(new_list)).eval(snap(), env)
+                            let mut call = Expression::new(Value::List(new_list));
+                            call.source = self.source.clone();
+                            Ok(Step::Tail(call, env))
                         }
                         Lambda(function) | Macro(function) => {
-                            let mut scoped_env = match *operator.value.read().unwrap() {
-                                Lambda(_) => Environment::root()
-                                    .with_parent(function.lexical_scope.clone(), None),
-                                Macro(_) => {
-                                    let mut env =
-                                        Environment::root().with_parent(env.clone(), None);
-                                    env.add_parent(function.lexical_scope.clone(), None);
-                                    env
-                                }
-                                _ => unreachable!(),
-                            };
-
-                            if function.collapse_input {
-                                let sym = function.params.get(0).unwrap(); // this unwrap will always be ok; it is enforced by the parser
-                                let args_evaled = {
-                                    let mut list = Vec::new();
-                                    for arg_expr in arguments {
-                                        list.push(match *operator.value.read().unwrap() {
-                                            Lambda { .. } => arg_expr.eval(snap(), env.clone())?,
-                                            Macro { .. } => arg_expr.clone(),
-                                            _ => unreachable!(),
-                                        });
+                            let is_macro = matches!(*operator.value.read().unwrap(), Macro(_));
+                            CallSnapshot::calls(&snapshot, function)?;
+                            let scoped_env = function.call_scope(
+                                is_macro,
+                                &env,
+                                arguments.len(),
+                                |i| match is_macro {
+                                    false => arguments[i].eval(snap(), env.clone()),
+                                    true => Ok(arguments[i].clone()),
+                                },
+                                &snapshot,
+                            )?;
+                            match is_macro {
+                                true => CallSnapshot::expand(&snapshot, self)?,
+                                false => CallSnapshot::enter_function(&snapshot)?,
+                            }
+                            match function.expressions.split_last() {
+                                Some((last, init)) => {
+                                    for exp in init {
+                                        exp.eval(snap(), scoped_env.clone())?;
                                     }
-                                    list
-                                };
-                                let arg = Expression::new(Value::List(args_evaled));
-                                scoped_env.assign(sym.clone(), arg, true, snap())?;
-                            } else {
-                                exp_assert!(
-                                    function.params.len() == arguments.len(),
-                                    EV::ArgumentMismatch(
-                                        arguments.len(),
-                                        format!("{}", function.params.len())
-                                    ),
-                                    snap()
-                                );
-                                for (symbol, arg_expr) in
-                                    function.params.iter().zip(arguments.into_iter())
-                                {
-                                    let arg_evaled = match *operator.value.read().unwrap() {
-                                        Lambda { .. } => arg_expr.eval(snap(), env.clone())?,
-                                        Macro { .. } => arg_expr.clone(),
-                                        _ => unreachable!(),
-                                    };
-                                    scoped_env.assign(symbol.clone(), arg_evaled, true, snap())?;
+                                    Ok(Step::Tail(last.clone(), scoped_env))
                                 }
+                                None => Ok(Step::Done(Expression::nil())),
                             }
-                            if let Macro { .. } = *operator.value.read().unwrap() {
-                                scoped_env = scoped_env.shadow();
-                            };
-                            let mut result = Expression::nil();
-                            let scoped_env_lock = Locker::new(scoped_env);
-                            for exp in &function.expressions {
-                                result = exp.eval(snap(), scoped_env_lock.clone())?;
+                        }
+                        Native(function) => {
+                            let mut evaluated = Vec::with_capacity(arguments.len());
+                            for arg in arguments {
+                                evaluated.push(arg.eval(snap(), env.clone())?);
                             }
-                            Ok(result)
+                            Ok(Step::Done(function.call(evaluated, &snapshot)?))
                         }
                         val => exp!(EV::InvalidOperator(val.clone()), snapshot),
                     }
                 } else {
-                    Ok(self.clone())
+                    Ok(Step::Done(self.clone()))
                 }
             }
-            Symbol(sym) => match env
-                .read()
-                .expect("unable to access environment (are threads locked?)")
-                .lookup(&sym)
-            {
-                Some(exp) => Ok(exp.read().unwrap().clone()), // TODO: make this not need a clone (allow returning pointers)
-                None => exp!(EV::UndefinedSymbol(sym.clone()), snapshot),
-            },
-            _ => Ok(self.clone()),
+            Symbol(sym) => {
+                let env = env
+                    .read()
+                    .expect("unable to access environment (are threads locked?)");
+                match env.lookup(sym) {
+                    Some(exp) => Ok(Step::Done(exp.read().unwrap().clone())), // TODO: make this not need a clone (allow returning pointers)
+                    None => exp!(EV::UndefinedSymbol(*sym, env.suggestions(sym)), snapshot),
+                }
+            }
+            _ => Ok(Step::Done(self.clone())),
         }
     }
 }
 
+/// The outcome of evaluating an expression by a single step: either its final
+/// value, or another expression in tail position that should be evaluated (in
+/// the given environment) in its place.
+pub enum Step {
+    Done(Expression),
+    Tail(Expression, Locker<Environment>),
+}
+
 impl fmt::Display for Expression {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
-        write!(f, "{}", self.value.read().unwrap())
+        fmt::Display::fmt(&*self.value.read().unwrap(), f)
     }
 }
 
 impl PartialOrd for Expression {
     fn partial_cmp(&self, other: &Self) -> Option<std::cmp::Ordering> {
-        self.value.read().unwrap().partial_cmp(&other.value.read().unwrap())
+        self.value
+            .read()
+            .unwrap()
+            .partial_cmp(&other.value.read().unwrap())
     }
 }
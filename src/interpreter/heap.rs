@@ -0,0 +1,321 @@
+use std::collections::HashMap;
+use std::mem;
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex, OnceLock, PoisonError, RwLock, RwLockReadGuard, Weak};
+
+use crate::Locker;
+
+use crate::{Environment, Expression, Program, Value};
+
+/// How many environments may be captured by new closures before the evaluator
+/// runs a collection (between two top-level expressions).
+pub const COLLECTION_THRESHOLD: usize = 1024;
+
+static LIVE_ENVIRONMENTS: AtomicUsize = AtomicUsize::new(0);
+static COLLECTIONS: AtomicUsize = AtomicUsize::new(0);
+static COLLECTED: AtomicUsize = AtomicUsize::new(0);
+static REQUESTED: AtomicBool = AtomicBool::new(false);
+
+// Held (shared) by every evaluation an `Evaluator` runs, and exclusively by
+// collections: references that move while the heap is being traced can make
+// something in use look like garbage
+static WORLD: RwLock<()> = RwLock::new(());
+
+/// A snapshot of the interpreter's memory usage.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct HeapStats {
+    /// Environments currently allocated.
+    pub environments: usize,
+    /// Environments captured by a closure (the only ones that can end up in a
+    /// reference cycle), as of the last collection plus those captured since.
+    pub scopes: usize,
+    /// How many collections have run.
+    pub collections: usize,
+    /// How many unreachable environments collections have cleared.
+    pub collected: usize,
+}
+
+pub fn stats() -> HeapStats {
+    let scopes = registry().lock().unwrap();
+    HeapStats {
+        environments: LIVE_ENVIRONMENTS.load(Ordering::Relaxed),
+        scopes: scopes.scopes.len(),
+        collections: COLLECTIONS.load(Ordering::Relaxed),
+        collected: COLLECTED.load(Ordering::Relaxed),
+    }
+}
+
+/// Keeps count of the live environments; every `Environment` holds one.
+#[derive(Debug)]
+pub(crate) struct Allocation(());
+
+impl Allocation {
+    pub(crate) fn new() -> Self {
+        LIVE_ENVIRONMENTS.fetch_add(1, Ordering::Relaxed);
+        Self(())
+    }
+}
+
+impl Clone for Allocation {
+    fn clone(&self) -> Self {
+        Self::new()
+    }
+}
+
+impl Drop for Allocation {
+    fn drop(&mut self) {
+        LIVE_ENVIRONMENTS.fetch_sub(1, Ordering::Relaxed);
+    }
+}
+
+// Every environment a closure has captured. A function holds on to the
+// environment it was defined in, and that environment usually ends up holding
+// the function, so these are where reference cycles start.
+#[derive(Default)]
+struct Registry {
+    scopes: HashMap<usize, Weak<RwLock<Environment>>>,
+    captured: usize,
+}
+
+fn registry() -> &'static Mutex<Registry> {
+    static REGISTRY: OnceLock<Mutex<Registry>> = OnceLock::new();
+    REGISTRY.get_or_init(|| Mutex::new(Registry::default()))
+}
+
+/// Registers `scope` as the lexical scope of a closure, so that collections
+/// consider it.
+pub(crate) fn track_scope(scope: &Locker<Environment>) {
+    let mut registry = registry().lock().unwrap();
+    // The address may have belonged to an environment that has since been
+    // freed, in which case this is a different one
+    let registered = match registry.scopes.get(&scope.id()) {
+        Some(existing) => existing.strong_count() > 0,
+        None => false,
+    };
+    if !registered {
+        registry.scopes.insert(scope.id(), scope.downgrade());
+        registry.captured += 1;
+    }
+}
+
+/// Whether enough environments have been captured since the last collection
+/// (or code asked for one with `(heap :collect)`) that another one is worth
+/// running.
+pub fn collection_due() -> bool {
+    REQUESTED.load(Ordering::Relaxed) || registry().lock().unwrap().captured >= COLLECTION_THRESHOLD
+}
+
+/// Asks for a collection once the evaluation that's running is done (code
+/// can't collect while it runs itself).
+pub(crate) fn request_collection() {
+    REQUESTED.store(true, Ordering::Relaxed);
+}
+
+/// Marks an evaluation as running until the guard is dropped; collections
+/// wait for it to finish.
+pub(crate) fn evaluating() -> RwLockReadGuard<'static, ()> {
+    WORLD.read().unwrap_or_else(PoisonError::into_inner)
+}
+
+/// Frees environments (and everything they hold) that are only kept alive by
+/// reference cycles, returning how many environments were cleared.
+///
+/// Anything referenced from outside the interpreter's heap (such as a Rust
+/// variable) is left alone. The collection first waits for every evaluation
+/// running on an `Evaluator` to finish, and holds off new ones until it's
+/// done, so it must not be called from inside one.
+pub fn collect() -> usize {
+    let _world = WORLD.write().unwrap_or_else(PoisonError::into_inner);
+    REQUESTED.store(false, Ordering::Relaxed);
+
+    let scopes: Vec<Locker<Environment>> = {
+        let mut registry = registry().lock().unwrap();
+        registry.scopes.retain(|_, scope| scope.strong_count() > 0);
+        registry.captured = 0;
+        registry
+            .scopes
+            .values()
+            .filter_map(Locker::upgrade)
+            .collect()
+    };
+
+    let mut tracer = Tracer::default();
+    for scope in scopes {
+        tracer.environment(&scope);
+    }
+    tracer.trace();
+    let garbage = tracer.garbage();
+
+    // Emptying the unreachable environments breaks every cycle they are part
+    // of; what they held is dropped once the tracer lets go of it too
+    let mut contents = Vec::with_capacity(garbage.len());
+    for env in garbage.iter() {
+        if let Some(mut env) = env.try_write() {
+            contents.push(mem::replace(&mut *env, Environment::root()));
+        }
+    }
+    let collected = contents.len();
+    drop(tracer);
+    drop(garbage);
+    drop(contents);
+
+    COLLECTIONS.fetch_add(1, Ordering::Relaxed);
+    COLLECTED.fetch_add(collected, Ordering::Relaxed);
+    collected
+}
+
+// Something reference counted that can hold references to the interpreter's
+// environments
+#[derive(Clone)]
+enum Node {
+    Environment(Locker<Environment>),
+    Binding(Locker<Expression>),
+    Value(Locker<Value>),
+    Code(Arc<OnceLock<Arc<Program>>>),
+    Program(Arc<Program>),
+}
+
+impl Node {
+    fn strong_count(&self) -> usize {
+        match self {
+            Node::Environment(env) => env.strong_count(),
+            Node::Binding(exp) => exp.strong_count(),
+            Node::Value(value) => value.strong_count(),
+            Node::Code(code) => Arc::strong_count(code),
+            Node::Program(program) => Arc::strong_count(program),
+        }
+    }
+}
+
+struct Traced {
+    node: Node,
+    // How many references to this node were found inside the heap
+    internal: usize,
+    children: Vec<usize>,
+    // Whether the node couldn't be looked inside of (because it was locked),
+    // in which case it's assumed to be in use
+    opaque: bool,
+}
+
+/// Walks everything reachable from the environments captured by closures,
+/// counting the references between them.
+#[derive(Default)]
+pub(crate) struct Tracer {
+    nodes: Vec<Traced>,
+    indices: HashMap<usize, usize>,
+    pending: Vec<usize>,
+    current: Option<usize>,
+}
+
+impl Tracer {
+    pub(crate) fn environment(&mut self, env: &Locker<Environment>) {
+        self.reference(env.id(), || Node::Environment(env.clone()));
+    }
+
+    pub(crate) fn binding(&mut self, exp: &Locker<Expression>) {
+        self.reference(exp.id(), || Node::Binding(exp.clone()));
+    }
+
+    pub(crate) fn value(&mut self, value: &Locker<Value>) {
+        self.reference(value.id(), || Node::Value(value.clone()));
+    }
+
+    pub(crate) fn code(&mut self, code: &Arc<OnceLock<Arc<Program>>>) {
+        self.reference(Arc::as_ptr(code) as usize, || Node::Code(code.clone()));
+    }
+
+    pub(crate) fn program(&mut self, program: &Arc<Program>) {
+        self.reference(Arc::as_ptr(program) as usize, || {
+            Node::Program(program.clone())
+        });
+    }
+
+    /// Marks the node being traced as in use, for when part of it can't be
+    /// looked at.
+    pub(crate) fn opaque(&mut self) {
+        if let Some(current) = self.current {
+            self.nodes[current].opaque = true;
+        }
+    }
+
+    fn reference(&mut self, id: usize, node: impl FnOnce() -> Node) {
+        let index = match self.indices.get(&id) {
+            Some(index) => *index,
+            None => {
+                self.nodes.push(Traced {
+                    node: node(),
+                    internal: 0,
+                    children: vec![],
+                    opaque: false,
+                });
+                let index = self.nodes.len() - 1;
+                self.indices.insert(id, index);
+                self.pending.push(index);
+                index
+            }
+        };
+        if let Some(current) = self.current {
+            self.nodes[index].internal += 1;
+            self.nodes[current].children.push(index);
+        }
+    }
+
+    fn trace(&mut self) {
+        while let Some(index) = self.pending.pop() {
+            self.current = Some(index);
+            let node = self.nodes[index].node.clone();
+            let traced = match &node {
+                Node::Environment(env) => env.try_read().map(|env| env.trace(self)),
+                Node::Binding(exp) => exp.try_read().map(|exp| exp.trace(self)),
+                Node::Value(value) => value.try_read().map(|value| value.trace(self)),
+                Node::Code(code) => {
+                    if let Some(program) = code.get() {
+                        self.program(program);
+                    }
+                    Some(())
+                }
+                Node::Program(program) => {
+                    for exp in program.constants() {
+                        exp.trace(self);
+                    }
+                    for cache in program.caches() {
+                        cache.trace(self);
+                    }
+                    Some(())
+                }
+            };
+            if traced.is_none() {
+                self.opaque();
+            }
+        }
+        self.current = None;
+    }
+
+    // The environments that nothing outside of the traced nodes refers to,
+    // directly or indirectly
+    fn garbage(&self) -> Vec<Locker<Environment>> {
+        let mut reachable = vec![false; self.nodes.len()];
+        // The tracer holds one reference to every node itself
+        let mut pending: Vec<usize> = (0..self.nodes.len())
+            .filter(|index| {
+                let traced = &self.nodes[*index];
+                traced.opaque || traced.node.strong_count() > traced.internal + 1
+            })
+            .collect();
+        while let Some(index) = pending.pop() {
+            if !reachable[index] {
+                reachable[index] = true;
+                pending.extend(self.nodes[index].children.iter().copied());
+            }
+        }
+
+        self.nodes
+            .iter()
+            .zip(reachable)
+            .filter_map(|(traced, reachable)| match (&traced.node, reachable) {
+                (Node::Environment(env), false) => Some(env.clone()),
+                _ => None,
+            })
+            .collect()
+    }
+}
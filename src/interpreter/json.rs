@@ -0,0 +1,107 @@
+use std::fmt;
+
+/// A JSON value, as diagnostics are written (see `Exception::to_json`). It's
+/// printed as compact JSON with its `Display` implementation; object keys
+/// keep the order they're given in.
+#[derive(Debug, Clone, PartialEq)]
+pub enum Json {
+    Null,
+    Number(usize),
+    Text(String),
+    Array(Vec<Json>),
+    Object(Vec<(&'static str, Json)>),
+}
+
+impl Json {
+    /// The value at `key`, if this is an object that has it.
+    pub fn get(&self, key: &str) -> Option<&Json> {
+        match self {
+            Json::Object(entries) => entries
+                .iter()
+                .find(|(name, _)| *name == key)
+                .map(|(_, value)| value),
+            _ => None,
+        }
+    }
+}
+
+impl From<usize> for Json {
+    fn from(val: usize) -> Self {
+        Json::Number(val)
+    }
+}
+
+impl From<String> for Json {
+    fn from(val: String) -> Self {
+        Json::Text(val)
+    }
+}
+
+impl From<&str> for Json {
+    fn from(val: &str) -> Self {
+        Json::Text(val.to_string())
+    }
+}
+
+// `None` is null
+impl<T: Into<Json>> From<Option<T>> for Json {
+    fn from(val: Option<T>) -> Self {
+        match val {
+            Some(val) => val.into(),
+            None => Json::Null,
+        }
+    }
+}
+
+impl From<Vec<Json>> for Json {
+    fn from(val: Vec<Json>) -> Self {
+        Json::Array(val)
+    }
+}
+
+fn write_text(text: &str, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+    write!(f, "\"")?;
+    for c in text.chars() {
+        match c {
+            '"' => write!(f, "\\\"")?,
+            '\\' => write!(f, "\\\\")?,
+            '\n' => write!(f, "\\n")?,
+            '\r' => write!(f, "\\r")?,
+            '\t' => write!(f, "\\t")?,
+            c if c.is_control() => write!(f, "\\u{:04x}", c as u32)?,
+            c => write!(f, "{}", c)?,
+        }
+    }
+    write!(f, "\"")
+}
+
+impl fmt::Display for Json {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Json::Null => write!(f, "null"),
+            Json::Number(val) => write!(f, "{}", val),
+            Json::Text(val) => write_text(val, f),
+            Json::Array(vals) => {
+                write!(f, "[")?;
+                for (i, val) in vals.iter().enumerate() {
+                    if i > 0 {
+                        write!(f, ",")?;
+                    }
+                    write!(f, "{}", val)?;
+                }
+                write!(f, "]")
+            }
+            Json::Object(entries) => {
+                write!(f, "{{")?;
+                for (i, (key, val)) in entries.iter().enumerate() {
+                    if i > 0 {
+                        write!(f, ",")?;
+                    }
+                    write_text(key, f)?;
+                    write!(f, ":{}", val)?;
+                }
+                write!(f, "}}")
+            }
+        }
+    }
+}
@@ -1,7 +1,15 @@
+pub mod bytecode;
 pub mod call_snapshot;
+pub mod conditions;
+pub mod embedding;
 pub mod environment;
+pub mod evaluator;
 pub mod exceptions;
 pub mod expression;
+pub mod heap;
+pub mod json;
 pub mod resolver;
 pub mod source;
+pub mod suggestions;
 pub mod values;
+pub mod vm;
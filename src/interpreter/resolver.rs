@@ -1,89 +1,283 @@
+use crate::interpreter::suggestions;
 use crate::{
     exp, parse, stdlib, CallSnapshot, Environment, Exception, ExceptionValue as EV, Expression,
 };
 use relative_path::RelativePath;
+use std::collections::HashMap;
 use std::env;
+use std::fmt;
 use std::fs;
 use std::path::PathBuf;
+use std::sync::Arc;
 
 use crate::Locker;
 
-pub fn resolve_resource(
-    path: &str,
-    snapshot: Locker<CallSnapshot>,
-    via: &Expression,
-    env: Locker<Environment>,
-) -> Result<Expression, Exception> {
-    let content = match path.starts_with('@') {
-        true => match stdlib::get_std_resource(path) {
-            Some(val) => val,
-            None => exp!(
-                EV::InvalidIncludePath(String::from(path)),
-                snapshot,
-                format!("`{}` is not in the standard library", path)
-            ),
-        },
-        false => {
-            let source_path_opt = match via.source() {
-                Some(source) => match source.location() {
-                    Some(location) => Some(location),
-                    None => None,
-                },
-                None => None,
-            };
-
-            let working_dir = match env::current_dir() {
-                Ok(dir) => dir,
-                Err(_) => exp!(
-                    EV::InvalidIncludePath(String::from(path)),
-                    snapshot,
+/// Finds the modules `import` loads. Hosts can install their own (see
+/// `Interpreter::with_resolver`) to serve modules from memory, from embedded
+/// assets or from a virtual filesystem, or to refuse imports altogether;
+/// otherwise the `DefaultResolver` is used.
+///
+/// Finding a module is split in two: `locate` works out which module a path
+/// refers to, and `load` reads its code. An interpreter only evaluates the
+/// module at a location once, however often it's imported, so `load` isn't
+/// called for modules that are already cached.
+///
+/// Modules that aren't available are reported with a note explaining why,
+/// which is thrown as an `InvalidIncludePath` exception.
+pub trait ModuleResolver: Send + Sync {
+    /// The location of the module at `path`, imported by code from `importer`
+    /// (if it's known where that code came from). The location identifies the
+    /// module; exceptions in it point there, and its own imports are relative
+    /// to it.
+    fn locate(&self, path: &str, importer: Option<&str>) -> Result<String, String>;
+
+    /// The code of the module at `location` (as found by `locate`).
+    fn load(&self, location: &str) -> Result<String, String>;
+}
+
+/// How Turtle finds modules unless told otherwise: paths starting with `@`
+/// are libraries (the standard library, and any registered with
+/// `register_library`), and all others are files relative to the importing
+/// file (or the working directory), located by their canonical path.
+pub struct DefaultResolver {
+    libraries: Locker<HashMap<String, String>>,
+}
+
+impl DefaultResolver {
+    pub fn new() -> Self {
+        Self {
+            libraries: Locker::new(HashMap::new()),
+        }
+    }
+
+    /// Makes `code` importable as the library `name` (which must start with
+    /// `@`), in place of any library of that name (standard ones included).
+    pub fn register_library(&self, name: &str, code: &str) -> Result<(), Exception> {
+        if !name.starts_with('@') {
+            return Err(Exception::new(
+                EV::InvalidIncludePath(name.to_string()),
+                None,
+                Some(format!(
+                    "library names must start with `@` (like `@{}`)",
+                    name
+                )),
+            ));
+        }
+        self.libraries
+            .write()?
+            .insert(name.to_string(), code.to_string());
+        Ok(())
+    }
+
+    // The code of the library at `path`, if there is one
+    fn library(&self, path: &str) -> Result<Option<String>, String> {
+        let libraries = self
+            .libraries
+            .read()
+            .map_err(|_| "the registered libraries could not be read".to_string())?;
+        Ok(match libraries.get(path) {
+            Some(code) => Some(code.clone()),
+            None => stdlib::get_std_resource(path),
+        })
+    }
+
+    fn has_library(&self, path: &str) -> Result<bool, String> {
+        let libraries = self
+            .libraries
+            .read()
+            .map_err(|_| "the registered libraries could not be read".to_string())?;
+        Ok(libraries.contains_key(path) || stdlib::std_resource_paths().any(|name| name == path))
+    }
+
+    fn missing_library(&self, path: &str) -> String {
+        let registered: Vec<String> = match self.libraries.read() {
+            Ok(libraries) => libraries.keys().cloned().collect(),
+            Err(_) => vec![],
+        };
+        let mut candidates: Vec<&str> = stdlib::std_resource_paths().collect();
+        candidates.extend(registered.iter().map(String::as_str));
+        let suggestions = suggestions::suggest(path, candidates);
+        match suggestions::did_you_mean(&suggestions) {
+            Some(question) => format!("`{}` is not in the standard library ({})", path, question),
+            None => format!("`{}` is not in the standard library", path),
+        }
+    }
+
+    fn file(&self, path: &str, importer: Option<&str>) -> Result<String, String> {
+        let working_dir = match env::current_dir() {
+            Ok(dir) => dir,
+            Err(_) => {
+                return Err(
                     "could not establish working directory (the environment is unknown)"
-                        .to_string()
-                ),
-            };
-
-            let relative_dir = match source_path_opt {
-                Some(source_path) => match fs::metadata(&source_path) {
-                    Ok(metadata) => match metadata.is_dir() {
-                        true => PathBuf::from(source_path),
-                        false => match PathBuf::from(source_path).parent() {
-                            Some(parent) => PathBuf::from(parent),
-                            None => working_dir,
-                        },
+                        .to_string(),
+                )
+            }
+        };
+
+        let relative_dir = match importer {
+            Some(source_path) => match fs::metadata(source_path) {
+                Ok(metadata) => match metadata.is_dir() {
+                    true => PathBuf::from(source_path),
+                    false => match PathBuf::from(source_path).parent() {
+                        Some(parent) => PathBuf::from(parent),
+                        None => working_dir,
                     },
-                    Err(_) => working_dir,
                 },
-                None => working_dir,
-            };
-
-            let relative_dir_composed = match RelativePath::from_path(&path) {
-                Ok(relative) => relative,
-                Err(err) => exp!(
-                    EV::InvalidIncludePath(String::from(path)),
-                    snapshot,
-                    format!(
-                        "could not understand include path ({}; all includes must be relative)",
-                        err
-                    )
-                ),
-            };
-
-            match fs::read_to_string(&relative_dir_composed.to_path(relative_dir)) {
-                Ok(value) => value,
-                Err(val) => exp!(
-                    EV::InvalidIncludePath(path.to_string()),
-                    snapshot,
-                    format!("unable to read file ({})", val)
-                ),
+                Err(_) => working_dir,
+            },
+            None => working_dir,
+        };
+
+        let relative_dir_composed = match RelativePath::from_path(&path) {
+            Ok(relative) => relative,
+            Err(err) => {
+                return Err(format!(
+                    "could not understand include path ({}; all includes must be relative)",
+                    err
+                ))
             }
+        };
+
+        let full_path = relative_dir_composed.to_path(relative_dir);
+        match fs::canonicalize(&full_path) {
+            Ok(location) => Ok(location.to_string_lossy().into_owned()),
+            Err(err) => Err(format!("unable to read file ({})", err)),
+        }
+    }
+}
+
+impl ModuleResolver for DefaultResolver {
+    fn locate(&self, path: &str, importer: Option<&str>) -> Result<String, String> {
+        match path.starts_with('@') {
+            true => match self.has_library(path)? {
+                true => Ok(path.to_string()),
+                false => Err(self.missing_library(path)),
+            },
+            false => self.file(path, importer),
+        }
+    }
+
+    fn load(&self, location: &str) -> Result<String, String> {
+        match location.starts_with('@') {
+            true => match self.library(location)? {
+                Some(code) => Ok(code),
+                None => Err(self.missing_library(location)),
+            },
+            false => match fs::read_to_string(location) {
+                Ok(code) => Ok(code),
+                Err(err) => Err(format!("unable to read file ({})", err)),
+            },
+        }
+    }
+}
+
+impl Default for DefaultResolver {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// The modules an interpreter imports: the resolver that finds them, and
+/// those evaluated already (by location, with the environment they were
+/// evaluated in and what they evaluated to), which everything that imports
+/// them shares. Kept in the snapshots of the interpreter's evaluations.
+#[derive(Clone)]
+pub(crate) struct Modules {
+    resolver: Arc<dyn ModuleResolver>,
+    cache: Locker<HashMap<String, (Locker<Environment>, Expression)>>,
+}
+
+impl Modules {
+    pub(crate) fn new(resolver: Arc<dyn ModuleResolver>) -> Self {
+        Self {
+            resolver,
+            cache: Locker::new(HashMap::new()),
+        }
+    }
+
+    /// The same modules, found with `resolver` from now on.
+    pub(crate) fn with_resolver(&self, resolver: Arc<dyn ModuleResolver>) -> Self {
+        Self {
+            resolver,
+            cache: self.cache.clone(),
+        }
+    }
+}
+
+impl fmt::Debug for Modules {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "Modules")
+    }
+}
+
+/// Imports the module at `path` (imported by `via`), returning the
+/// environment it was evaluated in and what it evaluated to. Each module is
+/// only evaluated once; after that, it's shared from the cache, unless
+/// `reload` asks for it to be evaluated again (in the same environment, so
+/// everything that imported it sees the changes). Modules that import
+/// themselves, however indirectly, throw an `ImportCycle` exception.
+pub fn resolve_resource(
+    path: &str,
+    snapshot: Locker<CallSnapshot>,
+    via: &Expression,
+    reload: bool,
+) -> Result<(Locker<Environment>, Expression), Exception> {
+    let importer = match via.source() {
+        Some(source) => source.location(),
+        None => None,
+    };
+    let (modules, importing) = {
+        let snapshot = snapshot.read()?;
+        (snapshot.modules(), snapshot.imports())
+    };
+    let default;
+    let resolver: &dyn ModuleResolver = match &modules {
+        Some(modules) => modules.resolver.as_ref(),
+        None => {
+            default = DefaultResolver::new();
+            &default
         }
     };
+    let location = match resolver.locate(path, importer.as_deref()) {
+        Ok(location) => location,
+        Err(note) => exp!(EV::InvalidIncludePath(String::from(path)), snapshot, note),
+    };
+
+    // The modules being imported, outermost first
+    let mut chain: Vec<String> = importing.iter().cloned().collect();
+    chain.reverse();
+    if let Some(start) = chain.iter().position(|importing| *importing == location) {
+        let mut cycle = chain.split_off(start);
+        cycle.push(location);
+        exp!(EV::ImportCycle(cycle), snapshot)
+    }
 
-    let parsed = parse(&content, &path.to_string())?;
+    let cached = match &modules {
+        Some(modules) => modules.cache.read()?.get(&location).cloned(),
+        None => None,
+    };
+    let env = match cached {
+        Some(cached) if !reload => return Ok(cached),
+        Some((env, _)) => env,
+        None => Locker::new(Environment::root()),
+    };
 
+    let code = match resolver.load(&location) {
+        Ok(code) => code,
+        Err(note) => exp!(EV::InvalidIncludePath(String::from(path)), snapshot, note),
+    };
+    let parsed = parse(&code, &location)?;
+    let snapshot = CallSnapshot::importing(&snapshot, location.clone())?;
     let mut return_val = Expression::nil();
     for exp in parsed {
         return_val = exp.eval(CallSnapshot::new(&exp, &snapshot)?, env.clone())?;
     }
-    Ok(return_val)
+
+    if let Some(modules) = &modules {
+        modules
+            .cache
+            .write()?
+            .insert(location, (env.clone(), return_val.clone()));
+    }
+    Ok((env, return_val))
 }
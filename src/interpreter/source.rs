@@ -1,7 +1,12 @@
+use crate::interpreter::exceptions::styled;
+use crate::interpreter::json::Json;
 use crate::parser::Rule;
 use ansi_term::{Color, Style};
 use pest::iterators::Pair;
 use std::fmt;
+use std::sync::Arc;
+use unicode_segmentation::UnicodeSegmentation;
+use unicode_width::{UnicodeWidthChar, UnicodeWidthStr};
 
 use crate::Locker;
 
@@ -9,11 +14,72 @@ use crate::Locker;
 pub struct Source {
     text: String,
     location: String,
+    // The byte offset each line starts at
+    line_starts: Vec<usize>,
 }
 
 impl Source {
     pub fn new(text: String, location: String) -> Self {
-        Self { text, location }
+        let line_starts = std::iter::once(0)
+            .chain(text.match_indices('\n').map(|(pos, _)| pos + 1))
+            .collect();
+        Self {
+            text,
+            location,
+            line_starts,
+        }
+    }
+
+    pub fn text(&self) -> &str {
+        &self.text
+    }
+
+    pub fn location(&self) -> &str {
+        &self.location
+    }
+
+    /// The line and column (both counted from 1) of the byte offset `offset`.
+    /// Columns count characters, not bytes; an offset in the middle of a
+    /// character is in that character's column.
+    pub fn line_col(&self, offset: usize) -> Option<(usize, usize)> {
+        if offset > self.text.len() {
+            return None;
+        }
+        let line = self.line_starts.partition_point(|start| *start <= offset) - 1;
+        let before = &self.text.as_bytes()[self.line_starts[line]..offset];
+        // Counting the bytes that start a character counts the characters
+        let column = before.iter().filter(|b| (**b as i8) >= -0x40).count() + 1;
+        Some((line + 1, column))
+    }
+
+    /// The byte offset of a line and column (both counted from 1), which may
+    /// be just past the end of the line.
+    pub fn offset(&self, line: usize, column: usize) -> Option<usize> {
+        let start = *self.line_starts.get(line.checked_sub(1)?)?;
+        let text = self.line_text(line)?;
+        match column.checked_sub(1)? {
+            column if column == text.chars().count() => Some(start + text.len()),
+            column => text
+                .char_indices()
+                .nth(column)
+                .map(|(offset, _)| start + offset),
+        }
+    }
+
+    /// A line's text (counted from 1), without its line ending.
+    pub fn line_text(&self, line: usize) -> Option<&str> {
+        let start = *self.line_starts.get(line.checked_sub(1)?)?;
+        let end = match self.line_starts.get(line) {
+            Some(next) => next - 1,
+            None => self.text.len(),
+        };
+        let text = &self.text[start..end];
+        Some(text.strip_suffix('\r').unwrap_or(text))
+    }
+
+    /// How many lines the source has.
+    pub fn lines(&self) -> usize {
+        self.line_starts.len()
     }
 }
 
@@ -22,6 +88,8 @@ pub struct SourcePosition {
     start_pos: usize,
     end_pos: usize,
     text: Locker<Source>,
+    // The macro call this code was created during, for code built at runtime
+    expanded_from: Option<Arc<SourcePosition>>,
 }
 
 impl SourcePosition {
@@ -30,6 +98,7 @@ impl SourcePosition {
             start_pos,
             end_pos,
             text,
+            expanded_from: None,
         }
     }
 
@@ -41,12 +110,93 @@ impl SourcePosition {
         )
     }
 
+    /// Marks this position as part of the expansion of the macro called at
+    /// `site` (after any expansion it is already part of).
+    pub fn expanded_from(mut self, site: SourcePosition) -> Self {
+        self.expanded_from = Some(Arc::new(match self.expanded_from.take() {
+            Some(outer) => (*outer).clone().expanded_from(site),
+            None => site,
+        }));
+        self
+    }
+
+    pub fn expansion_site(&self) -> Option<&SourcePosition> {
+        self.expanded_from.as_deref()
+    }
+
+    /// Whether both positions point at the same code.
+    pub fn is_at(&self, other: &Self) -> bool {
+        self.start_pos == other.start_pos
+            && self.end_pos == other.end_pos
+            && self.text.id() == other.text.id()
+    }
+
+    /// The byte offset the position starts at.
+    pub fn start(&self) -> usize {
+        self.start_pos
+    }
+
+    /// The byte offset just past the end of the position.
+    pub fn end(&self) -> usize {
+        self.end_pos
+    }
+
+    pub fn source(&self) -> &Locker<Source> {
+        &self.text
+    }
+
+    /// The code the position points at.
+    pub fn code(&self) -> Option<String> {
+        let source = self.text.read().ok()?;
+        source
+            .text
+            .get(self.start_pos..self.end_pos)
+            .map(String::from)
+    }
+
+    /// The line and column (both counted from 1) the position starts at.
+    pub fn line_col(&self) -> Option<(usize, usize)> {
+        self.text.read().ok()?.line_col(self.start_pos)
+    }
+
     pub fn location(&self) -> Option<String> {
         match self.text.read() {
             Ok(text) => Some(text.location.clone()),
             Err(_) => None,
         }
     }
+
+    /// The position as JSON, in the form diagnostics use: an object with the
+    /// `file`, the `start` and `end` (just past the code), each with a byte
+    /// `offset` and a 1-based `line` and `column`, the `code` itself, and the
+    /// position of the macro call it was `expanded_from` (or null).
+    pub fn to_json(&self) -> Json {
+        let source = match self.text.read() {
+            Ok(source) => source,
+            Err(_) => return Json::Null,
+        };
+        let point = |offset: usize| {
+            let line_col = source.line_col(offset);
+            Json::Object(vec![
+                ("offset", offset.into()),
+                ("line", line_col.map(|(line, _)| line).into()),
+                ("column", line_col.map(|(_, column)| column).into()),
+            ])
+        };
+        Json::Object(vec![
+            ("file", source.location.as_str().into()),
+            ("start", point(self.start_pos)),
+            ("end", point(self.end_pos)),
+            ("code", source.text.get(self.start_pos..self.end_pos).into()),
+            (
+                "expanded_from",
+                self.expanded_from
+                    .as_ref()
+                    .map(|site| site.to_json())
+                    .into(),
+            ),
+        ])
+    }
 }
 
 impl fmt::Display for SourcePosition {
@@ -55,83 +205,93 @@ impl fmt::Display for SourcePosition {
             Ok(text) => text,
             Err(_) => return Err(fmt::Error),
         };
-        let line_number =
-            &source.text[0..self.start_pos]
-                .chars()
-                .fold(0, |acc, c| match c == '\n' {
-                    true => acc + 1,
-                    false => acc,
-                })
-                + 1;
-
-        let lines = source.text.split('\n');
-
-        let mut relevant_lines_formatted: Vec<(usize, String)> = Vec::new();
-
-        let mut chars_seen = 0;
-        for (i, line) in lines.enumerate() {
-            let eol_pos = chars_seen + line.len() + 1;
-            if self.start_pos < eol_pos && self.end_pos > chars_seen {
-                let mut inner_start_pos: isize = self.start_pos as isize - chars_seen as isize;
-                if inner_start_pos < 0 {
-                    inner_start_pos = 0;
-                }
-
-                let mut inner_end_pos = self.end_pos - chars_seen;
-                if inner_end_pos > line.len() {
-                    inner_end_pos = line.len();
-                }
-                if inner_start_pos as usize != inner_end_pos && !line.is_empty() {
-                    relevant_lines_formatted.push((
-                        i + 1,
-                        format!(
-                            "{}{}{}",
-                            &line[0..inner_start_pos as usize],
-                            Color::Purple
-                                .paint(&line[inner_start_pos as usize..inner_end_pos as usize]),
-                            &line[inner_end_pos..]
-                        ),
-                    ));
-                }
-            }
-            chars_seen += line.len() + 1;
-        }
-        fn indent(n: usize) -> String {
-            String::from_utf8(vec![b' '; n]).unwrap()
-        }
-
-        let mut indentation = format!("{}", line_number).len() + 2;
-        if indentation < 6 {
-            indentation = 6;
-        }
+        let (first_line, column) = source.line_col(self.start_pos).ok_or(fmt::Error)?;
+        let last_line = match self.end_pos > self.start_pos {
+            true => source.line_col(self.end_pos - 1).ok_or(fmt::Error)?.0,
+            false => first_line,
+        };
 
+        let indentation = (format!("{}", last_line).len() + 2).max(6);
         writeln!(
             f,
             "{}{} {}",
             indent(indentation),
-            Color::Blue.bold().paint("├"),
-            Style::default()
-                .dimmed()
-                .paint(format!("{}:{} ↴", source.location, line_number)),
+            styled(f, Color::Blue.bold()).paint("├"),
+            styled(f, Style::default().dimmed())
+                .paint(format!("{}:{}:{} ↴", source.location, first_line, column)),
         )?;
 
-        for (line_no, line) in relevant_lines_formatted {
+        for line_no in first_line..=last_line {
+            let line = source.line_text(line_no).unwrap_or_default();
+            let line_start = source.offset(line_no, 1).unwrap_or_default();
+            let (start, end) = graphemes_within(
+                line,
+                self.start_pos.saturating_sub(line_start),
+                self.end_pos.saturating_sub(line_start),
+            );
+            if start == end {
+                continue;
+            }
             let line_no_str = format!("{}", line_no);
-            let line_no_indentation = indent(indentation - line_no_str.len() - 1);
             writeln!(
                 f,
-                "{}{} {}",
-                line_no_indentation,
-                Color::Blue.bold().paint(format!("{} │", line_no_str)),
-                line
+                "{}{} {}{}{}",
+                indent(indentation - line_no_str.len() - 1),
+                styled(f, Color::Blue.bold()).paint(format!("{} │", line_no_str)),
+                &line[..start],
+                styled(f, Color::Purple.normal()).paint(&line[start..end]),
+                &line[end..]
+            )?;
+            // Code on a single line is underlined too, lined up with how wide
+            // each character is when shown
+            if first_line == last_line {
+                let padding: String = line[..start]
+                    .chars()
+                    .map(|c| match c {
+                        '\t' => "\t".to_string(),
+                        c => indent(c.width().unwrap_or(0)),
+                    })
+                    .collect();
+                writeln!(
+                    f,
+                    "{}{} {}{}",
+                    indent(indentation),
+                    styled(f, Color::Blue.bold()).paint("│"),
+                    padding,
+                    styled(f, Color::Purple.normal())
+                        .paint("^".repeat(line[start..end].width().max(1)))
+                )?;
+            }
+        }
+        if let Some(site) = &self.expanded_from {
+            writeln!(
+                f,
+                "      {} {}",
+                styled(f, Color::Blue.bold()).paint("┆"),
+                styled(f, Style::new().dimmed()).paint("expanded from")
             )?;
+            fmt::Display::fmt(&**site, f)?;
         }
         write!(f, "")
     }
 }
 
-impl Source {
-    pub fn location(&self) -> &str {
-        &self.location
+fn indent(n: usize) -> String {
+    " ".repeat(n)
+}
+
+// The part of `line` between the byte offsets `start` and `end`, widened to
+// whole graphemes so accents stay with the letters they're on
+fn graphemes_within(line: &str, start: usize, end: usize) -> (usize, usize) {
+    let (mut from, mut to) = (line.len(), line.len());
+    for (offset, grapheme) in line.grapheme_indices(true) {
+        if offset + grapheme.len() > start && from == line.len() {
+            from = offset;
+        }
+        if offset >= end {
+            to = offset;
+            break;
+        }
     }
+    (from, to.max(from))
 }
@@ -0,0 +1,73 @@
+/// The most suggestions offered for a single mistake.
+const MAX_SUGGESTIONS: usize = 3;
+
+/// The candidates close enough to `name` (by edit distance) that `name` may
+/// have been a typo of them, closest first. A name that isn't namespaced is
+/// also compared with the identifier part of namespaced candidates, so that
+/// `sin` suggests `math::sin`.
+pub(crate) fn suggest<'a>(
+    name: &str,
+    candidates: impl IntoIterator<Item = &'a str>,
+) -> Vec<String> {
+    // Allow roughly one mistake for every three characters (of the
+    // identifier, which is where the typo usually is)
+    let identifier = name.rsplit("::").next().unwrap_or(name);
+    let max_distance = (identifier.chars().count() / 3).max(1);
+    let mut matches: Vec<(usize, &str)> = vec![];
+    for candidate in candidates {
+        if candidate == name {
+            continue;
+        }
+        let mut distance = edit_distance(name, candidate);
+        if !name.contains("::") {
+            if let Some((_, identifier)) = candidate.split_once("::") {
+                distance = distance.min(edit_distance(name, identifier));
+            }
+        }
+        if distance <= max_distance {
+            matches.push((distance, candidate));
+        }
+    }
+    matches.sort();
+    matches.dedup_by(|a, b| a.1 == b.1);
+    matches
+        .into_iter()
+        .take(MAX_SUGGESTIONS)
+        .map(|(_, candidate)| candidate.to_string())
+        .collect()
+}
+
+/// Lists suggestions as a question, like "did you mean `a` or `b`?" (or
+/// `None` when there aren't any).
+pub(crate) fn did_you_mean(suggestions: &[String]) -> Option<String> {
+    let quoted: Vec<String> = suggestions.iter().map(|s| format!("`{}`", s)).collect();
+    match quoted.split_last() {
+        None => None,
+        Some((last, [])) => Some(format!("did you mean {}?", last)),
+        Some((last, rest)) => Some(format!("did you mean {} or {}?", rest.join(", "), last)),
+    }
+}
+
+// The optimal string alignment distance between `a` and `b`: how many
+// characters must be inserted, removed, replaced or swapped with their
+// neighbour to turn one into the other
+fn edit_distance(a: &str, b: &str) -> usize {
+    let (a, b): (Vec<char>, Vec<char>) = (a.chars().collect(), b.chars().collect());
+    // Rows for the prefixes of `a` two, one and zero characters shorter
+    let mut before: Vec<usize> = vec![];
+    let mut previous: Vec<usize> = (0..=b.len()).collect();
+    for i in 1..=a.len() {
+        let mut current = vec![i; b.len() + 1];
+        for j in 1..=b.len() {
+            let cost = usize::from(a[i - 1] != b[j - 1]);
+            current[j] = (previous[j] + 1)
+                .min(current[j - 1] + 1)
+                .min(previous[j - 1] + cost);
+            if i > 1 && j > 1 && a[i - 1] == b[j - 2] && a[i - 2] == b[j - 1] {
+                current[j] = current[j].min(before[j - 2] + 1);
+            }
+        }
+        before = std::mem::replace(&mut previous, current);
+    }
+    previous[b.len()]
+}
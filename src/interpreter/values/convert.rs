@@ -0,0 +1,296 @@
+use std::collections::HashMap;
+use std::convert::TryFrom;
+
+use crate::{Exception, ExceptionValue as EV, Expression, Keyword, Map, Number, Value};
+
+/// Rust values that can be turned into Turtle values.
+pub trait IntoTurtle {
+    fn into_turtle(self) -> Expression;
+}
+
+/// Rust values that can be read from Turtle values. Values of the wrong type
+/// (or out of range) can't be, and are reported with an `InvalidArgument`
+/// exception that says what was expected.
+pub trait FromTurtle: Sized {
+    fn from_turtle(exp: &Expression) -> Result<Self, Exception>;
+}
+
+// The exception for when `value` isn't what was `expected` (taking the value
+// that's already been read, rather than locking it again)
+fn mismatch(expected: &str, value: &Value) -> Exception {
+    Exception::new(
+        EV::InvalidArgument,
+        None,
+        Some(format!(
+            "expected {} (got `{:#}`, which is of type {})",
+            expected,
+            value,
+            value.as_type()
+        )),
+    )
+}
+
+impl IntoTurtle for Expression {
+    fn into_turtle(self) -> Expression {
+        self
+    }
+}
+
+impl FromTurtle for Expression {
+    fn from_turtle(exp: &Expression) -> Result<Self, Exception> {
+        Ok(exp.clone())
+    }
+}
+
+impl IntoTurtle for Value {
+    fn into_turtle(self) -> Expression {
+        Expression::new(self)
+    }
+}
+
+impl FromTurtle for Value {
+    fn from_turtle(exp: &Expression) -> Result<Self, Exception> {
+        Ok(exp.value().read()?.clone())
+    }
+}
+
+impl IntoTurtle for Number {
+    fn into_turtle(self) -> Expression {
+        Expression::new(Value::Number(self))
+    }
+}
+
+impl FromTurtle for Number {
+    fn from_turtle(exp: &Expression) -> Result<Self, Exception> {
+        match &*exp.value().read()? {
+            Value::Number(val) => Ok(val.clone()),
+            val => Err(mismatch("a number", val)),
+        }
+    }
+}
+
+macro_rules! integer {
+    ($($int:ty),*) => {$(
+        impl IntoTurtle for $int {
+            fn into_turtle(self) -> Expression {
+                let number = match i64::try_from(self) {
+                    Ok(val) => Number::Integer(val),
+                    Err(_) => Number::from_bigint(self.into()),
+                };
+                Expression::new(Value::Number(number))
+            }
+        }
+
+        impl FromTurtle for $int {
+            fn from_turtle(exp: &Expression) -> Result<Self, Exception> {
+                let expected = concat!("an integer that fits in a `", stringify!($int), "`");
+                let value = exp.value();
+                let value = value.read()?;
+                match &*value {
+                    Value::Number(Number::Integer(val)) => {
+                        <$int>::try_from(*val).map_err(|_| mismatch(expected, &value))
+                    }
+                    Value::Number(Number::BigInteger(val)) => {
+                        <$int>::try_from(val).map_err(|_| mismatch(expected, &value))
+                    }
+                    val => Err(mismatch(expected, val)),
+                }
+            }
+        }
+    )*};
+}
+
+integer!(i8, i16, i32, i64, i128, u16, u32, u64, u128, isize, usize);
+
+impl IntoTurtle for f64 {
+    fn into_turtle(self) -> Expression {
+        Expression::new(Value::Number(Number::Float(self)))
+    }
+}
+
+impl FromTurtle for f64 {
+    fn from_turtle(exp: &Expression) -> Result<Self, Exception> {
+        Ok(Number::from_turtle(exp)?.to_f64())
+    }
+}
+
+impl IntoTurtle for f32 {
+    fn into_turtle(self) -> Expression {
+        f64::from(self).into_turtle()
+    }
+}
+
+impl FromTurtle for f32 {
+    fn from_turtle(exp: &Expression) -> Result<Self, Exception> {
+        Ok(f64::from_turtle(exp)? as f32)
+    }
+}
+
+// `u8`s are bytes rather than numbers
+impl IntoTurtle for u8 {
+    fn into_turtle(self) -> Expression {
+        Expression::new(Value::Byte(self))
+    }
+}
+
+impl FromTurtle for u8 {
+    fn from_turtle(exp: &Expression) -> Result<Self, Exception> {
+        match &*exp.value().read()? {
+            Value::Byte(val) => Ok(*val),
+            val => Err(mismatch("a byte", val)),
+        }
+    }
+}
+
+// Like `cond`, anything but nil is true
+impl IntoTurtle for bool {
+    fn into_turtle(self) -> Expression {
+        match self {
+            true => Expression::new(Value::True),
+            false => Expression::nil(),
+        }
+    }
+}
+
+impl FromTurtle for bool {
+    fn from_turtle(exp: &Expression) -> Result<Self, Exception> {
+        Ok(*exp != Expression::nil())
+    }
+}
+
+impl IntoTurtle for String {
+    fn into_turtle(self) -> Expression {
+        Expression::new(Value::Text(self))
+    }
+}
+
+impl IntoTurtle for &str {
+    fn into_turtle(self) -> Expression {
+        Expression::new(Value::Text(self.to_string()))
+    }
+}
+
+impl FromTurtle for String {
+    fn from_turtle(exp: &Expression) -> Result<Self, Exception> {
+        match &*exp.value().read()? {
+            Value::Text(val) => Ok(val.clone()),
+            val => Err(mismatch("text", val)),
+        }
+    }
+}
+
+impl<T: IntoTurtle> IntoTurtle for Vec<T> {
+    fn into_turtle(self) -> Expression {
+        Expression::new(Value::List(
+            self.into_iter().map(IntoTurtle::into_turtle).collect(),
+        ))
+    }
+}
+
+impl<T: FromTurtle> FromTurtle for Vec<T> {
+    fn from_turtle(exp: &Expression) -> Result<Self, Exception> {
+        match &*exp.value().read()? {
+            Value::List(vals) => vals.iter().map(T::from_turtle).collect(),
+            val => Err(mismatch("a list", val)),
+        }
+    }
+}
+
+// `None` is nil
+impl<T: IntoTurtle> IntoTurtle for Option<T> {
+    fn into_turtle(self) -> Expression {
+        match self {
+            Some(val) => val.into_turtle(),
+            None => Expression::nil(),
+        }
+    }
+}
+
+impl<T: FromTurtle> FromTurtle for Option<T> {
+    fn from_turtle(exp: &Expression) -> Result<Self, Exception> {
+        match *exp == Expression::nil() {
+            true => Ok(None),
+            false => T::from_turtle(exp).map(Some),
+        }
+    }
+}
+
+// Maps made from Rust have text keys; maps read into Rust can also have
+// keyword or symbol keys, which are used by name
+impl<T: IntoTurtle> IntoTurtle for HashMap<String, T> {
+    fn into_turtle(self) -> Expression {
+        let mut map = Map::new();
+        for (key, val) in self {
+            map = map.insert(Value::Text(key), val.into_turtle());
+        }
+        Expression::new(Value::Map(map))
+    }
+}
+
+impl<T: FromTurtle> FromTurtle for HashMap<String, T> {
+    fn from_turtle(exp: &Expression) -> Result<Self, Exception> {
+        let map = match &*exp.value().read()? {
+            Value::Map(map) => map.clone(),
+            val => return Err(mismatch("a map", val)),
+        };
+        let mut converted = HashMap::with_capacity(map.len());
+        for (key, val) in map.iter() {
+            let key = match key {
+                Value::Text(key) => key.clone(),
+                Value::Keyword(key) => key.string_value().clone(),
+                Value::Symbol(key) => key.string_value().clone(),
+                key => return Err(mismatch("a text, keyword or symbol key", key)),
+            };
+            converted.insert(key, T::from_turtle(val)?);
+        }
+        Ok(converted)
+    }
+}
+
+impl IntoTurtle for Keyword {
+    fn into_turtle(self) -> Expression {
+        Expression::new(Value::Keyword(self))
+    }
+}
+
+impl FromTurtle for Keyword {
+    fn from_turtle(exp: &Expression) -> Result<Self, Exception> {
+        match &*exp.value().read()? {
+            Value::Keyword(val) => Ok(val.clone()),
+            val => Err(mismatch("a keyword", val)),
+        }
+    }
+}
+
+// Tuples are lists of exactly as many values
+macro_rules! tuple {
+    ($len:expr; $($name:ident),*) => {
+        impl<$($name: IntoTurtle),*> IntoTurtle for ($($name,)*) {
+            #[allow(non_snake_case)]
+            fn into_turtle(self) -> Expression {
+                let ($($name,)*) = self;
+                Expression::new(Value::List(vec![$($name.into_turtle()),*]))
+            }
+        }
+
+        impl<$($name: FromTurtle),*> FromTurtle for ($($name,)*) {
+            fn from_turtle(exp: &Expression) -> Result<Self, Exception> {
+                let expected = concat!("a list of ", stringify!($len), " values");
+                match &*exp.value().read()? {
+                    Value::List(vals) if vals.len() == $len => {
+                        let mut vals = vals.iter();
+                        Ok(($($name::from_turtle(vals.next().unwrap())?,)*))
+                    }
+                    val => Err(mismatch(expected, val)),
+                }
+            }
+        }
+    };
+}
+
+tuple!(1; A);
+tuple!(2; A, B);
+tuple!(3; A, B, C);
+tuple!(4; A, B, C, D);
+tuple!(5; A, B, C, D, E);
+tuple!(6; A, B, C, D, E, F);
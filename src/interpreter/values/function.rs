@@ -1,14 +1,25 @@
-use crate::{Environment, Expression, Symbol};
+use crate::{
+    exp, exp_assert, CallSnapshot, Environment, Exception, ExceptionValue as EV, Expression,
+    Program, Symbol, Value,
+};
 use std::cmp::Ordering;
+use std::sync::{Arc, OnceLock};
 
+use crate::interpreter::heap::{self, Tracer};
 use crate::Locker;
 
 #[derive(Debug, Clone)]
 pub struct Function {
+    /// The name the function was first bound to (by `export` or `let`), if
+    /// any; it has no bearing on what the function does.
+    pub name: Option<Symbol>,
     pub params: Vec<Symbol>,
     pub expressions: Vec<Expression>,
     pub collapse_input: bool,
     pub lexical_scope: Locker<Environment>,
+    // The body compiled to bytecode, shared between copies of the function
+    // and filled in the first time the bytecode VM calls it
+    compiled: Arc<OnceLock<Arc<Program>>>,
 }
 
 impl PartialEq for Function {
@@ -36,11 +47,135 @@ impl Function {
         collapse_input: bool,
         lexical_scope: Locker<Environment>,
     ) -> Self {
+        heap::track_scope(&lexical_scope);
         Self {
+            name: None,
             params,
             expressions,
             collapse_input,
             lexical_scope,
+            compiled: Arc::new(OnceLock::new()),
         }
     }
+
+    /// The function bound to `name`, unless it already has a name (a
+    /// function keeps the name it was defined with when it's passed around).
+    pub fn named(mut self, name: Symbol) -> Self {
+        self.name = self.name.or(Some(name));
+        self
+    }
+
+    /// How the function is called, like `(add a b)` or `(log . args)` for a
+    /// function that collapses its arguments into a list.
+    pub fn signature(&self) -> String {
+        let name = match self.name {
+            Some(name) => name.to_string(),
+            None => "lambda".to_string(),
+        };
+        let params = self
+            .params
+            .iter()
+            .map(|param| param.to_string())
+            .collect::<Vec<String>>()
+            .join(" ");
+        match (self.collapse_input, params.is_empty()) {
+            (true, _) => format!("({} . {})", name, params),
+            (false, true) => format!("({})", name),
+            (false, false) => format!("({} {})", name, params),
+        }
+    }
+
+    pub(crate) fn trace(&self, tracer: &mut Tracer) {
+        tracer.environment(&self.lexical_scope);
+        for exp in self.expressions.iter() {
+            exp.trace(tracer);
+        }
+        tracer.code(&self.compiled);
+    }
+
+    /// The function's body, compiled to bytecode. Locals of the calls it was
+    /// defined in are read from their slots too.
+    pub fn compiled(&self) -> Result<Arc<Program>, Exception> {
+        if let Some(program) = self.compiled.get() {
+            return Ok(program.clone());
+        }
+        let enclosing = Environment::enclosing_layouts(&self.lexical_scope);
+        let program = Arc::new(Program::compile_function(
+            &self.params,
+            &self.expressions,
+            enclosing,
+        )?);
+        Ok(self.compiled.get_or_init(|| program).clone())
+    }
+
+    /// Creates the environment a call to this function runs in, binding each
+    /// parameter to the value `argument` produces for its index (evaluated
+    /// arguments for lambdas, unevaluated ones for macros). Once the body has
+    /// been compiled, its locals are kept in the slots its code reads them
+    /// from.
+    pub fn call_scope(
+        &self,
+        is_macro: bool,
+        caller_env: &Locker<Environment>,
+        argument_count: usize,
+        mut argument: impl FnMut(usize) -> Result<Expression, Exception>,
+        snapshot: &Locker<CallSnapshot>,
+    ) -> Result<Locker<Environment>, Exception> {
+        let mut scoped_env = match self.compiled.get().and_then(|program| program.layout()) {
+            Some(layout) => Environment::root().with_layout(layout.clone()),
+            None => Environment::root(),
+        };
+        match is_macro {
+            false => scoped_env.add_parent(self.lexical_scope.clone(), None),
+            true => {
+                scoped_env.add_parent(caller_env.clone(), None);
+                scoped_env.add_parent(self.lexical_scope.clone(), None);
+            }
+        };
+
+        if self.collapse_input {
+            let sym = self.params.first().unwrap(); // this unwrap will always be ok; it is enforced by the parser
+            CallSnapshot::check_length(snapshot, argument_count)?;
+            let mut args = Vec::with_capacity(argument_count);
+            for i in 0..argument_count {
+                args.push(argument(i)?);
+            }
+            let arg = Expression::new(Value::List(args));
+            scoped_env.assign(*sym, arg, true, snapshot.clone())?;
+        } else {
+            exp_assert!(
+                self.params.len() == argument_count,
+                EV::ArgumentMismatch(argument_count, format!("{}", self.params.len())),
+                snapshot,
+                format!(
+                    "`{}` was called with {}",
+                    self.signature(),
+                    given_arguments(snapshot)?
+                )
+            );
+            for (i, symbol) in self.params.iter().enumerate() {
+                let arg = argument(i)?;
+                scoped_env.assign(*symbol, arg, true, snapshot.clone())?;
+            }
+        }
+        if is_macro {
+            scoped_env = scoped_env.shadow();
+        }
+        Ok(Locker::new(scoped_env))
+    }
+}
+
+// The arguments of the call `snapshot` makes, as they were written
+fn given_arguments(snapshot: &Locker<CallSnapshot>) -> Result<String, Exception> {
+    let snapshot = snapshot.read()?;
+    let call = snapshot.expression().value();
+    let call = call.read()?;
+    Ok(match &*call {
+        Value::List(vals) if vals.len() > 1 => vals[1..]
+            .iter()
+            .map(|val| format!("`{:#}`", val))
+            .collect::<Vec<String>>()
+            .join(", "),
+        _ => "no arguments".to_string(),
+    })
 }
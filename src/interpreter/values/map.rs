@@ -0,0 +1,144 @@
+use std::cmp::Ordering;
+use std::collections::hash_map::DefaultHasher;
+use std::fmt;
+use std::hash::{BuildHasherDefault, Hash, Hasher};
+use std::mem;
+
+use crate::interpreter::heap::Tracer;
+use crate::{Expression, Value};
+
+/// An immutable hash map from values to expressions. Maps are persistent:
+/// adding or removing an entry makes a new map that shares most of its
+/// structure with the old one, so neither operation copies the whole map.
+#[derive(Debug, Clone, Default)]
+pub struct Map(im::HashMap<Key, Expression, BuildHasherDefault<DefaultHasher>>);
+
+/// A value used as the key of a `Map`. Keys hash consistently with how values
+/// compare, so numbers of different types that are `eq` are the same key.
+#[derive(Debug, Clone)]
+struct Key(Value);
+
+impl Map {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn len(&self) -> usize {
+        self.0.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.0.is_empty()
+    }
+
+    pub fn get(&self, key: &Value) -> Option<&Expression> {
+        self.0.get(&Key(key.clone()))
+    }
+
+    pub fn contains(&self, key: &Value) -> bool {
+        self.0.contains_key(&Key(key.clone()))
+    }
+
+    pub fn insert(&self, key: Value, value: Expression) -> Self {
+        Self(self.0.update(Key(key), value))
+    }
+
+    pub fn remove(&self, key: &Value) -> Self {
+        Self(self.0.without(&Key(key.clone())))
+    }
+
+    /// Combines the two maps; where both have the same key, `other` wins.
+    pub fn merge(&self, other: &Self) -> Self {
+        let mut merged = self.0.clone();
+        for (key, value) in other.0.iter() {
+            merged.insert(key.clone(), value.clone());
+        }
+        Self(merged)
+    }
+
+    /// The map's entries, in no particular (but a consistent) order.
+    pub fn iter(&self) -> impl Iterator<Item = (&Value, &Expression)> {
+        self.0.iter().map(|(key, value)| (&key.0, value))
+    }
+
+    pub(crate) fn trace(&self, tracer: &mut Tracer) {
+        for (key, value) in self.iter() {
+            key.trace(tracer);
+            value.trace(tracer);
+        }
+    }
+}
+
+impl PartialEq for Map {
+    fn eq(&self, other: &Self) -> bool {
+        self.0 == other.0
+    }
+}
+
+impl PartialOrd for Map {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        if self == other {
+            Some(Ordering::Equal)
+        } else {
+            None
+        }
+    }
+}
+
+impl fmt::Display for Map {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{{{}}}",
+            self.iter()
+                .map(|(key, value)| match f.alternate() {
+                    true => format!("{:#} {:#}", key, value),
+                    false => format!("{} {}", key, value),
+                })
+                .collect::<Vec<String>>()
+                .join(" ")
+        )
+    }
+}
+
+impl PartialEq for Key {
+    fn eq(&self, other: &Self) -> bool {
+        self.0 == other.0
+    }
+}
+
+impl Eq for Key {}
+
+impl Hash for Key {
+    fn hash<H: Hasher>(&self, state: &mut H) {
+        hash_value(&self.0, state);
+    }
+}
+
+fn hash_value<H: Hasher>(value: &Value, state: &mut H) {
+    mem::discriminant(value).hash(state);
+    match value {
+        Value::List(vals) => {
+            for val in vals {
+                hash_value(&val.value().read().unwrap(), state);
+            }
+        }
+        // Numbers that are `eq` have the same closest float, even when they
+        // aren't of the same type
+        Value::Number(val) => {
+            // `-0.0` and `0.0` are `eq` too
+            let val = match val.is_zero() {
+                true => 0.0,
+                false => val.to_f64(),
+            };
+            val.to_bits().hash(state)
+        }
+        Value::Text(val) => val.hash(state),
+        Value::Keyword(val) => val.hash(state),
+        Value::Symbol(val) => val.hash(state),
+        Value::Byte(val) => val.hash(state),
+        Value::Map(map) => map.len().hash(state),
+        // Everything else only ever hashes by its type
+        _ => {}
+    }
+}
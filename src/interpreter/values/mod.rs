@@ -1,12 +1,19 @@
-use crate::Expression;
+use crate::interpreter::heap::Tracer;
+use crate::{Exception, Expression};
 
 use std::fmt;
 
 pub mod operator;
-pub use operator::Operator;
+pub use operator::{Arguments, Operator};
+
+pub mod map;
+pub use map::Map;
+
+pub mod number;
+pub use number::Number;
 
 pub mod symbol;
-pub use symbol::Symbol;
+pub use symbol::{Symbol, SymbolMap};
 
 pub mod keyword;
 pub use keyword::Keyword;
@@ -14,21 +21,38 @@ pub use keyword::Keyword;
 pub mod function;
 pub use function::Function;
 
+pub mod convert;
+pub use convert::{FromTurtle, IntoTurtle};
+
+#[cfg(feature = "serde")]
+pub mod serialization;
+#[cfg(feature = "serde")]
+pub use serialization::{from_value, to_value};
+
+pub mod native;
+pub use native::{IntoNative, NativeFunction, Variadic};
+
 #[derive(Debug, Clone, PartialEq, PartialOrd)]
 pub enum Value {
     List(Vec<Expression>),
-    Number(f64),
+    Number(Number),
     Text(String),
     Keyword(Keyword),
     Symbol(Symbol),
     Byte(u8),
     True,
+    Map(Map),
 
     // Primitive (axiomatic) operators
     Operator(Operator),
 
     Lambda(Function),
     Macro(Function),
+    // A function implemented in Rust
+    Native(NativeFunction),
+
+    // A caught exception
+    Exception(Box<Exception>),
 }
 
 impl Value {
@@ -37,20 +61,63 @@ impl Value {
 
         Value::Keyword(crate::Keyword::new(match self {
             List(_) => "list".to_string(),
-            Number(_) => "number".to_string(),
+            Number(val) => val.type_name().to_string(),
             Text(_) => "text".to_string(),
             Keyword(_) => "keyword".to_string(),
             Symbol(_) => "symbol".to_string(),
             Operator(_) => "operator".to_string(),
             Byte(_) => "byte".to_string(),
+            Map(_) => "map".to_string(),
             Lambda { .. } => "lambda".to_string(),
             Macro { .. } => "macro".to_string(),
+            Native(_) => "native".to_string(),
+            Exception(_) => "exception".to_string(),
             _ => "unknown".to_string(),
         }))
     }
+
+    pub(crate) fn trace(&self, tracer: &mut Tracer) {
+        match self {
+            Value::List(vals) => {
+                for val in vals {
+                    val.trace(tracer);
+                }
+            }
+            Value::Map(map) => map.trace(tracer),
+            Value::Lambda(function) | Value::Macro(function) => function.trace(tracer),
+            Value::Exception(err) => err.trace(tracer),
+            _ => {}
+        }
+    }
 }
 
-impl<'a> fmt::Display for Value {
+// Text as a literal that `parse` reads back as the same text
+fn escape(text: &str) -> String {
+    let mut escaped = String::with_capacity(text.len() + 2);
+    escaped.push('"');
+    for c in text.chars() {
+        match c {
+            '"' => escaped.push_str("\\\""),
+            '\\' => escaped.push_str("\\\\"),
+            '\n' => escaped.push_str("\\n"),
+            '\r' => escaped.push_str("\\r"),
+            '\t' => escaped.push_str("\\t"),
+            '\u{8}' => escaped.push_str("\\b"),
+            '\u{c}' => escaped.push_str("\\f"),
+            c if c.is_control() => escaped.push_str(&format!("\\u{:04X}", c as u32)),
+            c => escaped.push(c),
+        }
+    }
+    escaped.push('"');
+    escaped
+}
+
+/// Values display the way `disp` prints them (and `format` interpolates
+/// them): text is written as-is, including inside lists and maps, so it can't
+/// be told apart from symbols and doesn't read back. Only the alternate form
+/// (`{:#}`) round-trips, by quoting and escaping text so that `parse` reads
+/// the output back as the same value.
+impl fmt::Display for Value {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
         use Value::*;
 
@@ -61,25 +128,35 @@ impl<'a> fmt::Display for Value {
                     f,
                     "({})",
                     vals.iter()
-                        .map(|v| format!("{}", v))
+                        .map(|v| match f.alternate() {
+                            true => format!("{:#}", v),
+                            false => format!("{}", v),
+                        })
                         .collect::<Vec<String>>()
                         .join(" ")
                 ),
             },
             Number(val) => write!(f, "{}", val),
+            Text(val) if f.alternate() => write!(f, "{}", escape(val)),
             Text(val) => write!(f, "{}", val),
             Symbol(val) => write!(f, "{}", val),
             Keyword(val) => write!(f, "{}", val),
             Byte(val) => write!(f, "b{}", val),
             True => write!(f, "true"),
+            Map(map) if f.alternate() => write!(f, "{:#}", map),
+            Map(map) => write!(f, "{}", map),
             Lambda(function) | Macro(function) => write!(
                 f,
-                "<{} {}{}{} -> {}>",
+                "<{} {}{}{}{} -> {}>",
                 match self {
                     Lambda(_) => "lambda",
                     Macro(_) => "macro",
                     _ => unreachable!(),
                 },
+                match function.name {
+                    Some(name) => format!("{} ", name),
+                    None => String::new(),
+                },
                 match function.collapse_input {
                     true => "",
                     false => "(",
@@ -101,6 +178,8 @@ impl<'a> fmt::Display for Value {
                     .collect::<Vec<String>>()
                     .join(" ")
             ),
+            Native(function) => write!(f, "<native {}>", function.name),
+            Exception(err) => write!(f, "<exception {}>", err.value().clone().into_expression()),
             _ => write!(f, "<{}>", format!("{:?}", self).to_lowercase()),
         }
     }
@@ -0,0 +1,140 @@
+use std::cmp::Ordering;
+use std::fmt;
+use std::sync::Arc;
+
+use crate::{
+    exp, exp_assert, CallSnapshot, Exception, ExceptionValue as EV, Expression, FromTurtle,
+    IntoTurtle, Symbol,
+};
+
+use crate::Locker;
+
+type Callable = dyn Fn(Vec<Expression>) -> Result<Expression, Exception> + Send + Sync;
+
+/// A function implemented in Rust and made available to Turtle code (see
+/// `Interpreter::register_fn`). It's called like a lambda: its arguments are
+/// evaluated first, and it must be given as many as it takes.
+#[derive(Clone)]
+pub struct NativeFunction {
+    pub name: Symbol,
+    // How many arguments the function takes (`None` if it takes any number)
+    arity: Option<usize>,
+    function: Arc<Callable>,
+}
+
+impl NativeFunction {
+    pub fn new<Args>(name: Symbol, function: impl IntoNative<Args>) -> Self {
+        let (arity, function) = function.into_native();
+        Self {
+            name,
+            arity,
+            function,
+        }
+    }
+
+    /// How many arguments the function takes, if it takes a fixed number.
+    pub fn arity(&self) -> Option<usize> {
+        self.arity
+    }
+
+    /// Calls the function with (already evaluated) `arguments`. Exceptions it
+    /// throws without a snapshot of their own are thrown from `snapshot`.
+    pub fn call(
+        &self,
+        arguments: Vec<Expression>,
+        snapshot: &Locker<CallSnapshot>,
+    ) -> Result<Expression, Exception> {
+        if let Some(arity) = self.arity {
+            exp_assert!(
+                arguments.len() == arity,
+                EV::ArgumentMismatch(arguments.len(), arity.to_string()),
+                snapshot,
+                format!("`{}` is a native function", self.name)
+            );
+        }
+        (self.function)(arguments).map_err(|err| err.or_at(snapshot))
+    }
+}
+
+impl fmt::Debug for NativeFunction {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "NativeFunction({})", self.name)
+    }
+}
+
+// Native functions are only ever the same as themselves
+impl PartialEq for NativeFunction {
+    fn eq(&self, other: &Self) -> bool {
+        Arc::ptr_eq(&self.function, &other.function)
+    }
+}
+
+impl PartialOrd for NativeFunction {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        if self == other {
+            Some(Ordering::Equal)
+        } else {
+            None
+        }
+    }
+}
+
+/// Closures that can be made into native functions: those that take up to
+/// six arguments (or a `Variadic` of any number of them) that are
+/// `FromTurtle`, and return a `Result` of something `IntoTurtle`. Arguments
+/// that can't be converted throw an `InvalidArgument` exception. `Args` only
+/// tells the implementations apart.
+pub trait IntoNative<Args> {
+    fn into_native(self) -> (Option<usize>, Arc<Callable>);
+}
+
+/// All the arguments of a native function that takes any number of them.
+pub struct Variadic<T = Expression>(pub Vec<T>);
+
+impl<F, T, R> IntoNative<Variadic<T>> for F
+where
+    F: Fn(Variadic<T>) -> Result<R, Exception> + Send + Sync + 'static,
+    T: FromTurtle,
+    R: IntoTurtle,
+{
+    fn into_native(self) -> (Option<usize>, Arc<Callable>) {
+        let function = move |arguments: Vec<Expression>| {
+            let arguments = arguments
+                .iter()
+                .map(T::from_turtle)
+                .collect::<Result<_, _>>()?;
+            self(Variadic(arguments)).map(IntoTurtle::into_turtle)
+        };
+        (None, Arc::new(function))
+    }
+}
+
+macro_rules! into_native {
+    ($arity:expr; $($arg:ident: $type:ident),*) => {
+        impl<F, R, $($type),*> IntoNative<($($type,)*)> for F
+        where
+            F: Fn($($type),*) -> Result<R, Exception> + Send + Sync + 'static,
+            R: IntoTurtle,
+            $($type: FromTurtle,)*
+        {
+            #[allow(unused_mut, unused_variables)]
+            fn into_native(self) -> (Option<usize>, Arc<Callable>) {
+                let function = move |arguments: Vec<Expression>| {
+                    // The arity has already been checked
+                    let mut arguments = arguments.iter();
+                    $(let $arg = $type::from_turtle(arguments.next().unwrap())?;)*
+                    self($($arg),*).map(IntoTurtle::into_turtle)
+                };
+                (Some($arity), Arc::new(function))
+            }
+        }
+    };
+}
+
+into_native!(0;);
+into_native!(1; a: A);
+into_native!(2; a: A, b: B);
+into_native!(3; a: A, b: B, c: C);
+into_native!(4; a: A, b: B, c: C, d: D);
+into_native!(5; a: A, b: B, c: C, d: D, e: E);
+into_native!(6; a: A, b: B, c: C, d: D, e: E, f: G);
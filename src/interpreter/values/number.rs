@@ -0,0 +1,266 @@
+use std::cmp::Ordering;
+use std::convert::TryFrom;
+use std::fmt;
+
+use num_bigint::BigInt;
+use num_rational::BigRational;
+use num_traits::{ToPrimitive, Zero};
+
+/// The most bits the exact result of `Number::pow` may have (about 1.26
+/// million decimal digits). Larger powers take seconds to minutes to compute.
+pub const MAX_POW_BITS: u64 = 1 << 22;
+
+/// A number on Turtle's numeric tower. Arithmetic on exact numbers (integers
+/// and rationals) stays exact: integers are promoted to big integers when they
+/// overflow and to rationals when they are divided. Anything involving a
+/// float is a float.
+#[derive(Debug, Clone)]
+pub enum Number {
+    Integer(i64),
+    // Only ever holds integers that don't fit in an `Integer`
+    BigInteger(BigInt),
+    // Only ever holds fractions that aren't whole numbers
+    Rational(BigRational),
+    Float(f64),
+}
+
+// Two numbers converted to the same level of the tower
+enum Pair {
+    Integers(i64, i64),
+    BigIntegers(BigInt, BigInt),
+    Rationals(BigRational, BigRational),
+    Floats(f64, f64),
+}
+
+impl Number {
+    pub fn from_bigint(val: BigInt) -> Self {
+        match val.to_i64() {
+            Some(val) => Number::Integer(val),
+            None => Number::BigInteger(val),
+        }
+    }
+
+    pub fn from_rational(val: BigRational) -> Self {
+        match val.is_integer() {
+            true => Self::from_bigint(val.to_integer()),
+            false => Number::Rational(val),
+        }
+    }
+
+    pub fn is_exact(&self) -> bool {
+        !matches!(self, Number::Float(_))
+    }
+
+    /// The name of the number's type, as returned by `type`.
+    pub fn type_name(&self) -> &'static str {
+        match self {
+            Number::Integer(_) | Number::BigInteger(_) => "integer",
+            Number::Rational(_) => "rational",
+            Number::Float(_) => "float",
+        }
+    }
+
+    pub fn to_f64(&self) -> f64 {
+        match self {
+            Number::Integer(val) => *val as f64,
+            Number::BigInteger(val) => val.to_f64().unwrap_or(f64::NAN),
+            Number::Rational(val) => val.to_f64().unwrap_or(f64::NAN),
+            Number::Float(val) => *val,
+        }
+    }
+
+    /// The number as an index or count, if it is a non-negative whole number
+    /// (floats are truncated).
+    pub fn to_usize(&self) -> Option<usize> {
+        match self {
+            Number::Integer(val) => usize::try_from(*val).ok(),
+            Number::BigInteger(val) => val.to_usize(),
+            Number::Rational(_) => None,
+            Number::Float(val) if *val >= 0.0 => Some(*val as usize),
+            Number::Float(_) => None,
+        }
+    }
+
+    fn level(&self) -> usize {
+        match self {
+            Number::Integer(_) => 0,
+            Number::BigInteger(_) => 1,
+            Number::Rational(_) => 2,
+            Number::Float(_) => 3,
+        }
+    }
+
+    fn to_bigint(&self) -> BigInt {
+        match self {
+            Number::Integer(val) => BigInt::from(*val),
+            Number::BigInteger(val) => val.clone(),
+            _ => unreachable!(),
+        }
+    }
+
+    fn to_rational(&self) -> BigRational {
+        match self {
+            Number::Rational(val) => val.clone(),
+            _ => BigRational::from_integer(self.to_bigint()),
+        }
+    }
+
+    fn promote(&self, other: &Self) -> Pair {
+        match (self, other) {
+            (Number::Integer(a), Number::Integer(b)) => Pair::Integers(*a, *b),
+            _ => match self.level().max(other.level()) {
+                1 => Pair::BigIntegers(self.to_bigint(), other.to_bigint()),
+                2 => Pair::Rationals(self.to_rational(), other.to_rational()),
+                _ => Pair::Floats(self.to_f64(), other.to_f64()),
+            },
+        }
+    }
+
+    pub fn add(&self, other: &Self) -> Self {
+        match self.promote(other) {
+            Pair::Integers(a, b) => match a.checked_add(b) {
+                Some(sum) => Number::Integer(sum),
+                None => Self::from_bigint(BigInt::from(a) + b),
+            },
+            Pair::BigIntegers(a, b) => Self::from_bigint(a + b),
+            Pair::Rationals(a, b) => Self::from_rational(a + b),
+            Pair::Floats(a, b) => Number::Float(a + b),
+        }
+    }
+
+    pub fn mul(&self, other: &Self) -> Self {
+        match self.promote(other) {
+            Pair::Integers(a, b) => match a.checked_mul(b) {
+                Some(prod) => Number::Integer(prod),
+                None => Self::from_bigint(BigInt::from(a) * b),
+            },
+            Pair::BigIntegers(a, b) => Self::from_bigint(a * b),
+            Pair::Rationals(a, b) => Self::from_rational(a * b),
+            Pair::Floats(a, b) => Number::Float(a * b),
+        }
+    }
+
+    /// The remainder of dividing `self` by `other`, which has the sign of
+    /// `self`. Returns `None` when dividing an exact number by zero.
+    pub fn rem(&self, other: &Self) -> Option<Self> {
+        match self.promote(other) {
+            Pair::Floats(a, b) => Some(Number::Float(a % b)),
+            _ if other.is_zero() => None,
+            Pair::Integers(a, b) => Some(Number::Integer(a.checked_rem(b).unwrap_or(0))),
+            Pair::BigIntegers(a, b) => Some(Self::from_bigint(a % b)),
+            Pair::Rationals(a, b) => Some(Self::from_rational(a % b)),
+        }
+    }
+
+    /// Raises `self` to the power of `exp`. The result is exact when the base
+    /// is exact and the exponent is an integer; returns `None` when raising an
+    /// exact zero to a negative power.
+    pub fn pow(&self, exp: &Self) -> Option<Self> {
+        let exact_exp = match (self.is_exact(), exp) {
+            (true, Number::Integer(exp)) => i32::try_from(*exp).ok(),
+            _ => None,
+        };
+        let exp = match exact_exp {
+            Some(exp) => exp,
+            None => return Some(Number::Float(self.to_f64().powf(exp.to_f64()))),
+        };
+        if exp < 0 && self.is_zero() {
+            return None;
+        }
+        match self {
+            Number::Integer(base) if exp >= 0 => match base.checked_pow(exp as u32) {
+                Some(val) => Some(Number::Integer(val)),
+                None => Some(Self::from_bigint(BigInt::from(*base).pow(exp as u32))),
+            },
+            _ => Some(Self::from_rational(self.to_rational().pow(exp))),
+        }
+    }
+
+    /// Estimates how many bits the result of raising `self` to the power of
+    /// `exp` takes, without computing it. An inexact result is a float, so it
+    /// takes none.
+    pub fn pow_bits(&self, exp: &Self) -> u64 {
+        let exp = match (self.is_exact(), exp) {
+            (true, Number::Integer(exp)) if i32::try_from(*exp).is_ok() => exp.unsigned_abs(),
+            _ => return 0,
+        };
+        match self {
+            Number::Rational(val) => (val.numer().bits() + val.denom().bits()) * exp,
+            // Zero and (negative) one stay the same size
+            _ => match self.to_bigint().bits() {
+                bits if bits <= 1 => bits,
+                bits => bits * exp,
+            },
+        }
+    }
+
+    pub fn floor(&self) -> Self {
+        match self {
+            Number::Rational(val) => Self::from_bigint(val.floor().to_integer()),
+            Number::Float(val) => Number::Float(val.floor()),
+            _ => self.clone(),
+        }
+    }
+
+    pub fn is_zero(&self) -> bool {
+        match self {
+            Number::Integer(val) => *val == 0,
+            Number::BigInteger(val) => val.is_zero(),
+            Number::Rational(val) => val.is_zero(),
+            Number::Float(val) => *val == 0.0,
+        }
+    }
+}
+
+impl From<i64> for Number {
+    fn from(val: i64) -> Self {
+        Number::Integer(val)
+    }
+}
+
+impl From<usize> for Number {
+    fn from(val: usize) -> Self {
+        match i64::try_from(val) {
+            Ok(val) => Number::Integer(val),
+            Err(_) => Number::BigInteger(BigInt::from(val)),
+        }
+    }
+}
+
+impl From<f64> for Number {
+    fn from(val: f64) -> Self {
+        Number::Float(val)
+    }
+}
+
+impl PartialEq for Number {
+    fn eq(&self, other: &Self) -> bool {
+        self.partial_cmp(other) == Some(Ordering::Equal)
+    }
+}
+
+// Numbers compare by value across the tower; comparisons involving a float are
+// made between floats
+impl PartialOrd for Number {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        match self.promote(other) {
+            Pair::Integers(a, b) => Some(a.cmp(&b)),
+            Pair::BigIntegers(a, b) => Some(a.cmp(&b)),
+            Pair::Rationals(a, b) => Some(a.cmp(&b)),
+            Pair::Floats(a, b) => a.partial_cmp(&b),
+        }
+    }
+}
+
+impl fmt::Display for Number {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Number::Integer(val) => write!(f, "{}", val),
+            Number::BigInteger(val) => write!(f, "{}", val),
+            Number::Rational(val) => write!(f, "{}", val),
+            // Whole floats keep their `.0`, so they can be told apart from
+            // integers
+            Number::Float(val) => write!(f, "{:?}", val),
+        }
+    }
+}
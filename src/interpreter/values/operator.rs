@@ -1,10 +1,13 @@
 use crate::{
     exp, exp_assert, parse, resolve_resource, CallSnapshot, Environment, Exception,
-    ExceptionValue as EV, Expression, Value,
+    ExceptionValue as EV, Expression, Frame, Map, Step, Value,
 };
 use regex::Regex;
 use std::fmt;
 
+use crate::interpreter::conditions::{self, Handler};
+use crate::interpreter::heap;
+use crate::interpreter::values::number::MAX_POW_BITS;
 use crate::Locker;
 
 #[derive(Debug, Clone, PartialEq, PartialOrd)]
@@ -16,6 +19,7 @@ pub enum Operator {
     Cdr,
     Cons,
     Cond,
+    If,
     Export,
     Let,
     Sum,
@@ -27,13 +31,20 @@ pub enum Operator {
     Type,
     Disp,
     Import,
+    Reload,
     Eval,
     While,
     Lambda,
     Macro,
     List,
     Catch,
+    Finally,
     Throw,
+    HandlerBind,
+    RestartCase,
+    InvokeRestart,
+    Restarts,
+    Signal,
     Format,
     Parse,
     Length,
@@ -43,19 +54,164 @@ pub enum Operator {
     Rand,
     Equiv,
     Nth,
+    Heap,
+    Hashmap,
+    Get,
+    Has,
+    Put,
+    Del,
+    Keys,
+    Values,
+    Entries,
+    Merge,
+    ExceptionKind,
+    ExceptionMessage,
+    ExceptionNote,
+    ExceptionPayload,
+    ExceptionBacktrace,
+    ExceptionCause,
 }
 
+/// The arguments an operator is called with. Operators receive their
+/// arguments unevaluated and evaluate them (or not) through this trait, so each
+/// evaluation backend can run argument code its own way.
+pub trait Arguments {
+    fn len(&self) -> usize;
+
+    fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// The unevaluated argument at `index`.
+    fn expression(&self, index: usize) -> &Expression;
+
+    /// Evaluates the argument at `index` in `env`.
+    fn eval(
+        &self,
+        index: usize,
+        snapshot: Locker<CallSnapshot>,
+        env: Locker<Environment>,
+    ) -> Result<Expression, Exception>;
+}
+
+impl Arguments for Vec<&Expression> {
+    fn len(&self) -> usize {
+        self.as_slice().len()
+    }
+
+    fn expression(&self, index: usize) -> &Expression {
+        self[index]
+    }
+
+    fn eval(
+        &self,
+        index: usize,
+        snapshot: Locker<CallSnapshot>,
+        env: Locker<Environment>,
+    ) -> Result<Expression, Exception> {
+        self[index].eval(snapshot, env)
+    }
+}
+
+// Operators are named like their variants, with a dash between words
 impl fmt::Display for Operator {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
-        write!(f, "{}", format!("{:?}", self).to_lowercase().as_str())
+        for (i, c) in format!("{:?}", self).chars().enumerate() {
+            if c.is_uppercase() && i > 0 {
+                write!(f, "-")?;
+            }
+            write!(f, "{}", c.to_ascii_lowercase())?;
+        }
+        Ok(())
     }
 }
 
 impl Operator {
+    /// Applies the operator like `apply`, but hands expressions in tail
+    /// position (the chosen `cond` branch, the last form of a `do`, and the
+    /// code run by `eval`) back to the caller instead of evaluating them.
+    pub fn step(
+        &self,
+        snapshot: Locker<CallSnapshot>,
+        arguments: &dyn Arguments,
+        expr: &Expression,
+        env: Locker<Environment>,
+    ) -> Result<Step, Exception> {
+        use crate::Operator::*;
+
+        let snap = || snapshot.clone();
+
+        match self {
+            Cond => {
+                for i in 0..arguments.len() {
+                    match &*arguments.expression(i).value().read()? {
+                        Value::List(elems) => {
+                            exp_assert!(
+                                elems.len() == 2,
+                                EV::InvalidArgument,
+                                snap(),
+                                format!(
+                                    "each `cond` condition must be a list of length two (the given list has {} elements)",
+                                    elems.len()
+                                )
+                            );
+                            let cond = { elems.first().unwrap() };
+                            if cond.eval(snap(), env.clone())? != Expression::nil() {
+                                let val = { elems.get(1).unwrap() };
+                                return Ok(Step::Tail(val.clone(), env));
+                            }
+                        }
+                        val => exp!(
+                            EV::InvalidArgument,
+                            snap(),
+                            format!("`cond` must be called on a list, got `{}`", val)
+                        ),
+                    }
+                }
+                Ok(Step::Done(Expression::nil()))
+            }
+            If => {
+                exp_assert!(
+                    arguments.len() == 2,
+                    EV::ArgumentMismatch(arguments.len(), "2".to_string()),
+                    snapshot
+                );
+                match arguments.eval(0, snap(), env.clone())? != Expression::nil() {
+                    true => Ok(Step::Tail(arguments.expression(1).clone(), env)),
+                    false => Ok(Step::Done(Expression::nil())),
+                }
+            }
+            Eval => {
+                if arguments.len() != 1 {
+                    exp!(
+                        EV::ArgumentMismatch(arguments.len(), "1".to_string()),
+                        snapshot
+                    );
+                }
+                let evaled = arguments.eval(0, snap(), env.clone())?;
+                Ok(Step::Tail(evaled, env))
+            }
+            Do => {
+                exp_assert!(
+                    !arguments.is_empty(),
+                    EV::ArgumentMismatch(arguments.len(), "1+".to_string()),
+                    snapshot
+                );
+
+                let last = arguments.len() - 1;
+                for i in 0..last {
+                    arguments.eval(i, snap(), env.clone())?;
+                }
+                Ok(Step::Tail(arguments.expression(last).clone(), env))
+            }
+            _ => Ok(Step::Done(self.apply(snapshot, arguments, expr, env)?)),
+        }
+    }
+
     pub fn apply(
         &self,
         snapshot: Locker<CallSnapshot>,
-        arguments: Vec<&Expression>,
+        arguments: &dyn Arguments,
         expr: &Expression,
         env: Locker<Environment>,
     ) -> Result<Expression, Exception> {
@@ -65,6 +221,10 @@ impl Operator {
         let snap = || snapshot.clone();
 
         match self {
+            Cond | If | Eval | Do => match self.step(snap(), arguments, expr, env)? {
+                Step::Done(result) => Ok(result),
+                Step::Tail(exp, env) => exp.eval(snapshot, env),
+            },
             Quote => {
                 if arguments.len() != 1 {
                     exp!(
@@ -72,8 +232,7 @@ impl Operator {
                         snapshot
                     );
                 }
-                let arg = *arguments.get(0).unwrap();
-                Ok(arg.clone())
+                Ok(arguments.expression(0).clone())
             }
             Atom => {
                 exp_assert!(
@@ -81,13 +240,7 @@ impl Operator {
                     EV::ArgumentMismatch(arguments.len(), "1".to_string()),
                     snapshot
                 );
-                match &*arguments
-                    .get(0)
-                    .unwrap()
-                    .eval(snapshot, env)?
-                    .value()
-                    .read()?
-                {
+                match &*arguments.eval(0, snapshot, env)?.value().read()? {
                     Value::List(_) => Ok(Expression::new(Value::List(vec![]))),
                     _ => Ok(Expression::new(Value::True)),
                 }
@@ -100,8 +253,8 @@ impl Operator {
                 );
 
                 let mut prev: Option<Expression> = None;
-                for argument in arguments {
-                    let evaled = argument.eval(snap(), env.clone())?;
+                for i in 0..arguments.len() {
+                    let evaled = arguments.eval(i, snap(), env.clone())?;
                     match &prev {
                         None => prev = Some(evaled),
                         Some(val) => match (&*evaled.value().read()?, &*val.value().read()?) {
@@ -127,7 +280,7 @@ impl Operator {
                     snap()
                 );
 
-                let list = arguments.get(0).unwrap().eval(snap(), env)?;
+                let list = arguments.eval(0, snap(), env)?;
 
                 match &*list.value().read()? {
                     Value::List(vals) => {
@@ -137,7 +290,7 @@ impl Operator {
                             snap(),
                             "cannot `car` an empty list (nil)".to_string()
                         );
-                        Ok(vals.get(0).unwrap().clone())
+                        Ok(vals.first().unwrap().clone())
                     }
                     val => exp!(
                         EV::InvalidArgument,
@@ -152,7 +305,7 @@ impl Operator {
                     EV::ArgumentMismatch(arguments.len(), "1".to_string()),
                     snap()
                 );
-                let list = arguments.get(0).unwrap().eval(snap(), env)?;
+                let list = arguments.eval(0, snap(), env)?;
                 match &*list.value().read()? {
                     Value::List(vals) => Ok(Expression::new(Value::List(
                         vals.iter().skip(1).cloned().collect(),
@@ -170,14 +323,15 @@ impl Operator {
                     EV::ArgumentMismatch(arguments.len(), "2".to_string()),
                     snap()
                 );
-                let first = arguments.get(0).unwrap().eval(snap(), env.clone())?;
-                let list = arguments.get(1).unwrap().eval(snap(), env)?;
+                let first = arguments.eval(0, snap(), env.clone())?;
+                let list = arguments.eval(1, snap(), env)?;
                 match &*list.value().read()? {
                     Value::List(vals) => {
+                        CallSnapshot::check_length(&snapshot, vals.len() + 1)?;
                         // TODO: do this without clone
                         let mut new_list = vals.clone();
                         new_list.insert(0, first);
-                        Ok(Expression::new(Value::List(new_list)))
+                        built_list(new_list, expr, &snapshot)
                     }
                     val => exp!(
                         EV::InvalidArgument,
@@ -189,43 +343,15 @@ impl Operator {
                     ),
                 }
             }
-            Cond => {
-                for argument in &arguments {
-                    match &*argument.value().read()? {
-                        Value::List(elems) => {
-                            exp_assert!(
-                                elems.len() == 2,
-                                EV::InvalidArgument,
-                                snap(),
-                                format!(
-                                    "each `cond` condition must be a list of length two (the given list has {} elements)",
-                                    elems.len()
-                                )
-                            );
-                            let cond = { elems.get(0).unwrap() };
-                            if cond.eval(snap(), env.clone())? != Expression::nil() {
-                                let val = { elems.get(1).unwrap() };
-                                return val.eval(snapshot, env);
-                            }
-                        }
-                        val => exp!(
-                            EV::InvalidArgument,
-                            snap(),
-                            format!("`cond` must be called on a list, got `{}`", val)
-                        ),
-                    }
-                }
-                Ok(Expression::nil())
-            }
             Export | Let => {
                 exp_assert!(
                     arguments.len() == 2,
                     EV::ArgumentMismatch(arguments.len(), "2".to_string()),
                     snap()
                 );
-                let sym_exp = arguments.get(0).unwrap().eval(snap(), env.clone())?;
+                let sym_exp = arguments.eval(0, snap(), env.clone())?;
                 let symbol = match &*sym_exp.value().read()? {
-                    Symbol(sym) => sym.clone(),
+                    Symbol(sym) => *sym,
                     other => exp!(
                         EV::InvalidArgument,
                         snap(),
@@ -236,9 +362,9 @@ impl Operator {
                     ),
                 };
 
-                let assigned_expr = arguments.get(1).unwrap().eval(snap(), env.clone())?;
+                let assigned_expr = name_function(arguments.eval(1, snap(), env.clone())?, symbol);
                 env.write().unwrap().assign(
-                    symbol.clone(),
+                    symbol,
                     assigned_expr.clone(),
                     !matches!(self, Export),
                     snap(),
@@ -246,10 +372,10 @@ impl Operator {
                 Ok(assigned_expr)
             }
             Sum => {
-                let mut sum = 0.0;
-                for arg in arguments {
-                    match &*arg.eval(snap(), env.clone())?.value().read()? {
-                        Number(val) => sum += val,
+                let mut sum = crate::Number::Integer(0);
+                for i in 0..arguments.len() {
+                    match &*arguments.eval(i, snap(), env.clone())?.value().read()? {
+                        Value::Number(val) => sum = sum.add(val),
                         val => exp!(
                             EV::InvalidArgument,
                             snap(),
@@ -260,10 +386,10 @@ impl Operator {
                 Ok(Expression::new(Value::Number(sum)))
             }
             Prod => {
-                let mut prod = 1.0;
-                for arg in arguments {
-                    match &*arg.eval(snap(), env.clone())?.value().read()? {
-                        Number(val) => prod *= val,
+                let mut prod = crate::Number::Integer(1);
+                for i in 0..arguments.len() {
+                    match &*arguments.eval(i, snap(), env.clone())?.value().read()? {
+                        Value::Number(val) => prod = prod.mul(val),
                         val => exp!(
                             EV::InvalidArgument,
                             snap(),
@@ -279,12 +405,32 @@ impl Operator {
                     EV::ArgumentMismatch(arguments.len(), "2".to_string()),
                     snap()
                 );
-                let base = arguments.get(0).unwrap().eval(snap(), env.clone())?;
-                let exp = arguments.get(1).unwrap().eval(snap(), env)?;
+                let base = arguments.eval(0, snap(), env.clone())?;
+                let exp = arguments.eval(1, snap(), env)?;
                 match (&*base.value().read()?, &*exp.value().read()?) {
-                    (Number(base), Number(exp)) => {
-                        Ok(Expression::new(Value::Number(base.powf(*exp))))
+                    (Value::Number(base), Value::Number(exp))
+                        if base.pow_bits(exp) > MAX_POW_BITS =>
+                    {
+                        exp!(
+                            EV::LengthLimit,
+                            snap(),
+                            format!(
+                                "`exp` can't compute `{}` to the power of `{}` (the result would take about {} bits, and may take at most {})",
+                                base,
+                                exp,
+                                base.pow_bits(exp),
+                                MAX_POW_BITS
+                            )
+                        )
                     }
+                    (Value::Number(base), Value::Number(exp)) => match base.pow(exp) {
+                        Some(val) => Ok(Expression::new(Value::Number(val))),
+                        None => exp!(
+                            EV::InvalidArgument,
+                            snap(),
+                            format!("`exp` can't raise `{}` to a negative power", base)
+                        ),
+                    },
                     (base, exp) => exp!(
                         EV::InvalidArgument,
                         snap(),
@@ -301,12 +447,17 @@ impl Operator {
                     EV::ArgumentMismatch(arguments.len(), "2".to_string()),
                     snap()
                 );
-                let val = arguments.get(0).unwrap().eval(snap(), env.clone())?;
-                let modu = arguments.get(1).unwrap().eval(snap(), env)?;
+                let val = arguments.eval(0, snap(), env.clone())?;
+                let modu = arguments.eval(1, snap(), env)?;
                 match (&*val.value().read()?, &*modu.value().read()?) {
-                    (Number(first), Number(second)) => {
-                        Ok(Expression::new(Value::Number(first % second)))
-                    }
+                    (Value::Number(first), Value::Number(second)) => match first.rem(second) {
+                        Some(val) => Ok(Expression::new(Value::Number(val))),
+                        None => exp!(
+                            EV::InvalidArgument,
+                            snap(),
+                            format!("`modulo` can't divide `{}` by zero", first)
+                        ),
+                    },
                     (base, exp) => exp!(
                         EV::InvalidArgument,
                         snap(),
@@ -318,8 +469,8 @@ impl Operator {
             }
             Gt => {
                 let mut args_evaled = Vec::with_capacity(arguments.len());
-                for arg in arguments {
-                    args_evaled.push(arg.eval(snap(), env.clone())?);
+                for i in 0..arguments.len() {
+                    args_evaled.push(arguments.eval(i, snap(), env.clone())?);
                 }
                 match args_evaled
                     .iter()
@@ -333,8 +484,8 @@ impl Operator {
             }
             Ge => {
                 let mut args_evaled = Vec::with_capacity(arguments.len());
-                for arg in arguments {
-                    args_evaled.push(arg.eval(snap(), env.clone())?);
+                for i in 0..arguments.len() {
+                    args_evaled.push(arguments.eval(i, snap(), env.clone())?);
                 }
                 match args_evaled
                     .iter()
@@ -352,85 +503,65 @@ impl Operator {
                     EV::ArgumentMismatch(arguments.len(), "1".to_string()),
                     snap()
                 );
-                let arg_type = arguments
-                    .get(0)
-                    .unwrap()
-                    .eval(snap(), env)?
-                    .value()
-                    .read()?
-                    .as_type();
+                let arg_type = arguments.eval(0, snap(), env)?.value().read()?.as_type();
                 Ok(Expression::new(arg_type))
             }
             Disp => {
-                for arg in arguments {
-                    println!("{}", arg.eval(snap(), env.clone())?);
+                for i in 0..arguments.len() {
+                    println!("{}", arguments.eval(i, snap(), env.clone())?);
                 }
                 Ok(Expression::nil())
             }
-            Import => {
+            Import | Reload => {
                 if !(arguments.len() == 1 || arguments.len() == 2) {
                     exp!(
                         EV::ArgumentMismatch(arguments.len(), "1 or 2".to_string()),
                         snapshot
                     );
                 }
-                let res = arguments.get(0).unwrap().eval(snap(), env.clone())?;
+                let res = arguments.eval(0, snap(), env.clone())?;
                 let path = match &*res.value().read()? {
                     Text(val) => val.clone(),
                     val => exp!(
                         EV::InvalidArgument,
                         snapshot,
                         format!(
-                            "`import` requires the path (:text) as its first argument (got `{}` instead)",
-                            val
+                            "`{}` requires the path (:text) as its first argument (got `{}` instead)",
+                            self, val
                         )
                     ),
                 };
 
-                let namespace = match arguments.get(1) {
-                    Some(val) => match &*val.eval(snap(), env.clone())?.value().read()? {
+                let namespace = match arguments.len() > 1 {
+                    true => match &*arguments.eval(1, snap(), env.clone())?.value().read()? {
                         Keyword(val) => Some(val.string_value().clone()),
                         val => exp!(
                             EV::InvalidArgument,
                             snapshot,
                             format!(
-                                "`import` requires the namespace (:keyword) as its second argument (got `{}` instead)",
-                                val
+                                "`{}` requires the namespace (:keyword) as its second argument (got `{}` instead)",
+                                self, val
                             )
                         ),
                     },
-                    None => None
+                    false => None
                 };
 
-                let imported_env = Locker::new(Environment::root());
-                let exp = resolve_resource(&path, snapshot, expr, imported_env.clone())?;
-                env.write().unwrap().add_parent(imported_env, namespace);
+                let reload = matches!(self, crate::Operator::Reload);
+                let (imported_env, exp) = resolve_resource(&path, snapshot, expr, reload)?;
+                env.write()?.add_import(imported_env, namespace);
                 Ok(exp)
             }
-            Eval => {
-                if arguments.len() != 1 {
-                    exp!(
-                        EV::ArgumentMismatch(arguments.len(), "1".to_string()),
-                        snapshot
-                    );
-                }
-                arguments
-                    .get(0)
-                    .unwrap()
-                    .eval(snap(), env.clone())?
-                    .eval(snap(), env)
-            }
             While => {
                 exp_assert!(
                     arguments.len() >= 2,
                     EV::ArgumentMismatch(arguments.len(), "2+".to_string()),
                     snapshot
                 );
-                let condition = arguments.get(0).unwrap();
                 let mut result = Expression::nil();
-                while condition.eval(snap(), env.clone())? != Expression::nil() {
-                    for action in arguments.iter().skip(1) {
-                        result = action.eval(snap(), env.clone())?
+                while arguments.eval(0, snap(), env.clone())? != Expression::nil() {
+                    for i in 1..arguments.len() {
+                        result = arguments.eval(i, snap(), env.clone())?
                     }
                 }
                 Ok(result)
@@ -443,25 +574,19 @@ impl Operator {
                 );
 
                 let mut collapse_input = true;
-                let func_args = match &*arguments
-                    .get(0)
-                    .unwrap()
-                    .eval(snap(), env.clone())?
-                    .value()
-                    .read()?
-                {
+                let func_args = match &*arguments.eval(0, snap(), env.clone())?.value().read()? {
                     Value::List(vals) => {
                         collapse_input = false;
                         let mut symbols = Vec::new();
                         for val in vals {
                             match &*val.value().read()? {
-                                Value::Symbol(sym) => symbols.push(sym.clone()),
+                                Value::Symbol(sym) => symbols.push(*sym),
                                 other => exp!(EV::InvalidArgument, snapshot, format!("each item in the first argument (a list) must be a symbol (got `{}`)", other)),
                             }
                         }
                         symbols
                     }
-                    Value::Symbol(sym) => vec![sym.clone()],
+                    Value::Symbol(sym) => vec![*sym],
                     val => exp!(
                         EV::InvalidArgument,
                         snapshot,
@@ -472,8 +597,8 @@ impl Operator {
                     ),
                 };
                 let mut func_expressions = Vec::new();
-                for arg_expr in arguments.iter().skip(1) {
-                    func_expressions.push(arg_expr.eval(snap(), env.clone())?);
+                for i in 1..arguments.len() {
+                    func_expressions.push(arguments.eval(i, snap(), env.clone())?);
                 }
 
                 (match self {
@@ -496,11 +621,12 @@ impl Operator {
                 .eval(snap(), env)
             }
             crate::Operator::List => {
+                CallSnapshot::check_length(&snapshot, arguments.len())?;
                 let mut args_evaled = Vec::new();
-                for argument in arguments {
-                    args_evaled.push(argument.eval(snap(), env.clone())?);
+                for i in 0..arguments.len() {
+                    args_evaled.push(arguments.eval(i, snap(), env.clone())?);
                 }
-                Ok(Expression::new(Value::List(args_evaled)))
+                built_list(args_evaled, expr, &snapshot)
             }
             Catch => {
                 exp_assert!(
@@ -508,14 +634,29 @@ impl Operator {
                     EV::ArgumentMismatch(arguments.len(), "2".to_string()),
                     snapshot
                 );
-                let action = arguments.get(0).unwrap().eval(snap(), env.clone());
-                let catch_func = arguments.get(1).unwrap().eval(snap(), env.clone())?;
+                // Handlers outside of the `catch` never see what it catches
+                let caught = CallSnapshot::with_handler(&snapshot, Handler::Catch)?;
+                let action = arguments.eval(0, caught, env.clone());
+                let catch_func = arguments.eval(1, snap(), env.clone())?;
                 match action {
                     Ok(exp) => Ok(exp),
+                    // Invoking a restart isn't an error to handle
+                    Err(err) if matches!(err.value(), EV::Restart(_, _)) => Err(err),
                     Err(err) => {
                         // TODO: remove extra clone
                         match &*catch_func.value().read()? {
-                            Value::Lambda{..} => Expression::new(Value::List(vec![catch_func.clone(), err.into_value().into_expression()])).eval(snapshot, Locker::new(Environment::root().with_parent(env, None))),
+                            Value::Lambda(function) => {
+                                let mut call = vec![catch_func.clone()];
+                                // Handlers that take a second argument are also
+                                // given the backtrace, as a list of frames
+                                let backtrace = err.backtrace();
+                                call.push(Expression::new(Value::Exception(Box::new(err))));
+                                if function.params.len() == 2 && !function.collapse_input {
+                                    let frames = backtrace.into_iter().map(Frame::into_expression).collect();
+                                    call.push(Expression::new(Value::List(vec![Expression::new(Value::Operator(Quote)), Expression::new(Value::List(frames))])));
+                                }
+                                Expression::new(Value::List(call)).eval(snapshot, Locker::new(Environment::root().with_parent(env, None)))
+                            }
                             _ => exp!(
                                 EV::InvalidArgument,
                                 snapshot,
@@ -525,17 +666,129 @@ impl Operator {
                     }
                 }
             }
+            Finally => {
+                exp_assert!(
+                    arguments.len() == 2,
+                    EV::ArgumentMismatch(arguments.len(), "2".to_string()),
+                    snapshot
+                );
+                // The cleanup runs however the protected code finished (code
+                // that hit a step or time limit is still in the grace period
+                // for handling it). An exception the cleanup throws replaces
+                // the protected code's own, which becomes its cause.
+                let result = arguments.eval(0, snap(), env.clone());
+                match (arguments.eval(1, snap(), env), result) {
+                    (Ok(_), result) => result,
+                    (Err(err), Ok(_)) => Err(err),
+                    (Err(err), Err(cause)) => Err(err.with_cause(cause)),
+                }
+            }
+            HandlerBind => {
+                exp_assert!(
+                    arguments.len() == 2,
+                    EV::ArgumentMismatch(arguments.len(), "2".to_string()),
+                    snapshot
+                );
+                let handler = eval_function(self, arguments, 1, &snapshot, env.clone())?;
+                let bound = CallSnapshot::with_handler(&snapshot, Handler::Function(handler))?;
+                arguments.eval(0, bound, env)
+            }
+            RestartCase => {
+                exp_assert!(
+                    !arguments.len().is_multiple_of(2),
+                    EV::ArgumentMismatch(arguments.len(), "an odd number".to_string()),
+                    snapshot,
+                    "`restart-case` takes code to run, then alternating restart names and functions"
+                        .to_string()
+                );
+                let mut restarts = Vec::with_capacity(arguments.len() / 2);
+                for i in (1..arguments.len()).step_by(2) {
+                    let name = eval_symbol(self, arguments, i, &snapshot, env.clone())?;
+                    let function = eval_function(self, arguments, i + 1, &snapshot, env.clone())?;
+                    restarts.push((name, function));
+                }
+                let restarts = crate::Restart::establish(restarts);
+                let established = CallSnapshot::with_restarts(&snapshot, restarts.clone())?;
+                let err = match arguments.eval(0, established, env.clone()) {
+                    Err(err) => err,
+                    result => return result,
+                };
+                match err.value() {
+                    EV::Restart(restart, args)
+                        if restarts.iter().any(|r| r.is_sibling(restart)) =>
+                    {
+                        call_with(&restart.function, args.clone(), snapshot, env)
+                    }
+                    _ => Err(err),
+                }
+            }
+            InvokeRestart => {
+                exp_assert!(
+                    !arguments.is_empty(),
+                    EV::ArgumentMismatch(arguments.len(), "1+".to_string()),
+                    snapshot
+                );
+                let name = eval_symbol(self, arguments, 0, &snapshot, env.clone())?;
+                let mut args = Vec::with_capacity(arguments.len() - 1);
+                for i in 1..arguments.len() {
+                    args.push(arguments.eval(i, snap(), env.clone())?);
+                }
+                let restarts = snapshot.read()?.restarts();
+                match restarts.into_iter().find(|restart| restart.name == name) {
+                    Some(restart) => Err(conditions::invoke(restart, args, &snapshot)),
+                    None => exp!(
+                        EV::InvalidArgument,
+                        snapshot,
+                        format!("no restart named `{}` is available here", name)
+                    ),
+                }
+            }
+            Restarts => {
+                exp_assert!(
+                    arguments.is_empty(),
+                    EV::ArgumentMismatch(arguments.len(), "0".to_string()),
+                    snapshot
+                );
+                let restarts = snapshot.read()?.restarts();
+                Ok(Expression::new(Value::List(
+                    restarts
+                        .into_iter()
+                        .map(|restart| Expression::new(Value::Symbol(restart.name)))
+                        .collect(),
+                )))
+            }
+            Signal => {
+                exp_assert!(
+                    arguments.len() == 1,
+                    EV::ArgumentMismatch(arguments.len(), "1".to_string()),
+                    snapshot
+                );
+                let condition = arguments.eval(0, snap(), env)?;
+                let mut err = match &*condition.value().read()? {
+                    Value::Exception(err) => (**err).clone(),
+                    _ => crate::Exception::new(EV::Other(condition.clone()), Some(snap()), None),
+                };
+                // Unlike throwing, signalling returns nil if no handler
+                // invokes a restart (or throws)
+                err.set_signalled(false);
+                conditions::signal(&mut err, &snapshot, false)?;
+                Ok(Expression::nil())
+            }
             Throw => {
                 exp_assert!(
                     arguments.len() == 1,
                     EV::ArgumentMismatch(arguments.len(), "1".to_string()),
                     snapshot
                 );
-                Err(Exception::new(
-                    EV::Other(arguments.get(0).unwrap().eval(snap(), env)?),
-                    Some(snap()),
-                    None,
-                ))
+                let thrown = arguments.eval(0, snap(), env)?;
+                // Caught exceptions are thrown again as they were
+                if let Value::Exception(err) = &*thrown.value().read()? {
+                    let mut err = (**err).clone();
+                    // Handlers get to see it again from here
+                    err.set_signalled(false);
+                    return Err(err);
+                }
+                Err(crate::Exception::new(EV::Other(thrown), Some(snap()), None))
             }
             Format => {
                 exp_assert!(
@@ -543,13 +796,7 @@ impl Operator {
                     EV::ArgumentMismatch(arguments.len(), "1+".to_string()),
                     snapshot
                 );
-                let mut literal = match &*arguments
-                    .get(0)
-                    .unwrap()
-                    .eval(snap(), env.clone())?
-                    .value()
-                    .read()?
-                {
+                let mut literal = match &*arguments.eval(0, snap(), env.clone())?.value().read()? {
                     Text(value) => value.clone(),
                     other => return Ok(Expression::new(Value::Text(format!("{}", other)))),
                 };
@@ -562,8 +809,7 @@ impl Operator {
                     format!("`{}` has {} placeholders, so {} total arguments are necessary (including the first string literal)", literal, interpolations.len(), interpolations.len() + 1)
                 );
                 for i in 1..arguments.len() {
-                    let replace_with =
-                        format!("{}", arguments.get(i).unwrap().eval(snap(), env.clone())?);
+                    let replace_with = format!("{}", arguments.eval(i, snap(), env.clone())?);
                     literal = String::from(placeholder.replace(&literal, replace_with.as_str()));
                 }
                 Ok(Expression::new(Value::Text(literal)))
@@ -574,7 +820,7 @@ impl Operator {
                     EV::ArgumentMismatch(arguments.len(), "1".to_string()),
                     snapshot
                 );
-                let val = arguments.get(0).unwrap().eval(snap(), env)?;
+                let val = arguments.eval(0, snap(), env)?;
                 let value_str = match &*val.value().read()? {
                     Text(value) => value.clone(),
                     other => exp!(
@@ -601,19 +847,14 @@ impl Operator {
                     EV::ArgumentMismatch(arguments.len(), "1".to_string()),
                     snapshot
                 );
-                match &*arguments
-                    .get(0)
-                    .unwrap()
-                    .eval(snap(), env)?
-                    .value()
-                    .read()?
-                {
-                    Value::List(vals) => Ok(Expression::new(Value::Number(vals.len() as f64))),
+                match &*arguments.eval(0, snap(), env)?.value().read()? {
+                    Value::List(vals) => Ok(Expression::new(Value::Number(vals.len().into()))),
+                    Value::Map(map) => Ok(Expression::new(Value::Number(map.len().into()))),
                     other => exp!(
                         EV::InvalidArgument,
                         snapshot,
                         format!(
-                            "length expects a list as its first argument (got `{}`)",
+                            "length expects a list or a map as its first argument (got `{}`)",
                             other
                         )
                     ),
@@ -626,9 +867,12 @@ impl Operator {
                     snapshot
                 );
                 let mut new_list: Vec<Expression> = Vec::with_capacity(arguments.len());
-                for argument in arguments {
-                    match &*argument.eval(snap(), env.clone())?.value().read()? {
-                        Value::List(values) => new_list.extend(values.clone()),
+                for i in 0..arguments.len() {
+                    match &*arguments.eval(i, snap(), env.clone())?.value().read()? {
+                        Value::List(values) => {
+                            CallSnapshot::check_length(&snapshot, new_list.len() + values.len())?;
+                            new_list.extend(values.clone())
+                        }
                         other => exp!(
                             EV::InvalidArgument,
                             snapshot,
@@ -639,20 +883,7 @@ impl Operator {
                         ),
                     }
                 }
-                Ok(Expression::new(Value::List(new_list)))
-            }
-            Do => {
-                exp_assert!(
-                    !arguments.is_empty(),
-                    EV::ArgumentMismatch(arguments.len(), "1+".to_string()),
-                    snapshot
-                );
-
-                let mut result = Expression::nil();
-                for argument in arguments {
-                    result = argument.eval(snap(), env.clone())?;
-                }
-                Ok(result)
+                built_list(new_list, expr, &snapshot)
             }
             Floor => {
                 exp_assert!(
@@ -660,14 +891,8 @@ impl Operator {
                     EV::ArgumentMismatch(arguments.len(), "1".to_string()),
                     snapshot
                 );
-                match &*arguments
-                    .get(0)
-                    .unwrap()
-                    .eval(snap(), env.clone())?
-                    .value()
-                    .read()?
-                {
-                    Number(val) => Ok(Expression::new(Value::Number(val.floor()))),
+                match &*arguments.eval(0, snap(), env.clone())?.value().read()? {
+                    Value::Number(val) => Ok(Expression::new(Value::Number(val.floor()))),
                     val => exp!(
                         EV::InvalidArgument,
                         snap(),
@@ -677,11 +902,13 @@ impl Operator {
             }
             Rand => {
                 exp_assert!(
-                    arguments.len() == 0,
+                    arguments.is_empty(),
                     EV::ArgumentMismatch(arguments.len(), "0".to_string()),
                     snapshot
                 );
-                Ok(Expression::new(Value::Number(rand::random())))
+                Ok(Expression::new(Value::Number(crate::Number::Float(
+                    rand::random(),
+                ))))
             }
             Equiv => {
                 exp_assert!(
@@ -689,9 +916,9 @@ impl Operator {
                     EV::ArgumentMismatch(arguments.len(), "2+".to_string()),
                     snapshot
                 );
-                let equals = arguments.get(0).unwrap().eval(snap(), env.clone())?;
-                for i in arguments.iter().skip(1) {
-                    if i.eval(snap(), env.clone())? != equals {
+                let equals = arguments.eval(0, snap(), env.clone())?;
+                for i in 1..arguments.len() {
+                    if arguments.eval(i, snap(), env.clone())? != equals {
                         return Ok(Expression::nil());
                     }
                 }
@@ -703,14 +930,15 @@ impl Operator {
                     EV::ArgumentMismatch(arguments.len(), "2".to_string()),
                     snapshot
                 );
-                let index = match &*arguments
-                    .get(0)
-                    .unwrap()
-                    .eval(snap(), env.clone())?
-                    .value()
-                    .read()?
-                {
-                    Number(n) => *n as usize,
+                let index = match &*arguments.eval(0, snap(), env.clone())?.value().read()? {
+                    Value::Number(n) => match n.to_usize() {
+                        Some(index) => index,
+                        None => exp!(
+                            EV::InvalidArgument,
+                            snapshot,
+                            format!("`{}` is not a valid index for `nth`", n)
+                        ),
+                    },
                     val => exp!(
                         EV::InvalidArgument,
                         snapshot,
@@ -720,7 +948,7 @@ impl Operator {
                         )
                     ),
                 };
-                let list_exp = arguments.get(1).unwrap().eval(snap(), env.clone())?;
+                let list_exp = arguments.eval(1, snap(), env.clone())?;
                 match &*list_exp.value().read()? {
                     Value::List(v) => match v.get(index) {
                         Some(v) => Ok(v.clone()),
@@ -744,6 +972,312 @@ impl Operator {
                     ),
                 }
             }
+            Heap => {
+                exp_assert!(
+                    arguments.len() <= 1,
+                    EV::ArgumentMismatch(arguments.len(), "0-1".to_string()),
+                    snapshot
+                );
+                if arguments.len() == 1 {
+                    match &*arguments.eval(0, snap(), env)?.value().read()? {
+                        Keyword(val) if val.string_value() == "collect" => {
+                            heap::request_collection();
+                        }
+                        val => exp!(
+                            EV::InvalidArgument,
+                            snapshot,
+                            format!(
+                                "`heap` only accepts `:collect` as an argument (got `{}`)",
+                                val
+                            )
+                        ),
+                    }
+                }
+                let stats = heap::stats();
+                let stat = |name: &str, value: usize| {
+                    Expression::new(Value::List(vec![
+                        Expression::new(Value::Keyword(crate::Keyword::new(name.to_string()))),
+                        Expression::new(Value::Number(value.into())),
+                    ]))
+                };
+                Ok(Expression::new(Value::List(vec![
+                    stat("environments", stats.environments),
+                    stat("scopes", stats.scopes),
+                    stat("collections", stats.collections),
+                    stat("collected", stats.collected),
+                ])))
+            }
+            Hashmap => {
+                exp_assert!(
+                    arguments.len().is_multiple_of(2),
+                    EV::ArgumentMismatch(arguments.len(), "an even number".to_string()),
+                    snapshot,
+                    "`hashmap` takes alternating keys and values".to_string()
+                );
+                CallSnapshot::check_length(&snapshot, arguments.len() / 2)?;
+                let mut map = crate::Map::new();
+                for i in (0..arguments.len()).step_by(2) {
+                    let key = arguments.eval(i, snap(), env.clone())?;
+                    let value = arguments.eval(i + 1, snap(), env.clone())?;
+                    map = map.insert(key.value().read()?.clone(), value);
+                }
+                Ok(Expression::new(Value::Map(map)))
+            }
+            Get => {
+                exp_assert!(
+                    arguments.len() == 2 || arguments.len() == 3,
+                    EV::ArgumentMismatch(arguments.len(), "2-3".to_string()),
+                    snapshot
+                );
+                let key = arguments.eval(0, snap(), env.clone())?;
+                let map = eval_map(self, arguments, 1, &snapshot, env.clone())?;
+                let value = map.get(&*key.value().read()?).cloned();
+                match value {
+                    Some(value) => Ok(value),
+                    None if arguments.len() == 3 => arguments.eval(2, snap(), env),
+                    None => Ok(Expression::nil()),
+                }
+            }
+            Has => {
+                exp_assert!(
+                    arguments.len() == 2,
+                    EV::ArgumentMismatch(arguments.len(), "2".to_string()),
+                    snapshot
+                );
+                let key = arguments.eval(0, snap(), env.clone())?;
+                let map = eval_map(self, arguments, 1, &snapshot, env)?;
+                let contains = map.contains(&*key.value().read()?);
+                match contains {
+                    true => Ok(Expression::t()),
+                    false => Ok(Expression::nil()),
+                }
+            }
+            Put => {
+                exp_assert!(
+                    arguments.len() == 3,
+                    EV::ArgumentMismatch(arguments.len(), "3".to_string()),
+                    snapshot
+                );
+                let key = arguments.eval(0, snap(), env.clone())?;
+                let value = arguments.eval(1, snap(), env.clone())?;
+                let map = eval_map(self, arguments, 2, &snapshot, env)?;
+                CallSnapshot::check_length(&snapshot, map.len() + 1)?;
+                let key = key.value().read()?.clone();
+                Ok(Expression::new(Value::Map(map.insert(key, value))))
+            }
+            Del => {
+                exp_assert!(
+                    arguments.len() == 2,
+                    EV::ArgumentMismatch(arguments.len(), "2".to_string()),
+                    snapshot
+                );
+                let key = arguments.eval(0, snap(), env.clone())?;
+                let map = eval_map(self, arguments, 1, &snapshot, env)?;
+                let map = map.remove(&*key.value().read()?);
+                Ok(Expression::new(Value::Map(map)))
+            }
+            Keys | Values | Entries => {
+                exp_assert!(
+                    arguments.len() == 1,
+                    EV::ArgumentMismatch(arguments.len(), "1".to_string()),
+                    snapshot
+                );
+                let map = eval_map(self, arguments, 0, &snapshot, env)?;
+                CallSnapshot::check_length(&snapshot, map.len())?;
+                let items = map
+                    .iter()
+                    .map(|(key, value)| match self {
+                        Keys => Expression::new(key.clone()),
+                        Values => value.clone(),
+                        _ => Expression::new(Value::List(vec![
+                            Expression::new(key.clone()),
+                            value.clone(),
+                        ])),
+                    })
+                    .collect();
+                Ok(Expression::new(Value::List(items)))
+            }
+            Merge => {
+                exp_assert!(
+                    !arguments.is_empty(),
+                    EV::ArgumentMismatch(arguments.len(), "1+".to_string()),
+                    snapshot
+                );
+                let mut merged = crate::Map::new();
+                for i in 0..arguments.len() {
+                    let map = eval_map(self, arguments, i, &snapshot, env.clone())?;
+                    merged = merged.merge(&map);
+                    CallSnapshot::check_length(&snapshot, merged.len())?;
+                }
+                Ok(Expression::new(Value::Map(merged)))
+            }
+            ExceptionKind | ExceptionMessage | ExceptionNote | ExceptionPayload
+            | ExceptionBacktrace | ExceptionCause => {
+                exp_assert!(
+                    arguments.len() == 1,
+                    EV::ArgumentMismatch(arguments.len(), "1".to_string()),
+                    snapshot
+                );
+                let err = eval_exception(self, arguments, 0, &snapshot, env)?;
+                Ok(match self {
+                    ExceptionKind => err.value().clone().into_expression(),
+                    ExceptionMessage => Expression::new(Value::Text(err.value().explain())),
+                    ExceptionNote => match err.note() {
+                        Some(note) => Expression::new(Value::Text(note.to_string())),
+                        None => Expression::nil(),
+                    },
+                    ExceptionPayload => err.value().payload(),
+                    ExceptionCause => match err.cause() {
+                        Some(cause) => Expression::new(Value::Exception(Box::new(cause.clone()))),
+                        None => Expression::nil(),
+                    },
+                    _ => Expression::new(Value::List(
+                        err.backtrace()
+                            .into_iter()
+                            .map(Frame::into_expression)
+                            .collect(),
+                    )),
+                })
+            }
+        }
+    }
+}
+
+// Lists built at runtime are often run as code (especially by macros), so they
+// point back at the call that built them
+fn built_list(
+    vals: Vec<Expression>,
+    expr: &Expression,
+    snapshot: &Locker<CallSnapshot>,
+) -> Result<Expression, Exception> {
+    let list = Expression::new(Value::List(vals));
+    Ok(match CallSnapshot::origin(snapshot, expr)? {
+        Some(origin) => list.with_source(origin),
+        None => list,
+    })
+}
+
+// Evaluates the argument at `index`, which `operator` expects to be a map
+fn eval_map(
+    operator: &Operator,
+    arguments: &dyn Arguments,
+    index: usize,
+    snapshot: &Locker<CallSnapshot>,
+    env: Locker<Environment>,
+) -> Result<Map, Exception> {
+    match &*arguments
+        .eval(index, snapshot.clone(), env)?
+        .value()
+        .read()?
+    {
+        Value::Map(map) => Ok(map.clone()),
+        val => exp!(
+            EV::InvalidArgument,
+            snapshot,
+            format!("`{}` expects a map (got `{}`)", operator, val)
+        ),
+    }
+}
+
+// Evaluates the argument at `index`, which `operator` expects to be an
+// exception
+fn eval_exception(
+    operator: &Operator,
+    arguments: &dyn Arguments,
+    index: usize,
+    snapshot: &Locker<CallSnapshot>,
+    env: Locker<Environment>,
+) -> Result<Exception, Exception> {
+    match &*arguments
+        .eval(index, snapshot.clone(), env)?
+        .value()
+        .read()?
+    {
+        Value::Exception(err) => Ok((**err).clone()),
+        val => exp!(
+            EV::InvalidArgument,
+            snapshot,
+            format!("`{}` expects an exception (got `{}`)", operator, val)
+        ),
+    }
+}
+
+// Evaluates the argument at `index`, which `operator` expects to be a symbol
+fn eval_symbol(
+    operator: &Operator,
+    arguments: &dyn Arguments,
+    index: usize,
+    snapshot: &Locker<CallSnapshot>,
+    env: Locker<Environment>,
+) -> Result<crate::Symbol, Exception> {
+    match &*arguments
+        .eval(index, snapshot.clone(), env)?
+        .value()
+        .read()?
+    {
+        Value::Symbol(symbol) => Ok(*symbol),
+        val => exp!(
+            EV::InvalidArgument,
+            snapshot,
+            format!("`{}` expects a symbol (got `{}`)", operator, val)
+        ),
+    }
+}
+
+// Evaluates the argument at `index`, which `operator` expects to be a lambda
+fn eval_function(
+    operator: &Operator,
+    arguments: &dyn Arguments,
+    index: usize,
+    snapshot: &Locker<CallSnapshot>,
+    env: Locker<Environment>,
+) -> Result<Expression, Exception> {
+    let function = arguments.eval(index, snapshot.clone(), env)?;
+    let is_lambda = matches!(&*function.value().read()?, Value::Lambda(_));
+    exp_assert!(
+        is_lambda,
+        EV::InvalidArgument,
+        snapshot,
+        format!("`{}` expects a lambda (got `{}`)", operator, function)
+    );
+    Ok(function)
+}
+
+// Calls `function` with `arguments` (which are passed as they are, rather
+// than evaluated)
+fn call_with(
+    function: &Expression,
+    arguments: Vec<Expression>,
+    snapshot: Locker<CallSnapshot>,
+    env: Locker<Environment>,
+) -> Result<Expression, Exception> {
+    let mut call = vec![function.clone()];
+    for arg in arguments {
+        call.push(Expression::new(Value::List(vec![
+            Expression::new(Value::Operator(Operator::Quote)),
+            arg,
+        ])));
+    }
+    Expression::new(Value::List(call)).eval(
+        snapshot,
+        Locker::new(Environment::root().with_parent(env, None)),
+    )
+}
+
+// Names the lambda or macro `exp` evaluates to after `symbol` (without the
+// namespace), if it doesn't have a name yet
+pub(crate) fn name_function(exp: Expression, symbol: crate::Symbol) -> Expression {
+    let named = match &*exp.value().read().unwrap() {
+        Value::Lambda(function) if function.name.is_none() => {
+            Value::Lambda(function.clone().named(symbol.identifier()))
+        }
+        Value::Macro(function) if function.name.is_none() => {
+            Value::Macro(function.clone().named(symbol.identifier()))
         }
+        _ => return exp,
+    };
+    match exp.source() {
+        Some(source) => Expression::new(named).with_source(source.clone()),
+        None => Expression::new(named),
     }
 }
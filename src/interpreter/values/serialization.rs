@@ -0,0 +1,881 @@
+use std::convert::TryFrom;
+use std::fmt;
+
+use num_bigint::BigInt;
+use num_traits::ToPrimitive;
+use serde::de::{
+    self, DeserializeOwned, DeserializeSeed, EnumAccess, IntoDeserializer, MapAccess, SeqAccess,
+    VariantAccess, Visitor,
+};
+use serde::ser::{self, Serialize};
+use serde::{forward_to_deserialize_any, Deserialize};
+
+use crate::{Exception, ExceptionValue as EV, Expression, Keyword, Map, Number, Value};
+
+/// Turns `value` into a Turtle value. Structs become association lists keyed
+/// by keywords (like `((:name "turtle") (:legs 4))`, which the `@map` module
+/// accepts as a map), maps become maps, sequences and tuples become lists,
+/// and enums become lists tagged with the variant's keyword (like `(:Point 1
+/// 2)`, or `(:Empty)`). `None`, `()` and `false` are all nil, so an empty
+/// `Some(vec![])` comes back as `None`.
+pub fn to_value<T: Serialize + ?Sized>(value: &T) -> Result<Expression, Exception> {
+    value.serialize(Serializer)
+}
+
+/// Reads a `T` from a Turtle value, as `to_value` would have made it. Structs
+/// can also be read from maps, and their fields (and enum variants) named by
+/// keywords, symbols or text.
+pub fn from_value<T: DeserializeOwned>(value: &Expression) -> Result<T, Exception> {
+    T::deserialize(Deserializer::new(value)?)
+}
+
+impl ser::Error for Exception {
+    fn custom<T: fmt::Display>(msg: T) -> Self {
+        Exception::new(EV::InvalidArgument, None, Some(msg.to_string()))
+    }
+}
+
+impl de::Error for Exception {
+    fn custom<T: fmt::Display>(msg: T) -> Self {
+        Exception::new(EV::InvalidArgument, None, Some(msg.to_string()))
+    }
+}
+
+fn list(values: Vec<Expression>) -> Expression {
+    Expression::new(Value::List(values))
+}
+
+fn keyword(name: &str) -> Expression {
+    Expression::new(Value::Keyword(Keyword::from_str(name)))
+}
+
+fn number(number: Number) -> Result<Expression, Exception> {
+    Ok(Expression::new(Value::Number(number)))
+}
+
+// Values serialize as the closest thing serde has: keywords as their text
+// (with the colon), symbols as their name, and `t` as `true`
+impl Serialize for Value {
+    fn serialize<S: ser::Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        use ser::{Error, SerializeMap, SerializeSeq};
+        match self {
+            Value::List(vals) if vals.is_empty() => serializer.serialize_unit(),
+            Value::List(vals) => {
+                let mut seq = serializer.serialize_seq(Some(vals.len()))?;
+                for val in vals {
+                    seq.serialize_element(val)?;
+                }
+                seq.end()
+            }
+            Value::Number(Number::Integer(val)) => serializer.serialize_i64(*val),
+            Value::Number(Number::BigInteger(val)) => match (val.to_i128(), val.to_u128()) {
+                (Some(val), _) => serializer.serialize_i128(val),
+                (_, Some(val)) => serializer.serialize_u128(val),
+                _ => Err(S::Error::custom(format!(
+                    "`{}` is too large to serialize",
+                    val
+                ))),
+            },
+            Value::Number(val) => serializer.serialize_f64(val.to_f64()),
+            Value::Text(val) => serializer.serialize_str(val),
+            Value::Keyword(val) => serializer.collect_str(val),
+            Value::Symbol(val) => serializer.serialize_str(val.string_value()),
+            Value::Byte(val) => serializer.serialize_u8(*val),
+            Value::True => serializer.serialize_bool(true),
+            Value::Map(map) => {
+                let mut ser = serializer.serialize_map(Some(map.len()))?;
+                for (key, val) in map.iter() {
+                    ser.serialize_entry(key, val)?;
+                }
+                ser.end()
+            }
+            val => Err(S::Error::custom(format!(
+                "values of type {} can't be serialized",
+                val.as_type()
+            ))),
+        }
+    }
+}
+
+impl Serialize for Expression {
+    fn serialize<S: ser::Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        use ser::Error;
+        match self.value().read() {
+            Ok(val) => val.serialize(serializer),
+            Err(err) => Err(S::Error::custom(err)),
+        }
+    }
+}
+
+impl<'de> Deserialize<'de> for Value {
+    fn deserialize<D: de::Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        deserializer.deserialize_any(ValueVisitor)
+    }
+}
+
+impl<'de> Deserialize<'de> for Expression {
+    fn deserialize<D: de::Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        Value::deserialize(deserializer).map(Expression::new)
+    }
+}
+
+struct ValueVisitor;
+
+impl<'de> Visitor<'de> for ValueVisitor {
+    type Value = Value;
+
+    fn expecting(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "a value Turtle can represent")
+    }
+
+    fn visit_bool<E>(self, val: bool) -> Result<Value, E> {
+        Ok(match val {
+            true => Value::True,
+            false => Value::List(vec![]),
+        })
+    }
+
+    fn visit_i64<E>(self, val: i64) -> Result<Value, E> {
+        Ok(Value::Number(Number::Integer(val)))
+    }
+
+    fn visit_i128<E>(self, val: i128) -> Result<Value, E> {
+        Ok(Value::Number(Number::from_bigint(BigInt::from(val))))
+    }
+
+    fn visit_u8<E>(self, val: u8) -> Result<Value, E> {
+        Ok(Value::Byte(val))
+    }
+
+    fn visit_u64<E>(self, val: u64) -> Result<Value, E> {
+        Ok(Value::Number(Number::from_bigint(BigInt::from(val))))
+    }
+
+    fn visit_u128<E>(self, val: u128) -> Result<Value, E> {
+        Ok(Value::Number(Number::from_bigint(BigInt::from(val))))
+    }
+
+    fn visit_f64<E>(self, val: f64) -> Result<Value, E> {
+        Ok(Value::Number(Number::Float(val)))
+    }
+
+    fn visit_str<E>(self, val: &str) -> Result<Value, E> {
+        Ok(Value::Text(val.to_string()))
+    }
+
+    fn visit_bytes<E>(self, val: &[u8]) -> Result<Value, E> {
+        Ok(Value::List(
+            val.iter()
+                .map(|byte| Expression::new(Value::Byte(*byte)))
+                .collect(),
+        ))
+    }
+
+    fn visit_none<E>(self) -> Result<Value, E> {
+        Ok(Value::List(vec![]))
+    }
+
+    fn visit_some<D: de::Deserializer<'de>>(self, deserializer: D) -> Result<Value, D::Error> {
+        Value::deserialize(deserializer)
+    }
+
+    fn visit_unit<E>(self) -> Result<Value, E> {
+        Ok(Value::List(vec![]))
+    }
+
+    fn visit_seq<A: SeqAccess<'de>>(self, mut seq: A) -> Result<Value, A::Error> {
+        let mut vals = vec![];
+        while let Some(val) = seq.next_element()? {
+            vals.push(val);
+        }
+        Ok(Value::List(vals))
+    }
+
+    fn visit_map<A: MapAccess<'de>>(self, mut access: A) -> Result<Value, A::Error> {
+        let mut map = Map::new();
+        while let Some((key, val)) = access.next_entry::<Value, Expression>()? {
+            map = map.insert(key, val);
+        }
+        Ok(Value::Map(map))
+    }
+}
+
+/// Serializes Rust values into Turtle values (see `to_value`).
+struct Serializer;
+
+impl ser::Serializer for Serializer {
+    type Ok = Expression;
+    type Error = Exception;
+
+    type SerializeSeq = SerializeList;
+    type SerializeTuple = SerializeList;
+    type SerializeTupleStruct = SerializeList;
+    type SerializeTupleVariant = SerializeList;
+    type SerializeMap = SerializeMap;
+    type SerializeStruct = SerializeList;
+    type SerializeStructVariant = SerializeList;
+
+    fn serialize_bool(self, val: bool) -> Result<Expression, Exception> {
+        Ok(match val {
+            true => Expression::t(),
+            false => Expression::nil(),
+        })
+    }
+
+    fn serialize_i8(self, val: i8) -> Result<Expression, Exception> {
+        self.serialize_i64(i64::from(val))
+    }
+
+    fn serialize_i16(self, val: i16) -> Result<Expression, Exception> {
+        self.serialize_i64(i64::from(val))
+    }
+
+    fn serialize_i32(self, val: i32) -> Result<Expression, Exception> {
+        self.serialize_i64(i64::from(val))
+    }
+
+    fn serialize_i64(self, val: i64) -> Result<Expression, Exception> {
+        number(Number::Integer(val))
+    }
+
+    fn serialize_i128(self, val: i128) -> Result<Expression, Exception> {
+        number(Number::from_bigint(BigInt::from(val)))
+    }
+
+    // `u8`s are bytes, like they are for `IntoTurtle`
+    fn serialize_u8(self, val: u8) -> Result<Expression, Exception> {
+        Ok(Expression::new(Value::Byte(val)))
+    }
+
+    fn serialize_u16(self, val: u16) -> Result<Expression, Exception> {
+        self.serialize_i64(i64::from(val))
+    }
+
+    fn serialize_u32(self, val: u32) -> Result<Expression, Exception> {
+        self.serialize_i64(i64::from(val))
+    }
+
+    fn serialize_u64(self, val: u64) -> Result<Expression, Exception> {
+        number(Number::from_bigint(BigInt::from(val)))
+    }
+
+    fn serialize_u128(self, val: u128) -> Result<Expression, Exception> {
+        number(Number::from_bigint(BigInt::from(val)))
+    }
+
+    fn serialize_f32(self, val: f32) -> Result<Expression, Exception> {
+        self.serialize_f64(f64::from(val))
+    }
+
+    fn serialize_f64(self, val: f64) -> Result<Expression, Exception> {
+        number(Number::Float(val))
+    }
+
+    fn serialize_char(self, val: char) -> Result<Expression, Exception> {
+        self.serialize_str(&val.to_string())
+    }
+
+    fn serialize_str(self, val: &str) -> Result<Expression, Exception> {
+        Ok(Expression::new(Value::Text(val.to_string())))
+    }
+
+    fn serialize_bytes(self, val: &[u8]) -> Result<Expression, Exception> {
+        Ok(list(
+            val.iter()
+                .map(|byte| Expression::new(Value::Byte(*byte)))
+                .collect(),
+        ))
+    }
+
+    fn serialize_none(self) -> Result<Expression, Exception> {
+        Ok(Expression::nil())
+    }
+
+    fn serialize_some<T: Serialize + ?Sized>(self, val: &T) -> Result<Expression, Exception> {
+        val.serialize(self)
+    }
+
+    fn serialize_unit(self) -> Result<Expression, Exception> {
+        Ok(Expression::nil())
+    }
+
+    fn serialize_unit_struct(self, _name: &'static str) -> Result<Expression, Exception> {
+        Ok(Expression::nil())
+    }
+
+    fn serialize_unit_variant(
+        self,
+        _name: &'static str,
+        _index: u32,
+        variant: &'static str,
+    ) -> Result<Expression, Exception> {
+        Ok(list(vec![keyword(variant)]))
+    }
+
+    fn serialize_newtype_struct<T: Serialize + ?Sized>(
+        self,
+        _name: &'static str,
+        val: &T,
+    ) -> Result<Expression, Exception> {
+        val.serialize(self)
+    }
+
+    fn serialize_newtype_variant<T: Serialize + ?Sized>(
+        self,
+        _name: &'static str,
+        _index: u32,
+        variant: &'static str,
+        val: &T,
+    ) -> Result<Expression, Exception> {
+        Ok(list(vec![keyword(variant), val.serialize(self)?]))
+    }
+
+    fn serialize_seq(self, len: Option<usize>) -> Result<SerializeList, Exception> {
+        Ok(SerializeList(Vec::with_capacity(len.unwrap_or(0))))
+    }
+
+    fn serialize_tuple(self, len: usize) -> Result<SerializeList, Exception> {
+        self.serialize_seq(Some(len))
+    }
+
+    fn serialize_tuple_struct(
+        self,
+        _name: &'static str,
+        len: usize,
+    ) -> Result<SerializeList, Exception> {
+        self.serialize_seq(Some(len))
+    }
+
+    fn serialize_tuple_variant(
+        self,
+        _name: &'static str,
+        _index: u32,
+        variant: &'static str,
+        _len: usize,
+    ) -> Result<SerializeList, Exception> {
+        Ok(SerializeList(vec![keyword(variant)]))
+    }
+
+    fn serialize_map(self, _len: Option<usize>) -> Result<SerializeMap, Exception> {
+        Ok(SerializeMap {
+            map: Map::new(),
+            key: None,
+        })
+    }
+
+    fn serialize_struct(self, _name: &'static str, len: usize) -> Result<SerializeList, Exception> {
+        self.serialize_seq(Some(len))
+    }
+
+    fn serialize_struct_variant(
+        self,
+        _name: &'static str,
+        _index: u32,
+        variant: &'static str,
+        _len: usize,
+    ) -> Result<SerializeList, Exception> {
+        Ok(SerializeList(vec![keyword(variant)]))
+    }
+}
+
+/// Builds a list: the elements of a sequence (or tuple), the `(:field value)`
+/// pairs of a struct, or either after the tag of an enum variant.
+struct SerializeList(Vec<Expression>);
+
+impl SerializeList {
+    fn push<T: Serialize + ?Sized>(&mut self, val: &T) -> Result<(), Exception> {
+        self.0.push(val.serialize(Serializer)?);
+        Ok(())
+    }
+
+    fn push_field<T: Serialize + ?Sized>(
+        &mut self,
+        field: &'static str,
+        val: &T,
+    ) -> Result<(), Exception> {
+        self.0
+            .push(list(vec![keyword(field), val.serialize(Serializer)?]));
+        Ok(())
+    }
+}
+
+impl ser::SerializeSeq for SerializeList {
+    type Ok = Expression;
+    type Error = Exception;
+
+    fn serialize_element<T: Serialize + ?Sized>(&mut self, val: &T) -> Result<(), Exception> {
+        self.push(val)
+    }
+
+    fn end(self) -> Result<Expression, Exception> {
+        Ok(list(self.0))
+    }
+}
+
+impl ser::SerializeTuple for SerializeList {
+    type Ok = Expression;
+    type Error = Exception;
+
+    fn serialize_element<T: Serialize + ?Sized>(&mut self, val: &T) -> Result<(), Exception> {
+        self.push(val)
+    }
+
+    fn end(self) -> Result<Expression, Exception> {
+        Ok(list(self.0))
+    }
+}
+
+impl ser::SerializeTupleStruct for SerializeList {
+    type Ok = Expression;
+    type Error = Exception;
+
+    fn serialize_field<T: Serialize + ?Sized>(&mut self, val: &T) -> Result<(), Exception> {
+        self.push(val)
+    }
+
+    fn end(self) -> Result<Expression, Exception> {
+        Ok(list(self.0))
+    }
+}
+
+impl ser::SerializeTupleVariant for SerializeList {
+    type Ok = Expression;
+    type Error = Exception;
+
+    fn serialize_field<T: Serialize + ?Sized>(&mut self, val: &T) -> Result<(), Exception> {
+        self.push(val)
+    }
+
+    fn end(self) -> Result<Expression, Exception> {
+        Ok(list(self.0))
+    }
+}
+
+impl ser::SerializeStruct for SerializeList {
+    type Ok = Expression;
+    type Error = Exception;
+
+    fn serialize_field<T: Serialize + ?Sized>(
+        &mut self,
+        field: &'static str,
+        val: &T,
+    ) -> Result<(), Exception> {
+        self.push_field(field, val)
+    }
+
+    fn end(self) -> Result<Expression, Exception> {
+        Ok(list(self.0))
+    }
+}
+
+impl ser::SerializeStructVariant for SerializeList {
+    type Ok = Expression;
+    type Error = Exception;
+
+    fn serialize_field<T: Serialize + ?Sized>(
+        &mut self,
+        field: &'static str,
+        val: &T,
+    ) -> Result<(), Exception> {
+        self.push_field(field, val)
+    }
+
+    fn end(self) -> Result<Expression, Exception> {
+        Ok(list(self.0))
+    }
+}
+
+/// Builds a map, one entry at a time.
+struct SerializeMap {
+    map: Map,
+    // The key of the entry whose value comes next
+    key: Option<Value>,
+}
+
+impl ser::SerializeMap for SerializeMap {
+    type Ok = Expression;
+    type Error = Exception;
+
+    fn serialize_key<T: Serialize + ?Sized>(&mut self, key: &T) -> Result<(), Exception> {
+        let key = key.serialize(Serializer)?.value().read()?.clone();
+        self.key = Some(key);
+        Ok(())
+    }
+
+    fn serialize_value<T: Serialize + ?Sized>(&mut self, val: &T) -> Result<(), Exception> {
+        let key = self
+            .key
+            .take()
+            .ok_or_else(|| <Exception as ser::Error>::custom("map value without a key"))?;
+        self.map = self.map.insert(key, val.serialize(Serializer)?);
+        Ok(())
+    }
+
+    fn end(self) -> Result<Expression, Exception> {
+        Ok(Expression::new(Value::Map(self.map)))
+    }
+}
+
+/// Deserializes Rust values from Turtle values (see `from_value`).
+struct Deserializer {
+    value: Value,
+}
+
+impl Deserializer {
+    fn new(exp: &Expression) -> Result<Self, Exception> {
+        Ok(Self {
+            value: exp.value().read()?.clone(),
+        })
+    }
+
+    fn is_nil(&self) -> bool {
+        matches!(&self.value, Value::List(vals) if vals.is_empty())
+    }
+
+    fn mismatch(&self, expected: &str) -> Exception {
+        Exception::new(
+            EV::InvalidArgument,
+            None,
+            Some(format!(
+                "expected {} (got `{:#}`, which is of type {})",
+                expected,
+                self.value,
+                self.value.as_type()
+            )),
+        )
+    }
+
+    // The name of a struct field or enum variant
+    fn name(&self) -> Option<String> {
+        match &self.value {
+            Value::Keyword(val) => Some(val.string_value().clone()),
+            Value::Symbol(val) => Some(val.string_value().clone()),
+            Value::Text(val) => Some(val.clone()),
+            _ => None,
+        }
+    }
+
+    // The entries of a map, or of an association list of `(key value)` pairs
+    fn entries(&self) -> Option<Vec<(Expression, Expression)>> {
+        match &self.value {
+            Value::Map(map) => Some(
+                map.iter()
+                    .map(|(key, val)| (Expression::new(key.clone()), val.clone()))
+                    .collect(),
+            ),
+            Value::List(pairs) => pairs.iter().map(pair).collect(),
+            _ => None,
+        }
+    }
+}
+
+// The key and value of a `(key value)` pair
+fn pair(exp: &Expression) -> Option<(Expression, Expression)> {
+    match &*exp.value().read().ok()? {
+        Value::List(pair) if pair.len() == 2 => Some((pair[0].clone(), pair[1].clone())),
+        _ => None,
+    }
+}
+
+impl<'de> de::Deserializer<'de> for Deserializer {
+    type Error = Exception;
+
+    fn deserialize_any<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value, Exception> {
+        match &self.value {
+            Value::List(vals) if vals.is_empty() => visitor.visit_unit(),
+            Value::List(vals) => visitor.visit_seq(Elements::new(vals.clone())),
+            Value::Number(Number::Integer(val)) => visitor.visit_i64(*val),
+            Value::Number(Number::BigInteger(val)) => match (val.to_i128(), val.to_u128()) {
+                (Some(val), _) => visitor.visit_i128(val),
+                (_, Some(val)) => visitor.visit_u128(val),
+                _ => Err(self.mismatch("a number that fits in 128 bits")),
+            },
+            Value::Number(val) => visitor.visit_f64(val.to_f64()),
+            Value::Text(val) => visitor.visit_str(val),
+            Value::Keyword(_) | Value::Symbol(_) => visitor.visit_string(self.name().unwrap()),
+            Value::Byte(val) => visitor.visit_u8(*val),
+            Value::True => visitor.visit_bool(true),
+            Value::Map(_) => visitor.visit_map(Entries::new(self.entries().unwrap())),
+            _ => Err(self.mismatch("a value that can be deserialized")),
+        }
+    }
+
+    // Like `cond`, anything but nil is true
+    fn deserialize_bool<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value, Exception> {
+        visitor.visit_bool(!self.is_nil())
+    }
+
+    fn deserialize_u8<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value, Exception> {
+        match &self.value {
+            Value::Byte(val) => visitor.visit_u8(*val),
+            Value::Number(Number::Integer(val)) => match u8::try_from(*val) {
+                Ok(val) => visitor.visit_u8(val),
+                Err(_) => Err(self.mismatch("a byte")),
+            },
+            _ => Err(self.mismatch("a byte")),
+        }
+    }
+
+    fn deserialize_bytes<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value, Exception> {
+        self.deserialize_byte_buf(visitor)
+    }
+
+    fn deserialize_byte_buf<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value, Exception> {
+        let bytes: Option<Vec<u8>> = match &self.value {
+            Value::List(vals) => vals
+                .iter()
+                .map(|val| match &*val.value().read().ok()? {
+                    Value::Byte(byte) => Some(*byte),
+                    _ => None,
+                })
+                .collect(),
+            _ => None,
+        };
+        match bytes {
+            Some(bytes) => visitor.visit_byte_buf(bytes),
+            None => Err(self.mismatch("a list of bytes")),
+        }
+    }
+
+    fn deserialize_option<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value, Exception> {
+        match self.is_nil() {
+            true => visitor.visit_none(),
+            false => visitor.visit_some(self),
+        }
+    }
+
+    fn deserialize_unit<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value, Exception> {
+        match self.is_nil() {
+            true => visitor.visit_unit(),
+            false => Err(self.mismatch("nil")),
+        }
+    }
+
+    fn deserialize_unit_struct<V: Visitor<'de>>(
+        self,
+        _name: &'static str,
+        visitor: V,
+    ) -> Result<V::Value, Exception> {
+        self.deserialize_unit(visitor)
+    }
+
+    fn deserialize_newtype_struct<V: Visitor<'de>>(
+        self,
+        _name: &'static str,
+        visitor: V,
+    ) -> Result<V::Value, Exception> {
+        visitor.visit_newtype_struct(self)
+    }
+
+    fn deserialize_seq<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value, Exception> {
+        match &self.value {
+            Value::List(vals) => visitor.visit_seq(Elements::new(vals.clone())),
+            _ => Err(self.mismatch("a list")),
+        }
+    }
+
+    fn deserialize_tuple<V: Visitor<'de>>(
+        self,
+        _len: usize,
+        visitor: V,
+    ) -> Result<V::Value, Exception> {
+        self.deserialize_seq(visitor)
+    }
+
+    fn deserialize_tuple_struct<V: Visitor<'de>>(
+        self,
+        _name: &'static str,
+        _len: usize,
+        visitor: V,
+    ) -> Result<V::Value, Exception> {
+        self.deserialize_seq(visitor)
+    }
+
+    fn deserialize_map<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value, Exception> {
+        match self.entries() {
+            Some(entries) => visitor.visit_map(Entries::new(entries)),
+            None => Err(self.mismatch("a map or a list of `(key value)` pairs")),
+        }
+    }
+
+    fn deserialize_struct<V: Visitor<'de>>(
+        self,
+        _name: &'static str,
+        _fields: &'static [&'static str],
+        visitor: V,
+    ) -> Result<V::Value, Exception> {
+        self.deserialize_map(visitor)
+    }
+
+    // Variants are tagged lists, though unit variants can also be just their
+    // tag
+    fn deserialize_enum<V: Visitor<'de>>(
+        self,
+        _name: &'static str,
+        _variants: &'static [&'static str],
+        visitor: V,
+    ) -> Result<V::Value, Exception> {
+        if let Some(name) = self.name() {
+            return visitor.visit_enum(Variant {
+                name,
+                contents: vec![],
+            });
+        }
+        let tagged = match &self.value {
+            Value::List(vals) => vals.split_first().and_then(|(tag, contents)| {
+                let name = Deserializer::new(tag).ok()?.name()?;
+                Some((name, contents.to_vec()))
+            }),
+            _ => None,
+        };
+        match tagged {
+            Some((name, contents)) => visitor.visit_enum(Variant { name, contents }),
+            None => Err(self.mismatch("a list tagged with a keyword")),
+        }
+    }
+
+    fn deserialize_identifier<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value, Exception> {
+        match self.name() {
+            Some(name) => visitor.visit_string(name),
+            None => Err(self.mismatch("a keyword")),
+        }
+    }
+
+    fn deserialize_ignored_any<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value, Exception> {
+        visitor.visit_unit()
+    }
+
+    forward_to_deserialize_any! {
+        i8 i16 i32 i64 i128 u16 u32 u64 u128 f32 f64 char str string
+    }
+}
+
+/// The elements of a list, deserialized one at a time.
+struct Elements(std::vec::IntoIter<Expression>);
+
+impl Elements {
+    fn new(vals: Vec<Expression>) -> Self {
+        Self(vals.into_iter())
+    }
+}
+
+impl<'de> SeqAccess<'de> for Elements {
+    type Error = Exception;
+
+    fn next_element_seed<T: DeserializeSeed<'de>>(
+        &mut self,
+        seed: T,
+    ) -> Result<Option<T::Value>, Exception> {
+        match self.0.next() {
+            Some(val) => seed.deserialize(Deserializer::new(&val)?).map(Some),
+            None => Ok(None),
+        }
+    }
+
+    fn size_hint(&self) -> Option<usize> {
+        Some(self.0.len())
+    }
+}
+
+/// The entries of a map, deserialized one at a time.
+struct Entries {
+    entries: std::vec::IntoIter<(Expression, Expression)>,
+    // The value of the entry whose key was just deserialized
+    value: Option<Expression>,
+}
+
+impl Entries {
+    fn new(entries: Vec<(Expression, Expression)>) -> Self {
+        Self {
+            entries: entries.into_iter(),
+            value: None,
+        }
+    }
+}
+
+impl<'de> MapAccess<'de> for Entries {
+    type Error = Exception;
+
+    fn next_key_seed<K: DeserializeSeed<'de>>(
+        &mut self,
+        seed: K,
+    ) -> Result<Option<K::Value>, Exception> {
+        match self.entries.next() {
+            Some((key, val)) => {
+                self.value = Some(val);
+                seed.deserialize(Deserializer::new(&key)?).map(Some)
+            }
+            None => Ok(None),
+        }
+    }
+
+    fn next_value_seed<V: DeserializeSeed<'de>>(&mut self, seed: V) -> Result<V::Value, Exception> {
+        match self.value.take() {
+            Some(val) => seed.deserialize(Deserializer::new(&val)?),
+            None => Err(de::Error::custom("map value without a key")),
+        }
+    }
+
+    fn size_hint(&self) -> Option<usize> {
+        Some(self.entries.len())
+    }
+}
+
+/// An enum variant: its name, and whatever came after its tag.
+struct Variant {
+    name: String,
+    contents: Vec<Expression>,
+}
+
+impl<'de> EnumAccess<'de> for Variant {
+    type Error = Exception;
+    type Variant = Self;
+
+    fn variant_seed<V: DeserializeSeed<'de>>(self, seed: V) -> Result<(V::Value, Self), Exception> {
+        let name: de::value::StringDeserializer<Exception> = self.name.clone().into_deserializer();
+        Ok((seed.deserialize(name)?, self))
+    }
+}
+
+impl<'de> VariantAccess<'de> for Variant {
+    type Error = Exception;
+
+    fn unit_variant(self) -> Result<(), Exception> {
+        match self.contents.is_empty() {
+            true => Ok(()),
+            false => Err(de::Error::custom(format!(
+                "`{}` doesn't take any values",
+                self.name
+            ))),
+        }
+    }
+
+    fn newtype_variant_seed<T: DeserializeSeed<'de>>(self, seed: T) -> Result<T::Value, Exception> {
+        match self.contents.as_slice() {
+            [val] => seed.deserialize(Deserializer::new(val)?),
+            _ => Err(de::Error::custom(format!(
+                "`{}` takes exactly one value",
+                self.name
+            ))),
+        }
+    }
+
+    fn tuple_variant<V: Visitor<'de>>(
+        self,
+        _len: usize,
+        visitor: V,
+    ) -> Result<V::Value, Exception> {
+        visitor.visit_seq(Elements::new(self.contents))
+    }
+
+    fn struct_variant<V: Visitor<'de>>(
+        self,
+        _fields: &'static [&'static str],
+        visitor: V,
+    ) -> Result<V::Value, Exception> {
+        let fields = Deserializer {
+            value: Value::List(self.contents),
+        };
+        de::Deserializer::deserialize_map(fields, visitor)
+    }
+}
@@ -1,19 +1,227 @@
+use std::cmp::Ordering;
+use std::collections::HashMap;
 use std::fmt;
+use std::hash::{BuildHasherDefault, Hash, Hasher};
+use std::sync::{Mutex, OnceLock};
 
-#[derive(Debug, Clone, PartialEq, PartialOrd, Eq, Hash)]
-pub struct Symbol(String);
+use crate::{Operator, Value};
+
+/// An interned symbol. Every distinct name is stored once in a global symbol
+/// table, so symbols are cheap to copy, compare and hash (by identity), and
+/// the namespace and identifier parts of a name like `ns::ident` (as well as
+/// any builtin the name refers to) are worked out once, when it is interned.
+///
+/// Interned names are never freed: the table lives for as long as the
+/// process, and grows with every distinct name any interpreter in it has
+/// parsed. Hosts that run untrusted code for a long time (which can parse
+/// arbitrary text into symbols) should expect memory use to grow accordingly,
+/// and run it in a process that can be restarted.
+#[derive(Clone, Copy)]
+pub struct Symbol(&'static Interned);
+
+struct Interned {
+    id: usize,
+    name: String,
+    // The namespace and identifier of names that contain `::`
+    components: Option<(Symbol, Symbol)>,
+    literal: Option<Value>,
+}
+
+fn table() -> &'static Mutex<HashMap<&'static str, Symbol>> {
+    static TABLE: OnceLock<Mutex<HashMap<&'static str, Symbol>>> = OnceLock::new();
+    TABLE.get_or_init(|| Mutex::new(HashMap::new()))
+}
 
 impl Symbol {
     pub fn new(val: String) -> Self {
-        Self(val)
+        if let Some(symbol) = table().lock().unwrap().get(val.as_str()) {
+            return *symbol;
+        }
+
+        // Interning the components first means the table lock is never held
+        // while interning another name
+        let components = val.split_once("::").map(|(namespace, identifier)| {
+            (Symbol::from_str(namespace), Symbol::from_str(identifier))
+        });
+        let literal = Self::get_literal(&val);
+
+        let mut table = table().lock().unwrap();
+        // Another thread may have interned the same name in the meantime
+        if let Some(symbol) = table.get(val.as_str()) {
+            return *symbol;
+        }
+        let interned: &'static Interned = Box::leak(Box::new(Interned {
+            id: table.len(),
+            name: val,
+            components,
+            literal,
+        }));
+        let symbol = Self(interned);
+        table.insert(interned.name.as_str(), symbol);
+        symbol
     }
 
+    // Interning can't fail, which `FromStr` would make callers handle
+    #[allow(clippy::should_implement_trait)]
     pub fn from_str(val: &str) -> Self {
-        Self(String::from(val))
+        Self::new(String::from(val))
+    }
+
+    pub fn string_value(&self) -> &'static String {
+        &self.0.name
+    }
+
+    /// The unique number this symbol was interned as.
+    pub fn id(&self) -> usize {
+        self.0.id
     }
 
-    pub fn string_value(&self) -> &'_ String {
-        &self.0
+    /// The namespace part of a symbol like `ns::ident`, if it has one.
+    pub fn namespace(&self) -> Option<Symbol> {
+        self.0.components.map(|(namespace, _)| namespace)
+    }
+
+    /// The symbol without its namespace (the symbol itself if it has none).
+    pub fn identifier(&self) -> Symbol {
+        match self.0.components {
+            Some((_, identifier)) => identifier,
+            None => *self,
+        }
+    }
+
+    /// The builtin value this symbol names when nothing shadows it.
+    pub fn literal(&self) -> Option<Value> {
+        self.0.literal.clone()
+    }
+
+    fn get_literal(name: &str) -> Option<Value> {
+        match name {
+            "nil" => Some(Value::List(vec![])),
+            "t" | "true" => Some(Value::True),
+            _ => OPERATORS
+                .iter()
+                .find(|(operator, _)| *operator == name)
+                .map(|(_, operator)| Value::Operator(operator.clone())),
+        }
+    }
+
+    /// The names that refer to builtins unless something shadows them.
+    pub fn builtin_names() -> impl Iterator<Item = &'static str> {
+        ["nil", "t", "true"]
+            .iter()
+            .copied()
+            .chain(OPERATORS.iter().map(|(name, _)| *name))
+    }
+}
+
+// The builtin operators, by the names they're available under
+const OPERATORS: &[(&str, Operator)] = &[
+    ("quote", Operator::Quote),
+    ("atom", Operator::Atom),
+    ("eq", Operator::Eq),
+    ("car", Operator::Car),
+    ("cdr", Operator::Cdr),
+    ("cons", Operator::Cons),
+    ("cond", Operator::Cond),
+    ("if", Operator::If),
+    ("export", Operator::Export),
+    ("let", Operator::Let),
+    ("sum", Operator::Sum),
+    ("prod", Operator::Prod),
+    ("exp", Operator::Exp),
+    ("modulo", Operator::Modulo),
+    ("gt", Operator::Gt),
+    ("ge", Operator::Ge),
+    ("type", Operator::Type),
+    ("disp", Operator::Disp),
+    ("import", Operator::Import),
+    ("reload", Operator::Reload),
+    ("eval", Operator::Eval),
+    ("while", Operator::While),
+    ("macro", Operator::Macro),
+    ("lambda", Operator::Lambda),
+    ("list", Operator::List),
+    ("catch", Operator::Catch),
+    ("finally", Operator::Finally),
+    ("throw", Operator::Throw),
+    ("handler-bind", Operator::HandlerBind),
+    ("restart-case", Operator::RestartCase),
+    ("invoke-restart", Operator::InvokeRestart),
+    ("restarts", Operator::Restarts),
+    ("signal", Operator::Signal),
+    ("format", Operator::Format),
+    ("parse", Operator::Parse),
+    ("length", Operator::Length),
+    ("append", Operator::Append),
+    ("do", Operator::Do),
+    ("floor", Operator::Floor),
+    ("rand", Operator::Rand),
+    ("equiv", Operator::Equiv),
+    ("nth", Operator::Nth),
+    ("heap", Operator::Heap),
+    ("hashmap", Operator::Hashmap),
+    ("get", Operator::Get),
+    ("has", Operator::Has),
+    ("put", Operator::Put),
+    ("del", Operator::Del),
+    ("keys", Operator::Keys),
+    ("values", Operator::Values),
+    ("entries", Operator::Entries),
+    ("merge", Operator::Merge),
+    ("exception-kind", Operator::ExceptionKind),
+    ("exception-message", Operator::ExceptionMessage),
+    ("exception-note", Operator::ExceptionNote),
+    ("exception-payload", Operator::ExceptionPayload),
+    ("exception-backtrace", Operator::ExceptionBacktrace),
+    ("exception-cause", Operator::ExceptionCause),
+];
+
+impl PartialEq for Symbol {
+    fn eq(&self, other: &Self) -> bool {
+        std::ptr::eq(self.0, other.0)
+    }
+}
+
+impl Eq for Symbol {}
+
+impl Hash for Symbol {
+    fn hash<H: Hasher>(&self, state: &mut H) {
+        self.0.id.hash(state)
+    }
+}
+
+/// A map keyed by symbols, hashed by their interned id rather than by name.
+pub type SymbolMap<V> = HashMap<Symbol, V, BuildHasherDefault<SymbolHasher>>;
+
+/// Hashes a symbol's id as-is; ids are already unique and small.
+#[derive(Default)]
+pub struct SymbolHasher(u64);
+
+impl Hasher for SymbolHasher {
+    fn finish(&self) -> u64 {
+        self.0
+    }
+
+    fn write(&mut self, bytes: &[u8]) {
+        for byte in bytes {
+            self.0 = (self.0 << 8) | u64::from(*byte);
+        }
+    }
+
+    fn write_usize(&mut self, value: usize) {
+        self.0 = value as u64;
+    }
+}
+
+impl PartialOrd for Symbol {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        self.string_value().partial_cmp(other.string_value())
+    }
+}
+
+impl fmt::Debug for Symbol {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_tuple("Symbol").field(self.string_value()).finish()
     }
 }
 
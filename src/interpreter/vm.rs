@@ -0,0 +1,594 @@
+use std::sync::Arc;
+
+use crate::interpreter::conditions;
+use crate::interpreter::values::operator::name_function;
+use crate::Locker;
+
+use crate::{
+    exp, Arguments, CallSnapshot, Environment, Exception, ExceptionValue as EV, Expression,
+    Function, Instruction, Number, Operator, Program, Step, Symbol, Value,
+};
+
+/// Runs every top-level expression of `program` in order, returning the value
+/// of the last one.
+pub fn run(
+    program: &Arc<Program>,
+    parent_snapshot: Locker<CallSnapshot>,
+    env: Locker<Environment>,
+) -> Result<Expression, Exception> {
+    let mut result = Expression::nil();
+    for entry in program.entries() {
+        result = eval(program, *entry, &parent_snapshot, env.clone())?;
+    }
+    Ok(result)
+}
+
+// What to do after running a call: either it produced a value, or some code
+// in tail position remains to be run in its place.
+enum Next {
+    Done(Expression),
+    Tail(Arc<Program>, usize, Locker<Environment>),
+}
+
+fn eval(
+    program: &Arc<Program>,
+    instruction: usize,
+    parent_snapshot: &Locker<CallSnapshot>,
+    env: Locker<Environment>,
+) -> Result<Expression, Exception> {
+    let site = match call_site(program, instruction) {
+        Some(site) => site,
+        None => return eval_leaf(program, instruction, parent_snapshot, env),
+    };
+
+    if let Some(result) = direct(program, instruction, parent_snapshot, &env)? {
+        return Ok(result);
+    }
+    let mut snapshot = CallSnapshot::new(site, parent_snapshot)?;
+    let mut program = program.clone();
+    let mut instruction = instruction;
+    let mut env = env;
+
+    // As in `Expression::eval`, code in tail position is run by this loop
+    // rather than recursively.
+    loop {
+        let next = match call(&program, instruction, &snapshot, env) {
+            Ok(next) => next,
+            Err(mut err) => {
+                conditions::signal(&mut err, &snapshot, true)?;
+                return Err(err);
+            }
+        };
+        match next {
+            Next::Done(result) => return Ok(result),
+            Next::Tail(next_program, next_instruction, next_env) => {
+                let site = match call_site(&next_program, next_instruction) {
+                    Some(site) => site,
+                    None => return eval_leaf(&next_program, next_instruction, &snapshot, next_env),
+                };
+                if let Some(result) = direct(&next_program, next_instruction, &snapshot, &next_env)?
+                {
+                    return Ok(result);
+                }
+                snapshot = CallSnapshot::tail(site, &snapshot)?;
+                program = next_program;
+                instruction = next_instruction;
+                env = next_env;
+            }
+        }
+    }
+}
+
+// The expression of the call `instruction` makes, if it makes one
+fn call_site(program: &Program, instruction: usize) -> Option<&Expression> {
+    match &program.instructions()[instruction] {
+        Instruction::Call { site, .. } => Some(&program.constants()[*site]),
+        Instruction::Cond { call, .. }
+        | Instruction::Sum(call)
+        | Instruction::Gt(call)
+        | Instruction::Let(call)
+        | Instruction::While(call)
+        | Instruction::If(call) => call_site(program, *call),
+        _ => None,
+    }
+}
+
+fn eval_leaf(
+    program: &Arc<Program>,
+    instruction: usize,
+    parent_snapshot: &Locker<CallSnapshot>,
+    env: Locker<Environment>,
+) -> Result<Expression, Exception> {
+    if let Some(exp) = read(program, instruction, &env)? {
+        return Ok(exp);
+    }
+    match &program.instructions()[instruction] {
+        Instruction::Lookup(symbol)
+        | Instruction::Local { symbol, .. }
+        | Instruction::Captured { symbol, .. }
+        | Instruction::Global { symbol, .. } => lookup(program, *symbol, parent_snapshot, env),
+        Instruction::Interpret(constant) => {
+            program.constants()[*constant].eval(parent_snapshot.clone(), env)
+        }
+        _ => eval(program, instruction, parent_snapshot, env),
+    }
+}
+
+// The value a constant or variable produces, if it can be read without a
+// full lookup
+fn read(
+    program: &Program,
+    instruction: usize,
+    env: &Locker<Environment>,
+) -> Result<Option<Expression>, Exception> {
+    let binding = match &program.instructions()[instruction] {
+        Instruction::Constant(constant) => return Ok(Some(program.constants()[*constant].clone())),
+        _ => binding(program, instruction, env)?,
+    };
+    match binding {
+        Some(binding) => Ok(Some(binding.read()?.clone())),
+        None => Ok(None),
+    }
+}
+
+// The binding a variable reads, if it can be found without a full lookup
+fn binding(
+    program: &Program,
+    instruction: usize,
+    env: &Locker<Environment>,
+) -> Result<Option<Locker<Expression>>, Exception> {
+    match &program.instructions()[instruction] {
+        Instruction::Local { slot, .. } => local(program, *slot, env),
+        Instruction::Captured { depth, slot, .. } => captured(program, *depth, *slot, env),
+        Instruction::Global {
+            depth, name, cache, ..
+        } => global(program, *depth, name, *cache, env),
+        _ => Ok(None),
+    }
+}
+
+// The binding of a local of the program's function, read from its slot
+fn local(
+    program: &Program,
+    slot: usize,
+    env: &Locker<Environment>,
+) -> Result<Option<Locker<Expression>>, Exception> {
+    Ok(match program.layout() {
+        Some(layout) => env.read()?.local(layout, slot),
+        None => None,
+    })
+}
+
+// The binding of a local of a function the program's function was defined in
+fn captured(
+    program: &Program,
+    depth: usize,
+    slot: usize,
+    env: &Locker<Environment>,
+) -> Result<Option<Locker<Expression>>, Exception> {
+    Ok(match enclosing_env(program, env, depth)? {
+        Some(enclosing) => enclosing
+            .read()?
+            .local(&program.enclosing()[depth - 1], slot),
+        None => None,
+    })
+}
+
+// The binding of a name none of the enclosing functions bind
+fn global(
+    program: &Program,
+    depth: usize,
+    name: &Symbol,
+    cache: usize,
+    env: &Locker<Environment>,
+) -> Result<Option<Locker<Expression>>, Exception> {
+    match enclosing_env(program, env, depth)? {
+        Some(enclosing) => Environment::resolve_cached(&enclosing, name, &program.caches()[cache]),
+        None => Ok(None),
+    }
+}
+
+// Runs `read` on the value a constant or variable produces, in place: `None`
+// for other instructions, and for variables only a full lookup can find
+fn peek<R>(
+    program: &Program,
+    instruction: usize,
+    env: &Locker<Environment>,
+    read: impl FnOnce(&Value) -> R,
+) -> Result<Option<R>, Exception> {
+    if let Instruction::Constant(constant) = &program.instructions()[instruction] {
+        return Ok(Some(read(&*program.constants()[*constant].read_value()?)));
+    }
+    match binding(program, instruction, env)? {
+        Some(binding) => Ok(Some(read(&*binding.read()?.read_value()?))),
+        None => Ok(None),
+    }
+}
+
+// The head and arguments of the `Call` at `call`
+fn call_parts(program: &Program, call: usize) -> (usize, &[(usize, usize)]) {
+    match &program.instructions()[call] {
+        Instruction::Call {
+            head, arguments, ..
+        } => (*head, arguments),
+        _ => unreachable!(),
+    }
+}
+
+// Whether the head of the `Call` at `call` (still) refers to `operator`
+fn calls_operator(
+    program: &Program,
+    call: usize,
+    env: &Locker<Environment>,
+    operator: Operator,
+) -> Result<bool, Exception> {
+    let (head, _) = call_parts(program, call);
+    let found = peek(program, head, env, |value| match value {
+        Value::Operator(op) => *op == operator,
+        _ => false,
+    })?;
+    Ok(found == Some(true))
+}
+
+fn is_nil(exp: &Expression) -> Result<bool, Exception> {
+    Ok(matches!(&*exp.read_value()?, Value::List(vals) if vals.is_empty()))
+}
+
+// Runs a call that can't fail without creating a snapshot for it (see
+// `CallSnapshot::reserve_calls`): a `sum` or `gt` of numbers read straight
+// from constants and bindings, or a `let` of such a call (or of a constant or
+// binding) to a name given outright. `None` if the call isn't one of those,
+// in which case it's run in full.
+fn direct(
+    program: &Program,
+    instruction: usize,
+    snapshot: &Locker<CallSnapshot>,
+    env: &Locker<Environment>,
+) -> Result<Option<Expression>, Exception> {
+    let call = match &program.instructions()[instruction] {
+        Instruction::Let(call) => *call,
+        _ => {
+            return match arithmetic(program, instruction, env)? {
+                Some(result) if CallSnapshot::reserve_calls(snapshot, 1)? => Ok(Some(result)),
+                _ => Ok(None),
+            }
+        }
+    };
+
+    let (_, arguments) = call_parts(program, call);
+    if arguments.len() != 2 || !calls_operator(program, call, env, Operator::Let)? {
+        return Ok(None);
+    }
+    let symbol = peek(program, arguments[0].0, env, |value| match value {
+        Value::Symbol(symbol) if symbol.namespace().is_none() => Some(*symbol),
+        _ => None,
+    })?;
+    let symbol = match symbol.flatten() {
+        Some(symbol) => symbol,
+        None => return Ok(None),
+    };
+    let code = arguments[1].0;
+    let (value, calls) = match arithmetic(program, code, env)? {
+        Some(value) => (value, 2),
+        None => match read(program, code, env)? {
+            Some(value) => (value, 1),
+            None => return Ok(None),
+        },
+    };
+    if !CallSnapshot::reserve_calls(snapshot, calls)? {
+        return Ok(None);
+    }
+    let value = name_function(value, symbol);
+    // Assigning locally to a name without a namespace can't fail
+    let snapshot = snapshot.clone();
+    env.write()?.assign(symbol, value.clone(), true, snapshot)?;
+    Ok(Some(value))
+}
+
+// The result of a `sum` or `gt` of numbers that can be read straight from
+// constants and bindings, if that's what `instruction` is
+fn arithmetic(
+    program: &Program,
+    instruction: usize,
+    env: &Locker<Environment>,
+) -> Result<Option<Expression>, Exception> {
+    let (operator, call) = match &program.instructions()[instruction] {
+        Instruction::Sum(call) => (Operator::Sum, *call),
+        Instruction::Gt(call) => (Operator::Gt, *call),
+        _ => return Ok(None),
+    };
+    if !calls_operator(program, call, env, operator.clone())? {
+        return Ok(None);
+    }
+    let (_, arguments) = call_parts(program, call);
+    let number = |code: usize| {
+        let found = peek(program, code, env, |value| match value {
+            Value::Number(number) => Some(number.clone()),
+            _ => None,
+        })?;
+        Ok::<_, Exception>(found.flatten())
+    };
+    match operator {
+        Operator::Sum => {
+            let mut sum = Number::Integer(0);
+            for (code, _) in arguments {
+                match number(*code)? {
+                    Some(val) => sum = sum.add(&val),
+                    None => return Ok(None),
+                }
+            }
+            Ok(Some(Expression::new(Value::Number(sum))))
+        }
+        _ => {
+            let mut greater = true;
+            let mut previous: Option<Number> = None;
+            for (code, _) in arguments {
+                let val = match number(*code)? {
+                    Some(val) => val,
+                    None => return Ok(None),
+                };
+                if let Some(previous) = &previous {
+                    greater = greater && val > *previous;
+                }
+                previous = Some(val);
+            }
+            Ok(Some(match greater {
+                true => Expression::t(),
+                false => Expression::nil(),
+            }))
+        }
+    }
+}
+
+// The environment `depth` calls out from `env`: through the call of the
+// program's function, then those of the functions it was defined in. `None`
+// if the code isn't running in such a call, or one on the way has changed
+// shape since the program was compiled.
+fn enclosing_env(
+    program: &Program,
+    env: &Locker<Environment>,
+    depth: usize,
+) -> Result<Option<Locker<Environment>>, Exception> {
+    let mut env = env.clone();
+    let layouts = program.layout().into_iter().chain(program.enclosing());
+    for layout in layouts.take(depth) {
+        let parent = env.read()?.static_parent(layout);
+        match parent {
+            Some(parent) => env = parent,
+            None => return Ok(None),
+        }
+    }
+    Ok(Some(env))
+}
+
+fn lookup(
+    program: &Arc<Program>,
+    constant: usize,
+    parent_snapshot: &Locker<CallSnapshot>,
+    env: Locker<Environment>,
+) -> Result<Expression, Exception> {
+    let symbol_exp = &program.constants()[constant];
+    let found = match &*symbol_exp.value().read()? {
+        Value::Symbol(sym) => env.read()?.lookup(sym),
+        _ => unreachable!(),
+    };
+    match found {
+        Some(exp) => Ok(exp.read()?.clone()),
+        None => {
+            // The tree-walking interpreter reports the same exception (from a
+            // snapshot of the symbol itself)
+            symbol_exp.eval(parent_snapshot.clone(), env)
+        }
+    }
+}
+
+// Runs the builtins the VM has instructions for itself, as long as their
+// heads still refer to them; everything else is called like any other call.
+fn call(
+    program: &Arc<Program>,
+    instruction: usize,
+    snapshot: &Locker<CallSnapshot>,
+    env: Locker<Environment>,
+) -> Result<Next, Exception> {
+    let (operator, call) = match &program.instructions()[instruction] {
+        Instruction::Cond { call, clauses } => {
+            if !calls_operator(program, *call, &env, Operator::Cond)? {
+                return call_generic(program, *call, snapshot, env);
+            }
+            for (condition, value) in clauses {
+                if !is_nil(&eval(program, *condition, snapshot, env.clone())?)? {
+                    return Ok(Next::Tail(program.clone(), *value, env));
+                }
+            }
+            return Ok(Next::Done(Expression::nil()));
+        }
+        Instruction::Let(call) => (Operator::Let, *call),
+        Instruction::While(call) => (Operator::While, *call),
+        Instruction::If(call) => (Operator::If, *call),
+        Instruction::Sum(call) | Instruction::Gt(call) => {
+            return call_generic(program, *call, snapshot, env)
+        }
+        _ => return call_generic(program, instruction, snapshot, env),
+    };
+    let (_, arguments) = call_parts(program, call);
+    // Calls with the wrong number of arguments are left to the operator to
+    // report
+    let arity = match operator {
+        Operator::While => arguments.len() >= 2,
+        _ => arguments.len() == 2,
+    };
+    if !arity || !calls_operator(program, call, &env, operator.clone())? {
+        return call_generic(program, call, snapshot, env);
+    }
+
+    match operator {
+        Operator::Let => {
+            // Only names given outright are bound here; anything else is
+            // left to the operator, which reports what isn't a symbol
+            let symbol = peek(program, arguments[0].0, &env, |value| match value {
+                Value::Symbol(symbol) => Some(*symbol),
+                _ => None,
+            })?;
+            let symbol = match symbol.flatten() {
+                Some(symbol) => symbol,
+                None => return call_generic(program, call, snapshot, env),
+            };
+            let value = eval(program, arguments[1].0, snapshot, env.clone())?;
+            let value = name_function(value, symbol);
+            env.write()?
+                .assign(symbol, value.clone(), true, snapshot.clone())?;
+            Ok(Next::Done(value))
+        }
+        Operator::While => {
+            let (condition, body) = arguments.split_first().unwrap();
+            let mut result = Expression::nil();
+            while !is_nil(&eval(program, condition.0, snapshot, env.clone())?)? {
+                for (code, _) in body {
+                    result = eval(program, *code, snapshot, env.clone())?;
+                }
+            }
+            Ok(Next::Done(result))
+        }
+        _ => match is_nil(&eval(program, arguments[0].0, snapshot, env.clone())?)? {
+            false => Ok(Next::Tail(program.clone(), arguments[1].0, env)),
+            true => Ok(Next::Done(Expression::nil())),
+        },
+    }
+}
+
+fn call_generic(
+    program: &Arc<Program>,
+    instruction: usize,
+    snapshot: &Locker<CallSnapshot>,
+    env: Locker<Environment>,
+) -> Result<Next, Exception> {
+    let (site, head, arguments) = match &program.instructions()[instruction] {
+        Instruction::Call {
+            site,
+            head,
+            arguments,
+        } => (&program.constants()[*site], *head, arguments),
+        _ => unreachable!(),
+    };
+
+    let callee = eval(program, head, snapshot, env.clone())?;
+    let callee_value = callee.value();
+    let callee_value = callee_value.read()?;
+    match &*callee_value {
+        Value::Operator(Operator::Do) if !arguments.is_empty() => {
+            let (last, init) = arguments.split_last().unwrap();
+            for (code, _) in init {
+                eval(program, *code, snapshot, env.clone())?;
+            }
+            Ok(Next::Tail(program.clone(), last.0, env))
+        }
+        Value::Operator(op) => {
+            let compiled_arguments = CompiledArguments { program, arguments };
+            match op.step(snapshot.clone(), &compiled_arguments, site, env)? {
+                Step::Done(result) => Ok(Next::Done(result)),
+                Step::Tail(exp, env) => match exp.compiled() {
+                    Some((program, instruction)) => {
+                        Ok(Next::Tail(program.clone(), *instruction, env))
+                    }
+                    None => Ok(Next::Done(exp.eval(snapshot.clone(), env)?)),
+                },
+            }
+        }
+        Value::Lambda(function) => {
+            call_function(program, function, false, site, arguments, snapshot, env)
+        }
+        Value::Macro(function) => {
+            call_function(program, function, true, site, arguments, snapshot, env)
+        }
+        Value::Native(function) => {
+            let mut evaluated = Vec::with_capacity(arguments.len());
+            for (code, _) in arguments {
+                evaluated.push(eval(program, *code, snapshot, env.clone())?);
+            }
+            Ok(Next::Done(function.call(evaluated, snapshot)?))
+        }
+        Value::List(vals) if vals.is_empty() => {
+            exp!(EV::InvalidOperator(Value::List(vec![])), snapshot)
+        }
+        Value::List(_) | Value::Symbol(_) => {
+            // The head evaluated to more code; leave that to the interpreter
+            let mut call = vec![callee.clone()];
+            for (_, arg) in arguments {
+                call.push(program.constants()[*arg].clone());
+            }
+            let mut call = Expression::new(Value::List(call));
+            if let Some(source) = site.source() {
+                call = call.with_source(source.clone());
+            }
+            Ok(Next::Done(call.eval(snapshot.clone(), env)?))
+        }
+        val => exp!(EV::InvalidOperator(val.clone()), snapshot),
+    }
+}
+
+fn call_function(
+    program: &Arc<Program>,
+    function: &Function,
+    is_macro: bool,
+    site: &Expression,
+    arguments: &[(usize, usize)],
+    snapshot: &Locker<CallSnapshot>,
+    env: Locker<Environment>,
+) -> Result<Next, Exception> {
+    CallSnapshot::calls(snapshot, function)?;
+    // Compiling the body first lays out the slots of the call's environment
+    let body = function.compiled()?;
+    let scoped_env = function.call_scope(
+        is_macro,
+        &env,
+        arguments.len(),
+        |i| match is_macro {
+            false => eval(program, arguments[i].0, snapshot, env.clone()),
+            // Macros usually end up evaluating their arguments, which can
+            // then run the code already compiled for them
+            true => Ok(program.constants()[arguments[i].1]
+                .clone()
+                .with_compiled(program, arguments[i].0)),
+        },
+        snapshot,
+    )?;
+    match is_macro {
+        true => CallSnapshot::expand(snapshot, site)?,
+        false => CallSnapshot::enter_function(snapshot)?,
+    }
+
+    match body.entries().split_last() {
+        Some((last, init)) => {
+            for entry in init {
+                eval(&body, *entry, snapshot, scoped_env.clone())?;
+            }
+            Ok(Next::Tail(body.clone(), *last, scoped_env))
+        }
+        None => Ok(Next::Done(Expression::nil())),
+    }
+}
+
+// The arguments of an operator call, evaluated by running their compiled code
+struct CompiledArguments<'a> {
+    program: &'a Arc<Program>,
+    arguments: &'a [(usize, usize)],
+}
+
+impl Arguments for CompiledArguments<'_> {
+    fn len(&self) -> usize {
+        self.arguments.len()
+    }
+
+    fn expression(&self, index: usize) -> &Expression {
+        &self.program.constants()[self.arguments[index].1]
+    }
+
+    fn eval(
+        &self,
+        index: usize,
+        snapshot: Locker<CallSnapshot>,
+        env: Locker<Environment>,
+    ) -> Result<Expression, Exception> {
+        eval(self.program, self.arguments[index].0, &snapshot, env)
+    }
+}
@@ -16,12 +16,23 @@ pub mod spec;
 pub mod stdlib;
 pub mod util;
 
-pub use interpreter::call_snapshot::CallSnapshot;
+pub use interpreter::bytecode::{Instruction, Layout, Program};
+pub use interpreter::call_snapshot::{CallSnapshot, EvalLimits, Frame, DEFAULT_MAX_DEPTH};
+pub use interpreter::conditions::{Debugger, Restart};
+pub use interpreter::embedding::Interpreter;
 pub use interpreter::environment::Environment;
-pub use interpreter::exceptions::{Exception, ExceptionValue};
-pub use interpreter::expression::Expression;
-pub use interpreter::resolver::resolve_resource;
+pub use interpreter::evaluator::{Backend, EvaluationHandle, Evaluator, DEFAULT_STACK_SIZE};
+pub use interpreter::exceptions::{ErrorFormat, Exception, ExceptionValue};
+pub use interpreter::expression::{Expression, Step};
+pub use interpreter::heap::HeapStats;
+pub use interpreter::json::Json;
+pub use interpreter::resolver::{resolve_resource, DefaultResolver, ModuleResolver};
 pub use interpreter::source::{Source, SourcePosition};
-pub use interpreter::values::{Function, Keyword, Operator, Symbol, Value};
+#[cfg(feature = "serde")]
+pub use interpreter::values::{from_value, to_value};
+pub use interpreter::values::{
+    Arguments, FromTurtle, Function, IntoNative, IntoTurtle, Keyword, Map, NativeFunction, Number,
+    Operator, Symbol, SymbolMap, Value, Variadic,
+};
 pub use parser::parse;
 pub use util::Locker;
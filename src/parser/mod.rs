@@ -1,7 +1,10 @@
 use crate::{
-    Exception, ExceptionValue as EV, Expression, Keyword, Operator, Source, SourcePosition, Symbol,
-    Value,
+    Exception, ExceptionValue as EV, Expression, Keyword, Number, Operator, Source, SourcePosition,
+    Symbol, Value,
 };
+use num_bigint::BigInt;
+use num_rational::BigRational;
+use pest::error::{Error, ErrorVariant, InputLocation};
 use pest::iterators::Pair;
 use pest::Parser;
 
@@ -22,14 +25,11 @@ pub fn parse(code: &str, location: &str) -> Result<Vec<Expression>, Exception> {
                     // SOI isn't given
                     continue;
                 }
-                exps.push(
-                    build_expression(pair.clone(), source.clone())?
-                        .with_source(SourcePosition::from_pair(&pair, &source)),
-                );
+                exps.push(build_expression(pair, source.clone())?);
             }
             Ok(exps)
         }
-        Err(err) => Err(Exception::from(err)),
+        Err(err) => Err(syntax_error(&err, code, &source)),
     }
 }
 
@@ -43,12 +43,9 @@ fn build_expression(pair: Pair<'_, Rule>, source: Locker<Source>) -> Result<Expr
         Rule::list => {
             let mut values: Vec<Expression> = Vec::new();
             for elem in pair.into_inner() {
-                values.push(
-                    build_expression(elem.clone(), source.clone())?
-                        .with_source(SourcePosition::from_pair(&elem, &source)),
-                )
+                values.push(build_expression(elem, source.clone())?)
             }
-            Ok(Expression::new(Value::List(values)))
+            Ok(Expression::new(Value::List(values)).with_source(pos))
         }
         Rule::symbol => Ok(Expression::new(Value::Symbol(Symbol::new(String::from(
             pair.as_str(),
@@ -58,13 +55,30 @@ fn build_expression(pair: Pair<'_, Rule>, source: Locker<Source>) -> Result<Expr
             pair.into_inner().next().unwrap().as_str(),
         ))))
         .with_source(pos)),
-        Rule::number => match pair.as_str().parse::<f64>() {
-            Ok(num) => Ok(Expression::new(Value::Number(num)).with_source(pos)),
+        Rule::integer => match pair.as_str().parse::<BigInt>() {
+            Ok(num) => {
+                Ok(Expression::new(Value::Number(Number::from_bigint(num))).with_source(pos))
+            }
+            Err(_) => Err(invalid_number(pair.as_str()).with_source(pos)),
+        },
+        Rule::rational => match pair.as_str().parse::<BigRational>() {
+            Ok(num) => {
+                Ok(Expression::new(Value::Number(Number::from_rational(num))).with_source(pos))
+            }
             Err(_) => Err(Exception::new(
                 EV::Syntax,
                 None,
-                Some(format!("`{}` is not a valid number", pair.as_str())),
-            )),
+                Some(format!(
+                    "`{}` is not a valid number (its denominator is zero)",
+                    pair.as_str()
+                )),
+            )
+            .with_source(pos)),
+        },
+        Rule::malformed_number => Err(invalid_number(pair.as_str()).with_source(pos)),
+        Rule::float => match pair.as_str().parse::<f64>() {
+            Ok(num) => Ok(Expression::new(Value::Number(Number::Float(num))).with_source(pos)),
+            Err(_) => Err(invalid_number(pair.as_str()).with_source(pos)),
         },
         Rule::byte => match pair.as_str().replace('b', "").parse::<u8>() {
             Ok(num) => Ok(Expression::new(Value::Byte(num)).with_source(pos)),
@@ -72,26 +86,27 @@ fn build_expression(pair: Pair<'_, Rule>, source: Locker<Source>) -> Result<Expr
                 EV::Syntax,
                 None,
                 Some(format!("`{}` is not a valid byte (0-255)", pair.as_str())),
-            )),
+            )
+            .with_source(pos)),
         },
-        Rule::text => Ok(Expression::new(Value::Text(
-            pair.into_inner().as_str().to_string(),
-        ))),
+        Rule::text => {
+            Ok(Expression::new(Value::Text(decode_text(pair, &source)?)).with_source(pos))
+        }
 
         // Sugar
-        Rule::quote | Rule::eval => {
+        Rule::quote | Rule::eval | Rule::map => {
             let mut elements = vec![Expression::new(Value::Operator(match &pair.as_rule() {
                 Rule::quote => Operator::Quote,
                 Rule::eval => Operator::Eval,
+                Rule::map => Operator::Hashmap,
                 _ => unreachable!(),
-            }))];
+            }))
+            // The operator is implied by the sugar itself
+            .with_source(pos.clone())];
             for elem in pair.into_inner() {
-                elements.push(
-                    build_expression(elem.clone(), source.clone())?
-                        .with_source(SourcePosition::from_pair(&elem, &source)),
-                );
+                elements.push(build_expression(elem, source.clone())?);
             }
-            Ok(Expression::new(Value::List(elements)))
+            Ok(Expression::new(Value::List(elements)).with_source(pos))
         }
         _ => Err(Exception::new(
             EV::Syntax,
@@ -100,3 +115,194 @@ fn build_expression(pair: Pair<'_, Rule>, source: Locker<Source>) -> Result<Expr
         )),
     }
 }
+
+fn invalid_number(literal: &str) -> Exception {
+    Exception::new(
+        EV::Syntax,
+        None,
+        Some(format!("`{}` is not a valid number", literal)),
+    )
+}
+
+// The characters of a text literal, with its escape sequences decoded
+fn decode_text(pair: Pair<'_, Rule>, source: &Locker<Source>) -> Result<String, Exception> {
+    let mut text = String::new();
+    let mut parts = pair.into_inner().peekable();
+    while let Some(part) = parts.next() {
+        if part.as_rule() == Rule::char {
+            text.push_str(part.as_str());
+            continue;
+        }
+        let escape = part.as_str();
+        let decoded = match &escape[1..] {
+            "\"" => Ok('"'),
+            "\\" => Ok('\\'),
+            "/" => Ok('/'),
+            "b" => Ok('\u{8}'),
+            "f" => Ok('\u{c}'),
+            "n" => Ok('\n'),
+            "r" => Ok('\r'),
+            "t" => Ok('\t'),
+            _ => match code_unit(&part) {
+                // UTF-16 surrogates only make up a character in pairs, high
+                // then low
+                Some(high @ 0xD800..=0xDBFF) => {
+                    match parts.peek().and_then(code_unit) {
+                        Some(low @ 0xDC00..=0xDFFF) => {
+                            parts.next();
+                            let code = 0x10000 + ((high - 0xD800) << 10) + (low - 0xDC00);
+                            Ok(char::from_u32(code).unwrap()) // always in range
+                        }
+                        _ => Err(format!("`{}` is not followed by a low surrogate", escape)),
+                    }
+                }
+                Some(unit) => char::from_u32(unit)
+                    .ok_or_else(|| format!("`{}` is not preceded by a high surrogate", escape)),
+                None => Err(format!("`{}` is not a valid escape sequence", escape)),
+            },
+        };
+        match decoded {
+            Ok(decoded) => text.push(decoded),
+            Err(note) => {
+                return Err(Exception::new(EV::Syntax, None, Some(note))
+                    .with_source(SourcePosition::from_pair(&part, source)))
+            }
+        }
+    }
+    Ok(text)
+}
+
+// The UTF-16 code unit of a `\uXXXX` escape
+fn code_unit(pair: &Pair<'_, Rule>) -> Option<u32> {
+    match pair.as_str().strip_prefix("\\u") {
+        Some(hex) if pair.as_rule() == Rule::escape && hex.len() == 4 => {
+            u32::from_str_radix(hex, 16).ok()
+        }
+        _ => None,
+    }
+}
+
+// Points a parse failure at the code it happened in. Unbalanced brackets and
+// quotes are the most common reason code doesn't parse, but pest only notices
+// them once it reaches the end of the code, so they are looked for first.
+fn syntax_error(err: &Error<Rule>, code: &str, source: &Locker<Source>) -> Exception {
+    let (positions, note) = match find_unbalanced(code) {
+        Some(unbalanced) => unbalanced,
+        None => {
+            let pos = match err.location {
+                InputLocation::Pos(pos) => pos,
+                InputLocation::Span((start, _)) => start,
+            };
+            (vec![pos], describe_error(err))
+        }
+    };
+    let mut exception = Exception::new(EV::Syntax, None, Some(note));
+    for pos in positions {
+        exception = exception.with_source(highlight(code, pos, source));
+    }
+    exception
+}
+
+// The character at `pos`, or the one before it when `pos` is at the end of a
+// line (where there's nothing to highlight)
+fn highlight(code: &str, pos: usize, source: &Locker<Source>) -> SourcePosition {
+    match code[pos..].chars().next() {
+        Some(c) if c != '\n' => SourcePosition::new(pos, pos + c.len_utf8(), source.clone()),
+        _ => match code[..pos].char_indices().next_back() {
+            Some((start, _)) => SourcePosition::new(start, pos, source.clone()),
+            None => SourcePosition::new(pos, pos, source.clone()),
+        },
+    }
+}
+
+// Finds the first closing bracket without a matching opener, text that is
+// never closed, or (failing those) the innermost bracket that is never closed
+fn find_unbalanced(code: &str) -> Option<(Vec<usize>, String)> {
+    let mut open: Vec<(usize, char)> = Vec::new();
+    let mut chars = code.char_indices().peekable();
+    while let Some((pos, c)) = chars.next() {
+        let next = chars.peek().map(|(_, next)| *next);
+        match c {
+            '"' => {
+                let mut closed = false;
+                while let Some((_, c)) = chars.next() {
+                    match c {
+                        '\\' => {
+                            chars.next();
+                        }
+                        '"' => {
+                            closed = true;
+                            break;
+                        }
+                        _ => {}
+                    }
+                }
+                if !closed {
+                    return Some((
+                        vec![pos],
+                        "this text is never closed (a `\"` is missing)".to_string(),
+                    ));
+                }
+            }
+            ';' if next == Some(';') => while chars.next_if(|(_, c)| *c != '\n').is_some() {},
+            '/' if next == Some('/') => while chars.next_if(|(_, c)| *c != '\n').is_some() {},
+            '(' | '{' => open.push((pos, c)),
+            ')' | '}' => match open.pop() {
+                Some((_, opener)) if closer(opener) == c => {}
+                Some((opener_pos, opener)) => {
+                    return Some((
+                        vec![opener_pos, pos],
+                        format!(
+                            "this `{}` is closed by a `{}` instead of a `{}`",
+                            opener,
+                            c,
+                            closer(opener)
+                        ),
+                    ))
+                }
+                None => return Some((vec![pos], format!("this `{}` doesn't close anything", c))),
+            },
+            _ => {}
+        }
+    }
+    open.pop().map(|(pos, opener)| {
+        (
+            vec![pos],
+            format!(
+                "this `{}` is never closed (a `{}` is missing)",
+                opener,
+                closer(opener)
+            ),
+        )
+    })
+}
+
+fn closer(opener: char) -> char {
+    match opener {
+        '{' => '}',
+        _ => ')',
+    }
+}
+
+// What pest expected to find, in words
+fn describe_error(err: &Error<Rule>) -> String {
+    let positives = match &err.variant {
+        ErrorVariant::ParsingError { positives, .. } => positives,
+        ErrorVariant::CustomError { message } => return message.clone(),
+    };
+    let mut expected: Vec<&str> = Vec::new();
+    for rule in positives {
+        let name = match rule {
+            Rule::EOI => "the end of the code",
+            _ => "an expression",
+        };
+        if !expected.contains(&name) {
+            expected.push(name);
+        }
+    }
+    match expected.split_last() {
+        None => "this code can't be parsed".to_string(),
+        Some((last, [])) => format!("expected {} here", last),
+        Some((last, init)) => format!("expected {} or {} here", init.join(", "), last),
+    }
+}
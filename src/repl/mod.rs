@@ -9,10 +9,10 @@ use rustyline::Editor;
 use rustyline::{CompletionType, Config, Context};
 use rustyline_derive::Helper;
 use std::borrow::Cow::{self, Borrowed, Owned};
+use std::io::{self, Write};
+use std::sync::Arc;
 
-use crate::Locker;
-
-use crate::{parse, CallSnapshot, Environment};
+use crate::{parse, CallSnapshot, ErrorFormat, Exception, Expression, Interpreter, Restart};
 
 #[derive(Helper)]
 struct ReplHelper {
@@ -81,7 +81,57 @@ impl Validator for ReplHelper {
     }
 }
 
-pub fn spawn(env: Locker<Environment>) {
+/// Offers the restarts available for an uncaught exception (printed in
+/// `format`), and asks which one to invoke (and with what) on stdin. Runs on
+/// the evaluation worker, while the REPL is waiting for the result.
+fn choose_restart(
+    err: &Exception,
+    restarts: &[Restart],
+    format: ErrorFormat,
+) -> Option<(usize, Vec<Expression>)> {
+    let read_line = |prompt: &str| {
+        print!("{}", Color::Yellow.bold().paint(prompt));
+        io::stdout().flush().ok()?;
+        let mut line = String::new();
+        match io::stdin().read_line(&mut line) {
+            Ok(0) | Err(_) => None,
+            Ok(_) => Some(line.trim().to_string()),
+        }
+    };
+
+    eprintln!("{}", err.render(format));
+    println!("{}", Style::default().bold().paint("available restarts:"));
+    for (index, restart) in restarts.iter().enumerate() {
+        println!(
+            "   {} {}",
+            Color::Blue.bold().paint(index.to_string()),
+            restart.name
+        );
+    }
+    loop {
+        let choice = read_line("restart (blank to unwind) > ")?;
+        if choice.is_empty() {
+            return None;
+        }
+        let index = match choice.parse::<usize>() {
+            Ok(index) if index < restarts.len() => index,
+            _ => {
+                eprintln!("`{}` is not one of the restarts", choice);
+                continue;
+            }
+        };
+        let arguments = read_line("arguments > ")?;
+        match parse(&arguments, "<stdin>") {
+            Ok(arguments) => return Some((index, arguments)),
+            Err(err) => eprintln!("{}", err.render(format)),
+        }
+    }
+}
+
+/// Reads, evaluates and prints code from stdin until it's closed, printing
+/// errors in `format`.
+pub fn spawn(interpreter: &Interpreter, format: ErrorFormat) {
+    let (env, evaluator) = (interpreter.environment(), interpreter.evaluator());
     let config = Config::builder()
         .history_ignore_space(true)
         .completion_type(CompletionType::List)
@@ -111,23 +161,34 @@ pub fn spawn(env: Locker<Environment>) {
                 match parse(line.as_str(), "<stdin>") {
                     Ok(values) => {
                         for value in values {
-                            let snapshot = CallSnapshot::root(&value.clone());
+                            let snapshot = match interpreter.snapshot(&value).and_then(|snapshot| {
+                                CallSnapshot::with_debugger(
+                                    &snapshot,
+                                    Arc::new(move |err: &Exception, restarts: &[Restart]| {
+                                        choose_restart(err, restarts, format)
+                                    }),
+                                )
+                            }) {
+                                Ok(snapshot) => snapshot,
+                                Err(err) => {
+                                    eprintln!("{}", err.render(format));
+                                    continue;
+                                }
+                            };
                             match value
-                                .eval_async(snapshot, env.clone())
-                                .unwrap()
-                                .recv()
-                                .unwrap()
+                                .eval_async(evaluator, snapshot, env.clone())
+                                .and_then(|handle| handle.wait())
                             {
                                 Ok(result) => println!(
                                     "   {} {}",
                                     Color::Blue.bold().paint("="),
-                                    Style::default().bold().paint(format!("{}", result))
+                                    Style::default().bold().paint(format!("{:#}", result))
                                 ),
-                                Err(error) => eprintln!("{}", error),
+                                Err(error) => eprintln!("{}", error.render(format)),
                             }
                         }
                     }
-                    Err(err) => eprintln!("{:#}", err),
+                    Err(err) => eprintln!("{}", err.render(format)),
                 }
             }
             Err(ReadlineError::Interrupted) => break,
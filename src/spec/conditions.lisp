@@ -0,0 +1,64 @@
+;; Handlers choose how lower-level code carries on from a condition, by
+;; invoking one of the restarts it established.
+
+(import "@prelude")
+
+(func parse-record (record)
+    (restart-case
+        (cond
+            ((eq (type record) :integer) record)
+            ('t (throw (list :bad-record record))))
+        'use-value (lambda '(value) 'value)
+        'skip-record (lambda '() ':skipped)))
+
+(func parse-all (records)
+    (cond
+        ((eq records nil) nil)
+        ('t (cons (parse-record (car records)) (parse-all (cdr records))))))
+
+(letq records '(1 "two" 3))
+
+(assert (equiv
+    (handler-bind (parse-all records) (lambda '(c) '(invoke-restart 'use-value 2)))
+    '(1 2 3)))
+(assert (equiv
+    (handler-bind (parse-all records) (lambda '(c) '(invoke-restart 'skip-record)))
+    '(1 :skipped 3)))
+
+;; Handlers run before anything unwinds, so they see the condition's payload
+;; and every restart established below them
+(assert (equiv
+    (handler-bind
+        (parse-record "two")
+        (lambda '(c) '(invoke-restart 'use-value (list (exception-kind c) (restarts)))))
+    '((:bad-record "two") (use-value skip-record))))
+(assert (eq (restarts) nil))
+
+;; A handler that returns declines, leaving the condition to outer handlers
+(assert (equiv
+    (handler-bind
+        (handler-bind (parse-all records) (lambda '(c) 'nil))
+        (lambda '(c) '(invoke-restart 'skip-record)))
+    '(1 :skipped 3)))
+
+;; Without a handler that chooses a restart, the exception unwinds as usual
+(assert (eq
+    (catch (handler-bind (parse-all records) (lambda '(c) 'nil)) (lambda '(err) ':unwound))
+    :unwound))
+
+;; `catch` handles everything inside it, so outer handlers never see it
+(assert (eq
+    (handler-bind
+        (catch (parse-record "two") (lambda '(err) ':caught))
+        (lambda '(c) '(invoke-restart 'use-value :handled)))
+    :caught))
+
+;; `signal` returns nil when no handler takes the condition
+(assert (eq (signal :warning) nil))
+(assert (eq
+    (restart-case
+        (handler-bind
+            (do (signal :warning) :unreachable)
+            (lambda '(c) '(invoke-restart 'continue :continued)))
+        'continue (lambda '(value) 'value))
+    :continued))
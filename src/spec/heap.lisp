@@ -0,0 +1,28 @@
+;; Closures that refer to themselves through the environment they were
+;; defined in are freed by collections, and live ones keep working.
+
+(import "@prelude")
+
+(func heap-stat (name)
+    (second (first (filter (lambda '(stat) '(eq (first stat) name)) (heap)))))
+
+(func make-counter ()
+    (do
+        (letq n 0)
+        (letq counter (lambda '() '(do (export 'n (+ n 1)) n)))
+        counter))
+
+(letq counter (make-counter))
+(counter)
+(assert (eq (counter) 2))
+
+(letq collected (heap-stat :collected))
+(letq i 0)
+(while (gt i 10)
+    (do
+        (make-counter)
+        (++ i)))
+(heap :collect)
+
+(assert (gt collected (heap-stat :collected)))
+(assert (eq (counter) 3))
@@ -11,4 +11,41 @@
 (assert (eq (map::extract :key map) 1))
 (assert (map::contains :key2 map))
 (map::remove! :key2 map)
-(assert (not (map::contains :key2 map)))
\ No newline at end of file
+(assert (not (map::contains :key2 map)))
+;; Old-style maps (lists of pairs) still work
+(assert (eq (map::extract :b '((:a 1) (:b 2))) 2))
+(assert (eq (map::as-map '((:a 1) (:a 2))) {:a 1}))
+(assert (eq (map::as-map nil) {}))
+;; Long ones too
+(letq pairs ())
+(letq n 5000)
+(while (gt 0 n)
+    (do
+        (letq pairs (cons (list n (* n n)) pairs))
+        (-- n)))
+(assert (eq (map::extract 4000 pairs) 16000000))
+(assert (not (map::contains 5001 pairs)))
+
+;; Native maps
+(letq m {:a 1 :b (+ 1 1) "c" '(1 2)})
+(assert (eq (type m) :map))
+(assert (eq (length m) 3))
+(assert (eq (get :b m) 2))
+(assert (eq (get :z m) ()))
+(assert (eq (get :z m 5) 5))
+(assert (has "c" m))
+(assert (not (has :z m)))
+(assert (eq (put :z 26 m) {:a 1 :b 2 "c" '(1 2) :z 26}))
+(assert (not (has :z m)))
+(assert (eq (del :a m) {"c" (list 1 2) :b 2}))
+(assert (eq (merge m {:a 10} {:d 4}) {:a 10 :b 2 "c" '(1 2) :d 4}))
+(assert (equiv (sort (values {:a 3 :b 1 :c 2})) '(1 2 3)))
+(assert (eq (length (keys m)) 3))
+(assert (equiv (entries {:a 1}) '((:a 1))))
+(assert (not (eq {:a 1} {:a 2})))
+(assert (eq {} (hashmap)))
+
+;; Numbers that are `eq` are the same key
+(assert (eq (get 1.0 {1 :one}) :one))
+(assert (eq (get 2/2 {1 :one}) :one))
+(assert (eq (length (put 1.0 :float {1 :one})) 1))
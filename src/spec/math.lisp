@@ -49,3 +49,36 @@
 (assert (strictly-decreasing 5 4 3 2 1))
 (assert (not (strictly-decreasing 1 2 8 3 4 5)))
 (assert (not (strictly-decreasing 5 4 4 3 2)))
+
+;; Numeric tower
+(assert (eq (type 1) :integer))
+(assert (eq (type 1.0) :float))
+(assert (eq (type 1e3) :float))
+(assert (eq (type 1/2) :rational))
+(assert (eq (type (/ 10 5)) :integer))
+(assert (eq 1 1.0))
+(assert (eq (/ 1 3) 1/3))
+(assert (eq (+ 1/3 2/3) 1))
+(assert (eq (+ 1/2 0.25) 0.75))
+(assert (eq (type (+ 1/2 0.25)) :float))
+(assert (gt 1/3 0.5 2/3 1))
+
+;; Integers never lose precision
+(assert (eq (type (exp 2 64)) :integer))
+(assert (eq (+ (exp 2 53) 1) 9007199254740993))
+(assert (not (eq (+ (exp 2 53) 1) (exp 2 53))))
+(assert (eq (- (* 9223372036854775807 2) 9223372036854775807) 9223372036854775807))
+(assert (eq (modulo (+ (exp 10 30) 7) 10) 7))
+(assert (eq (format "{}" (exp 2 100)) "1267650600228229401496703205376"))
+(assert (eq (format "{} {} {}" 3 3.0 3/4) "3 3.0 3/4"))
+
+;; Rounding
+(assert (eq (floor 7/2) 3))
+(assert (eq (floor -7/2) -4))
+(assert (eq (type (floor 3.5)) :float))
+(assert (eq (modulo 7/2 1) 1/2))
+
+;; Exact powers too large to compute are refused up front
+(assert (eq (catch (exp 3 200000000) (lambda '(err) '(exception-kind err))) :length-limit-exp))
+(assert (eq (exp 1 200000000) 1))
+(assert (eq (exp -1 200000001) -1))
@@ -1,23 +1,16 @@
-use crate::{parse, CallSnapshot, Environment, Exception, Expression};
-
-use crate::Locker;
-
-fn exec(code: &str) -> Result<Expression, Exception> {
-    let root = Locker::new(Environment::root());
-    let expressions = parse(code, "<test module>")?;
-    let mut ret = Expression::nil();
-    for expression in expressions {
-        let snapshot = CallSnapshot::root(&expression);
-        ret = expression
-            .eval_async(snapshot, root.clone())?
-            .recv()
-            .unwrap()?;
-    }
-    Ok(ret)
+use crate::{Backend, Evaluator, Exception, Expression, Interpreter};
+
+fn exec(code: &str, backend: Backend) -> Result<Expression, Exception> {
+    let interpreter = Interpreter::with_evaluator(Evaluator::new()?.with_backend(backend));
+    interpreter.eval_source(code, "<test module>")
 }
 
 pub fn check(code: &str) -> Result<Expression, Exception> {
-    match exec(code) {
+    check_with(code, Backend::Interpreter)
+}
+
+pub fn check_with(code: &str, backend: Backend) -> Result<Expression, Exception> {
+    match exec(code, backend) {
         Ok(value) => {
             println!("{}", value);
             Ok(value)
@@ -31,7 +24,16 @@ pub fn check(code: &str) -> Result<Expression, Exception> {
 
 #[cfg(test)]
 mod tests {
-    use super::check;
+    use super::{check, check_with};
+    use crate::interpreter::heap;
+    use crate::{
+        parse, Backend, CallSnapshot, Debugger, DefaultResolver, Environment, ErrorFormat,
+        EvalLimits, Evaluator, ExceptionValue, Expression, FromTurtle, Instruction, Interpreter,
+        IntoTurtle, Locker, ModuleResolver, Program, Source, Value, Variadic,
+    };
+    use std::collections::HashMap;
+    use std::sync::Arc;
+    use std::time::Duration;
 
     #[test]
     fn smoke_test() {
@@ -48,6 +50,26 @@ mod tests {
         assert!(check(include_str!("map.lisp")).is_ok());
     }
 
+    #[test]
+    fn tail_calls() {
+        assert!(check(include_str!("tail_calls.lisp")).is_ok());
+    }
+
+    #[test]
+    fn scoping() {
+        assert!(check(include_str!("scoping.lisp")).is_ok());
+    }
+
+    #[test]
+    fn heap() {
+        assert!(check(include_str!("heap.lisp")).is_ok());
+    }
+
+    #[test]
+    fn conditions() {
+        assert!(check(include_str!("conditions.lisp")).is_ok());
+    }
+
     #[test]
     fn euler_1() {
         assert!(check(include_str!("euler_1.lisp")).is_ok());
@@ -77,4 +99,970 @@ mod tests {
     fn euler_6() {
         assert!(check(include_str!("euler_6.lisp")).is_ok());
     }
+
+    #[test]
+    fn bytecode() {
+        for code in &[
+            include_str!("smoke_test.lisp"),
+            include_str!("math.lisp"),
+            include_str!("map.lisp"),
+            include_str!("tail_calls.lisp"),
+            include_str!("scoping.lisp"),
+            include_str!("heap.lisp"),
+            include_str!("conditions.lisp"),
+            include_str!("euler_1.lisp"),
+            include_str!("euler_2.lisp"),
+            include_str!("euler_3.lisp"),
+            include_str!("euler_4.lisp"),
+            include_str!("euler_5.lisp"),
+            include_str!("euler_6.lisp"),
+        ] {
+            assert!(check_with(code, Backend::Bytecode).is_ok());
+        }
+    }
+
+    #[test]
+    fn bytecode_locals() {
+        // Parameters and `let`-locals are read from slots, as are the locals
+        // of the function the code was defined in; everything else is
+        // resolved from outside of both
+        let symbol = crate::Symbol::from_str;
+        let outer = Program::compile_function(&[symbol("z")], &[], vec![]).unwrap();
+        let body = parse("(let 'y x) (sum x y z)", "<test module>").unwrap();
+        let enclosing = vec![outer.layout().unwrap().clone()];
+        let program = Program::compile_function(&[symbol("x")], &body, enclosing).unwrap();
+        let layout = program.layout().unwrap();
+        assert_eq!(layout.symbols(), &[symbol("x"), symbol("y")]);
+        let count = |matches: fn(&Instruction) -> bool| {
+            program
+                .instructions()
+                .iter()
+                .filter(|instruction| matches(instruction))
+                .count()
+        };
+        assert_eq!(count(|i| matches!(i, Instruction::Local { .. })), 3);
+        assert_eq!(
+            count(|i| matches!(i, Instruction::Captured { depth: 1, .. })),
+            1
+        );
+        // `let` and `sum`
+        assert_eq!(
+            count(|i| matches!(i, Instruction::Global { depth: 2, .. })),
+            2
+        );
+
+        // ...which gives the same results as looking them up
+        let code = "(import \"@prelude\") \
+                    (let 'y :global) \
+                    (let 'f (lambda '(x) '(do \
+                        (let 'before y) (let 'y x) (letq x :rebound) \
+                        (list before y x ((lambda '() 'x)))))) \
+                    (let 'adder (lambda '(n) '(lambda '(m) '(sum n m)))) \
+                    (let 'counter (lambda '() '(do (let 'n 0) \
+                        (lambda '() '(do (export 'n (sum n 1)) n))))) \
+                    (let 'count (counter)) (count) \
+                    (let 'shadowed (lambda '(n) '(do (let (car '(y)) n) \
+                        ((lambda '() 'y))))) \
+                    (list (f 1) (f 2) ((adder 2) 3) y (count) (shadowed :local))";
+        let expected =
+            "((:global 1 :rebound :rebound) (:global 2 :rebound :rebound) 5 :global 2 :local)";
+        for backend in [Backend::Interpreter, Backend::Bytecode].iter().copied() {
+            let result = check_with(code, backend).unwrap();
+            assert_eq!(format!("{}", result), expected);
+        }
+    }
+
+    #[test]
+    fn bytecode_builtins() {
+        // Calls to `sum`, `gt`, `cond`, `if`, `let` and `while` get their own
+        // instructions...
+        let body = parse(
+            "(let 'y (sum x 1)) (while (gt y 0) (let 'y (sum y -1))) \
+             (cond ((gt y 0) 1) ('t 2)) (if (gt x 0) x)",
+            "<test module>",
+        )
+        .unwrap();
+        let program =
+            Program::compile_function(&[crate::Symbol::from_str("x")], &body, vec![]).unwrap();
+        let count = |matches: fn(&Instruction) -> bool| {
+            program
+                .instructions()
+                .iter()
+                .filter(|instruction| matches(instruction))
+                .count()
+        };
+        assert_eq!(count(|i| matches!(i, Instruction::Sum(_))), 2);
+        assert_eq!(count(|i| matches!(i, Instruction::Gt(_))), 3);
+        assert_eq!(count(|i| matches!(i, Instruction::Let(_))), 2);
+        assert_eq!(count(|i| matches!(i, Instruction::While(_))), 1);
+        assert_eq!(count(|i| matches!(i, Instruction::Cond { .. })), 1);
+        assert_eq!(count(|i| matches!(i, Instruction::If(_))), 1);
+
+        // ...which still call whatever the names are bound to at the time
+        let code = "(let 'n 0) \
+                    (while (gt n 3) (let 'n (sum n 1))) \
+                    (let 'todo :bound) \
+                    (let 'first (list (if (gt 2 n) todo) (if () todo) n)) \
+                    (let 'sum (lambda '(a b) '(list a b))) \
+                    (let 'if (lambda '(a b) '(list b a))) \
+                    (list first (sum 1 2) (if 1 2))";
+        let expected = "((:bound nil 3) (1 2) (2 1))";
+        for backend in [Backend::Interpreter, Backend::Bytecode].iter().copied() {
+            let result = check_with(code, backend).unwrap();
+            assert_eq!(format!("{}", result), expected);
+            assert!(check_with("(if 't)", backend).is_err());
+        }
+    }
+
+    #[test]
+    fn text_escapes() {
+        let text = |code: &str| match &*parse(code, "<test module>").unwrap()[0]
+            .value()
+            .read()
+            .unwrap()
+        {
+            Value::Text(text) => text.clone(),
+            other => panic!("`{}` is not text", other),
+        };
+        let code = r#""tab\tquote\"slash\\ \u00e9\uD83D\uDE00\u0007""#;
+        let decoded = "tab\tquote\"slash\\ é😀\u{7}";
+        assert_eq!(text(code), decoded);
+
+        // The alternate form re-escapes text, and reads back the same
+        let value = Value::List(vec![Expression::new(Value::Text(decoded.to_string()))]);
+        let printed = format!("{:#}", value);
+        assert_eq!(printed, r#"("tab\tquote\"slash\\ é😀\u0007")"#);
+        assert_eq!(
+            format!("{:#}", parse(&printed, "<test module>").unwrap()[0]),
+            printed
+        );
+        assert_eq!(format!("{}", value), format!("({})", decoded));
+
+        for invalid in &[r#""\q""#, r#""\u12""#, r#""\uD83D""#, r#""\uDE00\uD83D""#] {
+            match parse(invalid, "<test module>") {
+                Err(err) => assert!(matches!(err.into_value(), ExceptionValue::Syntax)),
+                Ok(_) => panic!("{} should not parse", invalid),
+            }
+        }
+    }
+
+    #[test]
+    fn syntax_errors() {
+        let error = |code: &str| match parse(code, "<test module>") {
+            Err(err) => {
+                let message = format!("{}", err);
+                assert!(matches!(err.into_value(), ExceptionValue::Syntax));
+                message
+            }
+            Ok(_) => panic!("`{}` should not parse", code),
+        };
+
+        let unclosed = error("(disp 1)\n(disp (+ 1 2)\n(disp 3)");
+        assert!(unclosed.contains("<test module>:2"));
+        assert!(unclosed.contains("this `(` is never closed"));
+        assert!(error("(disp 1))").contains("this `)` doesn't close anything"));
+        assert!(error("(disp {1 2)").contains("this `{` is closed by a `)`"));
+        assert!(error("(disp \"text)").contains("this text is never closed"));
+        assert!(error("(disp ;; (\n 1.)").contains("`1.` is not a valid number"));
+        assert!(error("{1}").contains("expected an expression"));
+        assert!(error("(disp 2x)").contains("`2x` is not a valid number"));
+
+        // Numbers can run straight into comments and sugar
+        let numbers = "(list 1;; one\n2// two\n3/4;; three quarters\n5.5'six)";
+        assert_eq!(format!("{}", check(numbers).unwrap()), "(1 2 3/4 5.5 six)");
+        let eval = check("(let 'x 7) (list 1,x)").unwrap();
+        assert_eq!(format!("{}", eval), "(1 7)");
+    }
+
+    #[test]
+    fn error_formats() {
+        let err = check("(let 'f (lambda '(x) '(sum x oops)))\n(f 1)").unwrap_err();
+        assert!(err.render(ErrorFormat::Pretty).contains('\x1b'));
+        let plain = err.render(ErrorFormat::Plain);
+        assert!(!plain.contains('\x1b'));
+        assert!(plain.contains("<test module>:1:30"));
+
+        // Escapes in the values themselves are kept
+        let escaped = check("(throw \"\\u001b[1m\\\"bold\\\"\")").unwrap_err();
+        let plain = escaped.render(ErrorFormat::Plain);
+        assert!(plain.contains("\x1b[1m\"bold\""));
+        assert!(!plain.contains("\x1b[31m"));
+        let json: serde_json::Value =
+            serde_json::from_str(&escaped.render(ErrorFormat::Json)).unwrap();
+        assert_eq!(json["message"], "\x1b[1m\"bold\"");
+
+        let json: serde_json::Value = serde_json::from_str(&err.render(ErrorFormat::Json)).unwrap();
+        assert_eq!(json["kind"], ":undefined-symbol-exp");
+        assert_eq!(json["note"], serde_json::Value::Null);
+        assert_eq!(json["primary"]["code"], "oops");
+        assert_eq!(json["primary"]["start"]["line"], 1);
+        assert_eq!(json["primary"]["start"]["column"], 30);
+        assert_eq!(json["primary"]["end"]["column"], 34);
+        let frames = json["backtrace"].as_array().unwrap();
+        assert_eq!(frames[0]["expression"], "(f 1)");
+        assert_eq!(frames[0]["function"], "f");
+        assert_eq!(frames[0]["source"]["start"]["line"], 2);
+
+        // Syntax errors point at the code they're about
+        let err = parse("(disp (1 2", "<test module>").unwrap_err();
+        let json: serde_json::Value = serde_json::from_str(&err.to_json().to_string()).unwrap();
+        assert_eq!(json["kind"], ":syntax-exp");
+        assert_eq!(json["primary"]["code"], "(");
+        assert_eq!(json["primary"]["start"]["column"], 7);
+        assert!(json["backtrace"].as_array().unwrap().is_empty());
+    }
+
+    #[test]
+    fn source_positions() {
+        fn all_sourced(exp: &Expression) -> bool {
+            exp.source().is_some()
+                && match &*exp.value().read().unwrap() {
+                    Value::List(vals) => vals.iter().all(all_sourced),
+                    _ => true,
+                }
+        }
+        for exp in parse("(disp '(1 \"two\" {:three 3}))", "<test module>").unwrap() {
+            assert!(all_sourced(&exp));
+        }
+
+        // Code built by a macro points back at where the macro was called
+        let code = "(let 'build (macro '() '(,(list 'sum 1 'oops))))\n\
+                    (let 'f (lambda '() '(build)))\n\
+                    (f)";
+        for backend in &[Backend::Interpreter, Backend::Bytecode] {
+            let trace = format!("{}", check_with(code, *backend).unwrap_err());
+            assert!(trace.contains("expanded from"));
+            assert!(trace.contains("<test module>:2"));
+        }
+    }
+
+    #[test]
+    fn source_lines_and_columns() {
+        let source = Source::new(
+            "(disp \"日本語\")\r\n(sum 1 'é)\n".to_string(),
+            "<test module>".to_string(),
+        );
+        assert_eq!(source.lines(), 3);
+        assert_eq!(source.line_text(1), Some("(disp \"日本語\")"));
+        assert_eq!(source.line_col(0), Some((1, 1)));
+        assert_eq!(source.line_col(10), Some((1, 9)));
+        assert_eq!(source.line_col(20), Some((2, 1)));
+        assert_eq!(source.offset(2, 1), Some(20));
+        assert_eq!(source.offset(1, 9), Some(10));
+        assert_eq!(source.offset(2, 11), Some(31));
+        assert_eq!(source.offset(2, 12), None);
+        assert_eq!(source.line_col(100), None);
+
+        // Without colors, so the underlines can be lined up
+        let ansi = regex::Regex::new("\x1b\\[[0-9;]*m").unwrap();
+        let error = |code: &str| {
+            let trace = format!("{}", check(code).unwrap_err());
+            ansi.replace_all(&trace, "").to_string()
+        };
+        let trace = error("(disp \"日本語\" (sum 'ü 1))");
+        assert!(trace.contains("<test module>:1:13"));
+        assert!(trace.contains(&format!("│ {}^^^^^^^^^^\n", " ".repeat(15))));
+        assert!(error("(disp\n  \"🐢\" oops)").contains("<test module>:2:7"));
+    }
+
+    #[test]
+    fn backtraces() {
+        let code = "(let 'count (lambda '(n) '(cond ((eq n 0) (sum \"none\" 1)) ('t (cons n (count (sum n -1)))))))\n\
+                    (count 20)";
+        let err = check(code).unwrap_err();
+        let frames = err.backtrace();
+        assert_eq!(frames.first().unwrap().expression, "(count 20)");
+        assert_eq!(frames.first().unwrap().depth, 0);
+        assert_eq!(frames.last().unwrap().expression, "(sum \"none\" 1)");
+        let calls = frames
+            .iter()
+            .filter(|frame| frame.function == Some(crate::Symbol::from_str("count")))
+            .count();
+        assert_eq!(calls, 21);
+        assert!(format!("{}", err).contains("... 36 similar frames omitted"));
+
+        // Handlers that take two arguments get the frames too
+        let caught = check(&format!(
+            "{}\n(catch (count 2) (lambda '(err trace) '(list (length trace) (get :line (nth 0 trace)))))",
+            code.lines().next().unwrap()
+        ))
+        .unwrap();
+        assert_eq!(format!("{}", caught), "(7 2)");
+    }
+
+    #[test]
+    fn function_names() {
+        let code = "(import \"@prelude\") \
+                    (func add (a b) (+ a b)) \
+                    (func twice (f x) (f x x x))";
+        assert_eq!(
+            format!("{}", check(&format!("{} add", code)).unwrap()),
+            "<lambda add (a b) -> (+ a b)>"
+        );
+        // Functions keep the name they were defined with
+        assert_eq!(
+            format!("{}", check(&format!("{} (let 'g add) g", code)).unwrap()),
+            "<lambda add (a b) -> (+ a b)>"
+        );
+
+        let err = check(&format!("{} (twice add 1)", code)).unwrap_err();
+        assert!(matches!(
+            err.value(),
+            ExceptionValue::ArgumentMismatch(3, expected) if expected == "2"
+        ));
+        assert_eq!(
+            err.backtrace().last().unwrap().function,
+            Some(crate::Symbol::from_str("add"))
+        );
+        assert!(format!("{}", err).contains("`(add a b)` was called with `x`, `x`, `x`"));
+    }
+
+    #[test]
+    fn exception_values() {
+        let caught = |code: &str, handler: &str| {
+            let handler = format!("(lambda '(err) '{})", handler);
+            let result = check(&format!("(catch {} {})", code, handler)).unwrap();
+            format!("{}", result)
+        };
+        let oops = "(sum 1 oops)";
+        assert_eq!(
+            caught(oops, "(exception-kind err)"),
+            ":undefined-symbol-exp"
+        );
+        assert_eq!(caught(oops, "(exception-payload err)"), "oops");
+        assert_eq!(caught(oops, "(type err)"), ":exception");
+        let given = "(get :given (exception-payload err))";
+        assert_eq!(caught("(cons 1)", given), "1");
+        assert_eq!(
+            caught("(throw '(custom 1))", "(exception-kind err)"),
+            "(custom 1)"
+        );
+        assert_eq!(caught("(throw :custom)", "(exception-note err)"), "nil");
+        let invalid = "(sum 1 \"a\")";
+        assert!(caught(invalid, "(exception-message err)").contains("are invalid"));
+        assert!(caught(invalid, "(exception-note err)").contains("expects numbers"));
+        let frames = "(length (exception-backtrace err))";
+        assert_eq!(caught(oops, frames), "3");
+
+        // Cleanup always runs, and an exception it throws wins
+        assert_eq!(format!("{}", check("(finally 1 2)").unwrap()), "1");
+        let cause = "(list (exception-kind err) (exception-kind (exception-cause err)))";
+        assert_eq!(
+            caught("(finally (sum 1 oops) (cons 1))", cause),
+            "(:argument-mismatch-exp :undefined-symbol-exp)"
+        );
+        assert_eq!(caught("(finally oops 1)", "(exception-cause err)"), "nil");
+
+        // Re-throwing keeps the exception as it was
+        let err = check("(catch (sum 1 oops) (lambda '(err) '(throw err)))").unwrap_err();
+        assert!(matches!(err.value(), ExceptionValue::UndefinedSymbol(_, _)));
+        assert_eq!(err.backtrace().last().unwrap().expression, "oops");
+    }
+
+    #[test]
+    fn suggestions() {
+        let message = |code: &str| {
+            let handler = "(lambda '(err) '(exception-message err))";
+            let code = format!(
+                "(import \"@math\" :math) (let 'counter 1) (catch {} {})",
+                code, handler
+            );
+            format!("{}", check(&code).unwrap())
+        };
+        assert!(message("countr").contains("did you mean `counter`?"));
+        assert!(message("(lsit 1 2)").contains("did you mean `list`?"));
+        assert!(message("sin").contains("`math::sin`"));
+        assert!(message("math::sinn").contains("did you mean `math::sin`?"));
+        assert!(message("xyzzy").contains("did you mean to quote this symbol?"));
+
+        let err = check("(import \"@mth\")").unwrap_err();
+        assert!(matches!(err.value(), ExceptionValue::InvalidIncludePath(_)));
+        assert!(format!("{}", err).contains("did you mean `@math`?"));
+    }
+
+    #[test]
+    fn debugger_restarts() {
+        let run = |choice: Option<usize>| {
+            let env = Locker::new(Environment::root());
+            let code = "(restart-case (sum 1 oops) \
+                        'use-value (lambda '(value) 'value) \
+                        'abort (lambda '() ':aborted))";
+            let expression = parse(code, "<test module>").unwrap().remove(0);
+            let debugger: Debugger = Arc::new(move |err, restarts| {
+                assert!(matches!(err.value(), ExceptionValue::UndefinedSymbol(_, _)));
+                let names: Vec<String> = restarts.iter().map(|r| r.name.to_string()).collect();
+                assert_eq!(names, vec!["use-value", "abort"]);
+                choice.map(|index| {
+                    (
+                        index,
+                        vec![Expression::new(Value::Keyword(crate::Keyword::from_str(
+                            "chosen",
+                        )))],
+                    )
+                })
+            });
+            let snapshot =
+                CallSnapshot::with_debugger(&CallSnapshot::root(&expression), debugger).unwrap();
+            expression.eval(snapshot, env)
+        };
+        assert_eq!(format!("{}", run(Some(0)).unwrap()), ":chosen");
+        assert!(matches!(
+            run(None).unwrap_err().into_value(),
+            ExceptionValue::UndefinedSymbol(_, _)
+        ));
+    }
+
+    #[test]
+    fn embedding() {
+        for backend in [Backend::Interpreter, Backend::Bytecode].iter() {
+            let evaluator = Evaluator::new().unwrap().with_backend(*backend);
+            let interpreter = Interpreter::with_evaluator(evaluator);
+            interpreter.load_prelude().unwrap();
+            interpreter
+                .register_fn("hypot", |a: f64, b: f64| Ok(a.hypot(b)))
+                .unwrap();
+            interpreter
+                .register_fn("count-args", |Variadic(args): Variadic| Ok(args.len()))
+                .unwrap();
+            let eval = |code: &str| interpreter.eval_str(code).map(|exp| format!("{}", exp));
+
+            assert_eq!(eval("(hypot 3 (+ 2 2))").unwrap(), "5.0");
+            assert_eq!(eval("(count-args 1 2 3)").unwrap(), "3");
+            assert_eq!(eval("(type hypot)").unwrap(), ":native");
+            assert_eq!(eval("hypot").unwrap(), "<native hypot>");
+            let err = interpreter.eval_str("(hypot 1)").unwrap_err();
+            assert!(matches!(
+                err.value(),
+                ExceptionValue::ArgumentMismatch(1, _)
+            ));
+            // Exceptions are thrown from the call, and can be caught
+            let err = interpreter.eval_str("(hypot 1 \"a\")").unwrap_err();
+            assert_eq!(
+                err.backtrace().last().unwrap().expression,
+                "(hypot 1 \"a\")"
+            );
+            assert_eq!(
+                eval("(catch (hypot 1 :a) (lambda '(err) '(exception-kind err)))").unwrap(),
+                ":invalid-argument-exp"
+            );
+
+            interpreter
+                .set("answer", Expression::new(Value::Number(42i64.into())))
+                .unwrap();
+            assert_eq!(eval("(+ answer 1)").unwrap(), "43");
+            eval("(let 'doubled (* answer 2))").unwrap();
+            assert_eq!(format!("{}", interpreter.get("doubled").unwrap()), "84");
+            assert!(interpreter.get("missing").is_none());
+
+            let file = interpreter.eval_file("src/spec/smoke_test.lisp");
+            assert!(file.is_ok());
+            let missing = interpreter.eval_file("src/spec/missing.lisp").unwrap_err();
+            assert!(matches!(
+                missing.value(),
+                ExceptionValue::InvalidIncludePath(_)
+            ));
+        }
+    }
+
+    #[test]
+    fn conversions() {
+        fn round_trip<T: IntoTurtle + FromTurtle + PartialEq + std::fmt::Debug + Clone>(val: T) {
+            assert_eq!(T::from_turtle(&val.clone().into_turtle()).unwrap(), val);
+        }
+        round_trip(-3i64);
+        round_trip(u64::MAX);
+        round_trip(2.5f64);
+        round_trip(7u8);
+        round_trip(true);
+        round_trip(false);
+        round_trip(String::from("text"));
+        round_trip(vec![1i32, 2, 3]);
+        round_trip(Some(vec![Some(1usize), None]));
+        round_trip((1i64, String::from("a"), vec![0u8]));
+        round_trip(HashMap::from([(String::from("a"), 1i64)]));
+
+        let interpreter = Interpreter::new().unwrap();
+        interpreter.load_prelude().unwrap();
+        interpreter
+            .register_fn("shout", |text: String, times: Option<usize>| {
+                Ok(text.to_uppercase().repeat(times.unwrap_or(1)))
+            })
+            .unwrap();
+        interpreter
+            .register_fn("sum", |Variadic(vals): Variadic<i64>| {
+                Ok(vals.iter().sum::<i64>())
+            })
+            .unwrap();
+        interpreter
+            .register_fn("keys", |map: HashMap<String, Expression>| {
+                let mut keys: Vec<String> = map.into_keys().collect();
+                keys.sort();
+                Ok(keys)
+            })
+            .unwrap();
+        let eval = |code: &str| interpreter.eval_str(code).map(|exp| format!("{}", exp));
+
+        assert_eq!(eval("(shout \"hi\" 2)").unwrap(), "HIHI");
+        assert_eq!(eval("(shout \"hi\" nil)").unwrap(), "HI");
+        assert_eq!(eval("(sum 1 2 3)").unwrap(), "6");
+        assert_eq!(eval("(keys {:a 1 \"b\" 2})").unwrap(), "(a b)");
+
+        let err = interpreter.eval_str("(shout 1 2)").unwrap_err();
+        assert!(matches!(err.value(), ExceptionValue::InvalidArgument));
+        assert_eq!(
+            err.note().unwrap(),
+            "expected text (got `1`, which is of type :integer)"
+        );
+        let err = interpreter.eval_str("(shout \"a\" -1)").unwrap_err();
+        assert_eq!(
+            err.note().unwrap(),
+            "expected an integer that fits in a `usize` (got `-1`, which is of type :integer)"
+        );
+        assert_eq!(
+            err.backtrace().last().unwrap().expression,
+            "(shout \"a\" -1)"
+        );
+        assert!(eval("(sum 1 2.5)").is_err());
+    }
+
+    #[cfg(feature = "serde")]
+    #[test]
+    fn serialization() {
+        use serde::{Deserialize, Serialize};
+
+        #[derive(Debug, PartialEq, Serialize, Deserialize)]
+        enum Shape {
+            Empty,
+            Circle(f64),
+            Point(i64, i64),
+            Rect { width: u32, height: u32 },
+        }
+
+        #[derive(Debug, PartialEq, Serialize, Deserialize)]
+        struct Config {
+            name: String,
+            retries: Option<u16>,
+            shapes: Vec<Shape>,
+            tags: HashMap<String, bool>,
+        }
+
+        let config = Config {
+            name: String::from("turtle"),
+            retries: None,
+            shapes: vec![
+                Shape::Empty,
+                Shape::Circle(1.5),
+                Shape::Point(1, -2),
+                Shape::Rect {
+                    width: 3,
+                    height: 4,
+                },
+            ],
+            tags: HashMap::from([(String::from("fast"), true)]),
+        };
+        let value = crate::to_value(&config).unwrap();
+        assert_eq!(
+            format!("{:#}", value),
+            "((:name \"turtle\") (:retries nil) (:shapes ((:Empty) (:Circle 1.5) \
+             (:Point 1 -2) (:Rect (:width 3) (:height 4)))) (:tags {\"fast\" true}))"
+        );
+        assert_eq!(crate::from_value::<Config>(&value).unwrap(), config);
+
+        // Association lists are maps to `@map`, and structs can come from
+        // either (keyed by keywords, symbols or text)
+        let interpreter = Interpreter::new().unwrap();
+        interpreter.load_prelude().unwrap();
+        interpreter.set("config", value).unwrap();
+        let name = interpreter
+            .eval_str("(import \"@map\" :map) (map::extract :name config)")
+            .unwrap();
+        assert_eq!(format!("{}", name), "turtle");
+        let written = interpreter
+            .eval_str("{:name \"shell\" 'retries 2 \"shapes\" '((:Point 0 0) :Empty) :tags {}}")
+            .unwrap();
+        let config = crate::from_value::<Config>(&written).unwrap();
+        assert_eq!(config.name, "shell");
+        assert_eq!(config.retries, Some(2));
+        assert_eq!(config.shapes, vec![Shape::Point(0, 0), Shape::Empty]);
+
+        let err = crate::from_value::<Config>(&Expression::t()).unwrap_err();
+        assert!(matches!(err.value(), ExceptionValue::InvalidArgument));
+
+        // Turtle values themselves can be serialized, to JSON for instance
+        let list = interpreter
+            .eval_str("(list 1 \"two\" 3.5 (eq 1 1))")
+            .unwrap();
+        assert_eq!(
+            serde_json::to_string(&list).unwrap(),
+            "[1,\"two\",3.5,true]"
+        );
+        let parsed: Expression = serde_json::from_str("{\"a\": [1, null]}").unwrap();
+        assert_eq!(format!("{:#}", parsed), "{\"a\" (1 nil)}");
+    }
+
+    #[test]
+    fn module_resolvers() {
+        // Modules served from memory, which can import each other
+        struct Memory(HashMap<&'static str, &'static str>);
+        impl ModuleResolver for Memory {
+            fn locate(&self, path: &str, _importer: Option<&str>) -> Result<String, String> {
+                match self.0.contains_key(path) {
+                    true => Ok(format!("memory:{}", path)),
+                    false => Err(format!("`{}` is not in memory", path)),
+                }
+            }
+
+            fn load(&self, location: &str) -> Result<String, String> {
+                Ok(self.0[&location["memory:".len()..]].to_string())
+            }
+        }
+        let memory = Memory(HashMap::from([
+            (
+                "greet",
+                "(import \"names\") (let 'greeting (list \"hi\" name))",
+            ),
+            ("names", "(let 'name \"turtle\")"),
+        ]));
+        let interpreter =
+            Interpreter::with_evaluator(Evaluator::new().unwrap()).with_resolver(Arc::new(memory));
+        interpreter.load_prelude().unwrap();
+        let eval = |code: &str| interpreter.eval_str(code).map(|exp| format!("{:#}", exp));
+        assert_eq!(
+            eval("(import \"greet\" :greet) greet::greeting").unwrap(),
+            "(\"hi\" \"turtle\")"
+        );
+        let err = interpreter.eval_str("(import \"@math\")").unwrap_err();
+        assert!(matches!(err.value(), ExceptionValue::InvalidIncludePath(_)));
+        assert_eq!(err.note().unwrap(), "`@math` is not in memory");
+
+        // Sandboxes can refuse every import
+        struct DenyAll;
+        impl ModuleResolver for DenyAll {
+            fn locate(&self, _path: &str, _importer: Option<&str>) -> Result<String, String> {
+                Err(String::from("imports are disabled"))
+            }
+
+            fn load(&self, _location: &str) -> Result<String, String> {
+                Err(String::from("imports are disabled"))
+            }
+        }
+        let interpreter = Interpreter::new().unwrap().with_resolver(Arc::new(DenyAll));
+        interpreter.load_prelude().unwrap();
+        let err = interpreter
+            .eval_str("(import \"src/spec/smoke_test.lisp\")")
+            .unwrap_err();
+        assert_eq!(err.note().unwrap(), "imports are disabled");
+
+        // Libraries can be added to the default resolver at any time
+        let resolver = Arc::new(DefaultResolver::new());
+        let interpreter = Interpreter::new().unwrap().with_resolver(resolver.clone());
+        interpreter.load_prelude().unwrap();
+        let err = interpreter.eval_str("(import \"@config\")").unwrap_err();
+        assert!(matches!(err.value(), ExceptionValue::InvalidIncludePath(_)));
+        resolver
+            .register_library("@config", "(let 'port 8080)")
+            .unwrap();
+        let eval = |code: &str| interpreter.eval_str(code).map(|exp| format!("{:#}", exp));
+        assert_eq!(
+            eval("(import \"@config\" :config) config::port").unwrap(),
+            "8080"
+        );
+        assert_eq!(
+            eval("(import \"@math\" :math) (math::fibonacci 2)").unwrap(),
+            "(0 1 1)"
+        );
+        let err = interpreter.eval_str("(import \"@confg\")").unwrap_err();
+        assert_eq!(
+            err.note().unwrap(),
+            "`@confg` is not in the standard library (did you mean `@config`?)"
+        );
+        assert!(resolver.register_library("config", "").is_err());
+    }
+
+    #[test]
+    fn module_cache() {
+        // Modules whose code can be changed between imports, counting how
+        // often their code is loaded
+        struct Editable(Locker<HashMap<&'static str, &'static str>>, Locker<usize>);
+        impl ModuleResolver for Editable {
+            fn locate(&self, path: &str, _importer: Option<&str>) -> Result<String, String> {
+                match self.0.read().unwrap().contains_key(path) {
+                    true => Ok(path.to_string()),
+                    false => Err(format!("`{}` is not available", path)),
+                }
+            }
+
+            fn load(&self, location: &str) -> Result<String, String> {
+                *self.1.write().unwrap() += 1;
+                Ok(self.0.read().unwrap()[location].to_string())
+            }
+        }
+        let modules = Locker::new(HashMap::from([
+            ("version", "(let 'version 1)"),
+            ("a", "(import \"b\")"),
+            ("b", "(import \"c\")"),
+            ("c", "(import \"a\")"),
+        ]));
+        let loads = Locker::new(0);
+        let interpreter = Interpreter::new()
+            .unwrap()
+            .with_resolver(Arc::new(Editable(modules.clone(), loads.clone())));
+        interpreter.load_prelude().unwrap();
+        let eval = |code: &str| interpreter.eval_str(code).map(|exp| format!("{:#}", exp));
+
+        // Modules are only evaluated once, and shared by everything that
+        // imports them
+        eval("(import \"version\" :first)").unwrap();
+        modules
+            .write()
+            .unwrap()
+            .insert("version", "(let 'version 2)");
+        eval("(import \"version\" :second)").unwrap();
+        assert_eq!(
+            eval("(list first::version second::version)").unwrap(),
+            "(1 1)"
+        );
+        assert_eq!(*loads.read().unwrap(), 1);
+
+        // ...until they're reloaded
+        assert_eq!(eval("(reload \"version\")").unwrap(), "2");
+        assert_eq!(
+            eval("(list first::version second::version)").unwrap(),
+            "(2 2)"
+        );
+        modules
+            .write()
+            .unwrap()
+            .insert("version", "(let 'version 3)");
+        assert_eq!(
+            eval("(import \"version\") (let 'current (lambda '() 'version)) (current)").unwrap(),
+            "2"
+        );
+        assert_eq!(eval("(reload \"version\") (current)").unwrap(), "3");
+
+        let err = interpreter.eval_str("(import \"a\")").unwrap_err();
+        assert!(matches!(
+            err.value(),
+            ExceptionValue::ImportCycle(chain) if chain == &["a", "b", "c", "a"]
+        ));
+        assert_eq!(
+            err.value().explain(),
+            "import cycle: `a` imports `b`, which imports `c`, which imports `a`"
+        );
+        assert_eq!(
+            eval("(catch (import \"b\") (lambda '(err) '(exception-payload err)))").unwrap(),
+            "(\"b\" \"c\" \"a\" \"b\")"
+        );
+    }
+
+    #[test]
+    fn module_isolation() {
+        let resolver = Arc::new(DefaultResolver::new());
+        resolver
+            .register_library(
+                "@a",
+                "(import \"@prelude\") \
+                 (export 'leaked 42) \
+                 (export 'map (lambda '(f l) ':hijacked))",
+            )
+            .unwrap();
+        let interpreter = Interpreter::new().unwrap().with_resolver(resolver);
+        interpreter.load_prelude().unwrap();
+        let eval = |code: &str| interpreter.eval_str(code).map(|exp| format!("{:#}", exp));
+
+        // What a module exports stays in the module, even when it reaches
+        // for the modules it imports
+        eval("(import \"@a\" :a)").unwrap();
+        assert_eq!(
+            eval("(catch leaked (lambda '(err) ':not-visible))").unwrap(),
+            ":not-visible"
+        );
+        assert_eq!(
+            eval("(map (lambda '(x) '(+ x 1)) '(1 2))").unwrap(),
+            "(2 3)"
+        );
+        assert_eq!(eval("a::leaked").unwrap(), "42");
+
+        // ...and importers can't assign into it either
+        let err = interpreter.eval_str("(export 'a::leaked 1)").unwrap_err();
+        assert!(matches!(err.value(), ExceptionValue::Assignment(_, _)));
+        eval("(export 'mine 7)").unwrap();
+        assert_eq!(
+            eval("(import \"@a\" :b) (list mine b::leaked)").unwrap(),
+            "(7 42)"
+        );
+    }
+
+    #[test]
+    fn environment_clones() {
+        let mut env = Environment::root();
+        let x = crate::Symbol::from_str("x");
+        let assign = |env: &mut Environment, value: i64| {
+            let exp = value.into_turtle();
+            env.assign(x, exp.clone(), true, CallSnapshot::root(&exp))
+                .unwrap();
+        };
+        let lookup = |env: &Environment| format!("{}", env.lookup(&x).unwrap().read().unwrap());
+        assign(&mut env, 1);
+        let mut clone = env.clone();
+        assign(&mut clone, 2);
+        assert_eq!(lookup(&env), "1");
+        assert_eq!(lookup(&clone), "2");
+
+        // Resolutions cached through an environment see its rebinds
+        let env = Locker::new(env);
+        let child = Environment::root().with_parent(env.clone(), None);
+        assert_eq!(lookup(&child), "1");
+        assign(&mut env.write().unwrap(), 3);
+        assert_eq!(lookup(&child), "3");
+    }
+
+    #[test]
+    fn collect_cycles() {
+        let env = Locker::new(Environment::root());
+        let evaluator = Evaluator::new().unwrap();
+        for expression in parse(
+            "(let 'count (lambda '(n) '(cond ((eq n 0) 0) ('t (count (sum n -1))))))",
+            "<test module>",
+        )
+        .unwrap()
+        {
+            let snapshot = CallSnapshot::root(&expression);
+            evaluator
+                .submit(expression, snapshot, env.clone())
+                .unwrap()
+                .wait()
+                .unwrap();
+        }
+        drop(evaluator);
+
+        // Nothing reachable is touched
+        heap::collect();
+        assert!(env
+            .read()
+            .unwrap()
+            .lookup(&crate::Symbol::from_str("count"))
+            .is_some());
+
+        // `count` refers to itself through the environment it's defined in
+        let scope = env.downgrade();
+        drop(env);
+        heap::collect();
+        assert!(scope.upgrade().is_none());
+        assert!(heap::stats().collected >= 1);
+    }
+
+    #[test]
+    fn eval_limits() {
+        let run = |code: &str, limits: EvalLimits| {
+            let env = Locker::new(Environment::root());
+            let evaluator = Evaluator::new().unwrap().with_limits(limits);
+            let mut result = None;
+            for expression in parse(code, "<test module>").unwrap() {
+                let snapshot = CallSnapshot::root(&expression);
+                result = Some(
+                    evaluator
+                        .submit(expression, snapshot, env.clone())
+                        .unwrap()
+                        .wait(),
+                );
+            }
+            result.unwrap()
+        };
+        let limits = EvalLimits {
+            max_depth: 50,
+            max_steps: Some(10_000),
+            max_time: Some(Duration::from_millis(200)),
+            max_list_length: Some(100),
+        };
+
+        let recurse = "(let 'f (lambda '(n) '(cons n (f n)))) (f 1)";
+        assert!(matches!(
+            run(recurse, limits.clone()).unwrap_err().into_value(),
+            ExceptionValue::StackOverflow
+        ));
+        let steps = EvalLimits {
+            max_time: None,
+            ..limits.clone()
+        };
+        assert!(matches!(
+            run("(while 't ())", steps.clone())
+                .unwrap_err()
+                .into_value(),
+            ExceptionValue::StepLimit
+        ));
+        let time = EvalLimits {
+            max_steps: None,
+            ..limits.clone()
+        };
+        assert!(matches!(
+            run("(while 't ())", time).unwrap_err().into_value(),
+            ExceptionValue::TimeLimit
+        ));
+        let grow = "(let 'l ()) (while 't (let 'l (cons 1 l)))";
+        assert!(matches!(
+            run(grow, limits.clone()).unwrap_err().into_value(),
+            ExceptionValue::LengthLimit
+        ));
+
+        // Cleanup still runs after a limit is exceeded
+        let cleanup = "(let 'cleaned nil) \
+                       (catch (finally (while 't ()) (let 'cleaned :done)) (lambda '(err) 'nil)) \
+                       cleaned";
+        assert_eq!(format!("{}", run(cleanup, steps.clone()).unwrap()), ":done");
+
+        // Exceeding a limit can be handled like any other exception
+        let caught = run(
+            "(catch (while 't ()) (lambda '(err) '(exception-kind err)))",
+            steps,
+        )
+        .unwrap();
+        assert_eq!(format!("{}", caught), ":step-limit-exp");
+    }
+
+    #[test]
+    fn cancel_evaluation() {
+        let env = Locker::new(Environment::root());
+        let evaluator = Evaluator::new().unwrap();
+        let mut expressions = parse("(while 't ())", "<test module>").unwrap();
+        let expression = expressions.remove(0);
+        let handle = evaluator
+            .submit(expression.clone(), CallSnapshot::root(&expression), env)
+            .unwrap();
+        assert!(!handle.is_finished());
+        handle.cancel();
+        match handle.wait() {
+            Err(err) => assert!(matches!(err.into_value(), ExceptionValue::Interrupted)),
+            Ok(_) => panic!("cancelled evaluation should not finish"),
+        }
+    }
+
+    #[test]
+    fn taken_results() {
+        let env = Locker::new(Environment::root());
+        let evaluator = Evaluator::new().unwrap();
+        let mut expressions = parse("(sum 1 2)", "<test module>").unwrap();
+        let expression = expressions.remove(0);
+        let mut handle = evaluator
+            .submit(expression.clone(), CallSnapshot::root(&expression), env)
+            .unwrap();
+        let result = loop {
+            if let Some(result) = handle.try_result() {
+                break result;
+            }
+            std::thread::yield_now();
+        };
+        assert_eq!(format!("{}", result.unwrap()), "3");
+
+        // The result is only handed out once
+        assert!(handle.is_finished());
+        assert!(matches!(
+            handle.try_result().unwrap().unwrap_err().into_value(),
+            ExceptionValue::Concurrency
+        ));
+        assert!(matches!(
+            handle.wait().unwrap_err().into_value(),
+            ExceptionValue::Concurrency
+        ));
+    }
 }
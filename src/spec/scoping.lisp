@@ -0,0 +1,28 @@
+;; Bindings introduced after a symbol has already been looked up should
+;; still shadow whatever it resolved to before.
+
+(import "@prelude")
+
+(letq y :outer)
+
+(func outer ()
+    (do
+        ;; `h` closes over the environment of an intermediate call, so its
+        ;; lookups of `y` pass through that environment on their way out
+        (letq h ((lambda '() '(lambda '() 'y))))
+        (letq before (h))
+        (letq y :inner)
+        (list before (h))))
+
+(assert (equiv (outer) '(:outer :inner)))
+(assert (eq y :outer))
+
+(func get-y () y)
+(assert (eq (get-y) :outer))
+(letq y :updated)
+(assert (eq (get-y) :updated))
+
+(func roll () (type 1))
+(assert (eq (roll) :integer))
+(letq type (lambda '(x) ':shadowed))
+(assert (eq (roll) :shadowed))
@@ -2,6 +2,16 @@
 
 (assert (eq "smoke" "smoke"))
 (assert (not (eq "smoke" "smoketest")))
+(assert (not (eq " smoke " "smoke")))
+(assert (eq "\u0041\/\u00e9" "A/é"))
+(assert (eq "\uD83D\uDE00" "😀"))
+
+;; `format` (like `disp`) writes text as-is, even inside lists, so what it
+;; writes only reads back with `parse` when it holds no text
+(assert (eq (format "{}" "say \"hi\"") "say \"hi\""))
+(assert (eq (format "{}" (list "a b" :c)) "(a b :c)"))
+(assert (eq (length (parse (format "{}" (list "a b" :c)))) 3))
+(assert (equiv (parse (format "{}" (list 1 :two))) (list 1 :two)))
 
 (assert (eq 5 (+ 2 3)))
 
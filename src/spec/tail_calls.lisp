@@ -0,0 +1,35 @@
+;; Recursion in tail position should run in constant space, well past the
+;; depth at which non-tail calls overflow the stack.
+
+(import "@prelude")
+
+(func count-down (n)
+    (cond
+        ((eq n 0) :done)
+        ('t (count-down (- n 1)))))
+
+(assert (eq (count-down 5000) :done))
+
+(func is-even (n)
+    (cond
+        ((eq n 0) 't)
+        ('t (is-odd (- n 1)))))
+(func is-odd (n)
+    (cond
+        ((eq n 0) ())
+        ('t (is-even (- n 1)))))
+
+(assert (is-even 3000))
+(assert (not (is-odd 3000)))
+
+(func sum-to (n acc)
+    (if (gt 0 n)
+        (do
+            (let 'acc (+ acc n))
+            (sum-to (- n 1) acc))))
+
+(assert (eq (sum-to 2000 0) ()))
+
+(assert (eq (length (drop 1500 (range 2000))) 500))
+(assert (eq (first (reverse (range 2000))) 2000))
+(assert (equiv (remove 1 '(1 2 3)) '(1 3)))
@@ -1,44 +1,47 @@
 (import "@prelude")
 
+;; Maps used to be lists of key-value pairs, so those (and nil) are still
+;; accepted anywhere a map is
+(func from-list 
+    (pairs) 
+    (do
+        (let 'converted {})
+        (let 'i (sum (length pairs) -1))
+        ;; Going backwards, so earlier pairs shadow later ones like they do
+        ;; in a list
+        (while (ge 0 i)
+            (do
+                (let 'pair (nth i pairs))
+                (let 'converted (put (first pair) (second pair) converted))
+                (let 'i (sum i -1))))
+        converted))
+(func as-map 
+    (map) 
+    (cond 
+        (
+            (eq 
+                (type map) :list) 
+            (from-list map)) 
+        ('t map)))
+
 (func contains 
     (key map) 
-    (gt 0 
-        (length 
-            (filter 
-                (lambda '
-                    (k) '
-                    (eq 
-                        (first k) 
-                        key)) map))))
+    (has key 
+        (as-map map)))
 (func insert 
     (kvpair map) 
-    (cons kvpair 
-        (filter 
-            (lambda '
-                (k) '
-                (not 
-                    (eq 
-                        (first k) 
-                        (first kvpair)))) map)))
+    (put 
+        (first kvpair) 
+        (second kvpair) 
+        (as-map map)))
 (metafunc insert! ($kvpair $map) (let $map (insert ,$kvpair ,$map)))
 (let 'remove. (lambda 
     '(key map) 
-        '(filter 
-            (lambda '
-                (k) '
-                (not 
-                    (eq 
-                        (first k) 
-                        key))) map)))
+        '(del key 
+            (as-map map))))
 (metafunc remove! ($key $map) (let $map (remove. ,$key ,$map)))
 (export 'remove remove.)
 (func extract 
     (key map) 
-    (second 
-        (first 
-            (filter 
-                (lambda '
-                    (k) '
-                    (eq 
-                        (first k) 
-                        key)) map))))
\ No newline at end of file
+    (get key 
+        (as-map map)))
@@ -1,8 +1,18 @@
+// The standard library, by the paths it's imported from
+const RESOURCES: &[(&str, &str)] = &[
+    ("@prelude", include_str!("prelude.lisp")),
+    ("@map", include_str!("map.lisp")),
+    ("@math", include_str!("math.lisp")),
+];
+
 pub fn get_std_resource(path: &str) -> Option<String> {
-    match path {
-        "@prelude" => Some(include_str!("prelude.lisp").to_string()),
-        "@map" => Some(include_str!("map.lisp").to_string()),
-        "@math" => Some(include_str!("math.lisp").to_string()),
-        _ => None,
-    }
+    RESOURCES
+        .iter()
+        .find(|(name, _)| *name == path)
+        .map(|(_, code)| code.to_string())
+}
+
+/// The paths the standard library can be imported from.
+pub fn std_resource_paths() -> impl Iterator<Item = &'static str> {
+    RESOURCES.iter().map(|(name, _)| *name)
 }
@@ -60,32 +60,37 @@
                         ('t ()))
                     (++ n)))
             matches)))
-(export 'remove 
-    (lambda '
-        (n xs) '
-        (cond 
-            (
-                (eq n 0) 
-                (tail xs)) 
-            ('t 
-                (cons 
-                    (first xs) 
-                    (remove 
-                        (sum n -1) 
-                        (tail xs)))))))
-(export 'reverse 
+(export 'reverse
     (lambda '
         (xs) '
-        (cond 
-            (xs 
-                (cons 
-                    (last xs) 
-                    (reverse 
-                        (remove 
-                            (sum 
-                                (length xs) -1) xs)))) 
-            ('t 
-                ()))))
+        (do
+            (let 'reversed ())
+            (while xs
+                (do
+                    (let 'reversed
+                        (cons
+                            (head xs) reversed))
+                    (let 'xs
+                        (tail xs))))
+            reversed)))
+(export 'remove
+    (lambda '
+        (n xs) '
+        (do
+            (let 'skipped ())
+            (while
+                (gt 0 n)
+                (do
+                    (let 'skipped
+                        (cons
+                            (head xs) skipped))
+                    (let 'xs
+                        (tail xs))
+                    (let 'n
+                        (sum n -1))))
+            (append
+                (reverse skipped)
+                (tail xs)))))
 
 ;; Macros
 (export 'metafunc 
@@ -197,12 +202,6 @@
          ,
             (cons or 
                 (tail vals)))))
-(metafunc if 
-    (val todo) 
-    (cond 
-        (,val ,todo) 
-        ('t 
-            ())))
 (metafunc ? 
     (val if else) 
     (cond 
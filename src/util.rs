@@ -1,5 +1,5 @@
 use crate::{Exception, ExceptionValue as EV};
-use std::sync::{Arc, RwLock, RwLockReadGuard, RwLockWriteGuard};
+use std::sync::{Arc, RwLock, RwLockReadGuard, RwLockWriteGuard, Weak};
 
 #[derive(Clone, Debug)]
 pub struct Locker<T> {
@@ -13,7 +13,7 @@ impl<T> Locker<T> {
         }
     }
 
-    pub fn read(&self) -> Result<RwLockReadGuard<T>, Exception> {
+    pub fn read(&self) -> Result<RwLockReadGuard<'_, T>, Exception> {
         match self.val.read() {
             Ok(val) => Ok(val),
             Err(_) => Err(Exception::new(
@@ -27,7 +27,7 @@ impl<T> Locker<T> {
         }
     }
 
-    pub fn write(&self) -> Result<RwLockWriteGuard<T>, Exception> {
+    pub fn write(&self) -> Result<RwLockWriteGuard<'_, T>, Exception> {
         match self.val.write() {
             Ok(val) => Ok(val),
             Err(_) => Err(Exception::new(
@@ -40,4 +40,29 @@ impl<T> Locker<T> {
             )),
         }
     }
+
+    /// Identifies the shared value; clones of the same locker have equal ids.
+    pub(crate) fn id(&self) -> usize {
+        Arc::as_ptr(&self.val) as *const () as usize
+    }
+
+    pub(crate) fn strong_count(&self) -> usize {
+        Arc::strong_count(&self.val)
+    }
+
+    pub(crate) fn downgrade(&self) -> Weak<RwLock<T>> {
+        Arc::downgrade(&self.val)
+    }
+
+    pub(crate) fn upgrade(weak: &Weak<RwLock<T>>) -> Option<Self> {
+        weak.upgrade().map(|val| Self { val })
+    }
+
+    pub(crate) fn try_read(&self) -> Option<RwLockReadGuard<'_, T>> {
+        self.val.try_read().ok()
+    }
+
+    pub(crate) fn try_write(&self) -> Option<RwLockWriteGuard<'_, T>> {
+        self.val.try_write().ok()
+    }
 }